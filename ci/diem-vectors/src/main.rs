@@ -0,0 +1,124 @@
+//! Generates the golden vectors of `test_smt_diem_scheme` with Diem's own hashing
+//! and proof verification:
+//!
+//! ```sh
+//! cargo run --manifest-path ci/diem-vectors/Cargo.toml
+//! ```
+//!
+//! Leaves and internal nodes are hashed with Diem's `SparseMerkleLeafNode` and
+//! `SparseMerkleInternalNode`, and every proof is checked by Diem's
+//! `SparseMerkleProof::verify`. Keys and values are hashed with plain SHA3-256, as
+//! by `DiemScheme`, rather than with the `CryptoHash` of Diem's key and value types.
+
+use diem_crypto::hash::{
+    CryptoHash, CryptoHasher, HashValue, SparseMerkleInternalHasher, TestOnlyHasher,
+    SPARSE_MERKLE_PLACEHOLDER_HASH,
+};
+use diem_types::proof::{
+    SparseMerkleInternalNode, SparseMerkleLeafNode, SparseMerkleLeafNodeHasher, SparseMerkleProof,
+};
+
+/// The entries of the tree of the test.
+const ENTRIES: [(&[u8], &[u8]); 4] = [
+    (b"alice", b"100"),
+    (b"bob", b"200"),
+    (b"carol", b"300"),
+    (b"dave", b"400"),
+];
+
+/// The keys proved by the test, with their values or `None` for non-membership.
+const PROVED: [(&[u8], Option<&[u8]>); 3] =
+    [(b"alice", Some(b"100")), (b"eve", None), (b"key11", None)];
+
+/// A value whose hash is the plain SHA3-256 of its bytes, as with `DiemScheme`.
+struct Value(&'static [u8]);
+
+impl CryptoHash for Value {
+    type Hasher = TestOnlyHasher;
+
+    fn hash(&self) -> HashValue {
+        HashValue::sha3_256_of(self.0)
+    }
+}
+
+type Leaf = (HashValue, HashValue);
+
+fn bit(hash: &HashValue, depth: usize) -> bool {
+    hash.iter_bits().nth(depth).unwrap()
+}
+
+/// Splits leaves by their bit at the given depth.
+fn split(leaves: &[Leaf], depth: usize) -> (Vec<Leaf>, Vec<Leaf>) {
+    leaves.iter().partition(|(path, _)| !bit(path, depth))
+}
+
+/// Hashes the subtree of the given leaves, which share their first `depth` bits.
+fn subtree(leaves: &[Leaf], depth: usize) -> HashValue {
+    match leaves {
+        [] => *SPARSE_MERKLE_PLACEHOLDER_HASH,
+        [(path, value_hash)] => SparseMerkleLeafNode::new(*path, *value_hash).hash(),
+        _ => {
+            let (left, right) = split(leaves, depth);
+            SparseMerkleInternalNode::new(subtree(&left, depth + 1), subtree(&right, depth + 1))
+                .hash()
+        }
+    }
+}
+
+/// Proves a path, returning the leaf found on it and the siblings from the bottom of
+/// the tree to the root.
+fn prove(leaves: &[Leaf], path: HashValue) -> SparseMerkleProof<Value> {
+    let mut leaves = leaves.to_vec();
+    let mut siblings = Vec::new();
+    let mut depth = 0;
+    while leaves.len() > 1 {
+        let (left, right) = split(&leaves, depth);
+        let (next, other) = if bit(&path, depth) {
+            (right, left)
+        } else {
+            (left, right)
+        };
+        siblings.push(subtree(&other, depth + 1));
+        leaves = next;
+        depth += 1;
+    }
+    siblings.reverse();
+    let leaf = leaves
+        .first()
+        .map(|(path, value_hash)| SparseMerkleLeafNode::new(*path, *value_hash));
+    SparseMerkleProof::new(leaf, siblings)
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn main() {
+    println!("leaf seed: {}", hex(SparseMerkleLeafNodeHasher::seed()));
+    println!("internal seed: {}", hex(SparseMerkleInternalHasher::seed()));
+    println!("placeholder: {:x}", *SPARSE_MERKLE_PLACEHOLDER_HASH);
+
+    let leaves = ENTRIES
+        .iter()
+        .map(|(key, value)| (HashValue::sha3_256_of(key), HashValue::sha3_256_of(value)))
+        .collect::<Vec<_>>();
+    println!("root with alice: {:x}", subtree(&leaves[..1], 0));
+    let root = subtree(&leaves, 0);
+    println!("root: {:x}", root);
+
+    for (key, value) in PROVED.iter() {
+        let path = HashValue::sha3_256_of(key);
+        let proof = prove(&leaves, path);
+        let value = value.map(Value);
+        proof.verify(root, path, value.as_ref()).unwrap();
+
+        println!("proof of {}:", String::from_utf8_lossy(key));
+        if let Some(leaf) = proof.leaf() {
+            println!("  leaf path: {:x}", leaf.key());
+            println!("  leaf value hash: {:x}", leaf.value_hash());
+        }
+        for sibling in proof.siblings() {
+            println!("  sibling: {:x}", sibling);
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use alloc::collections::{btree_map, BTreeMap};
+use bytes::Bytes;
+
+/// A write to a `KVStore`, yielded by a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Sets the value of a key.
+    Set(Bytes, Bytes),
+    /// Removes a key which is in the store.
+    Remove(Bytes),
+}
+
+/// The writes of a single tree operation to one of its stores, handed to
+/// `KVStore::write_batch`.
+///
+/// A batch holds at most one write per key, and yields them in key order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: BTreeMap<Bytes, Option<Bytes>>,
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of writes in the batch.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns true if the batch holds no write.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Sets the value of a key, replacing any earlier write to the key.
+    #[inline]
+    pub fn set(&mut self, key: Bytes, value: Bytes) {
+        self.ops.insert(key, Some(value));
+    }
+
+    /// Removes a key, replacing any earlier write to the key. The key must be in
+    /// the store.
+    #[inline]
+    pub fn remove(&mut self, key: Bytes) {
+        self.ops.insert(key, None);
+    }
+
+    /// Returns the write to a key: `Some(Some(value))` if the key is set,
+    /// `Some(None)` if it is removed, and `None` if the batch does not write it.
+    #[inline]
+    pub fn get(&self, key: &[u8]) -> Option<Option<&Bytes>> {
+        self.ops.get(key).map(Option::as_ref)
+    }
+
+    /// Returns the keys removed by the batch.
+    pub(crate) fn removed(&self) -> impl Iterator<Item = &Bytes> {
+        self.ops
+            .iter()
+            .filter_map(|(key, value)| value.is_none().then_some(key))
+    }
+
+    /// Drops the write to a key.
+    #[inline]
+    pub(crate) fn discard(&mut self, key: &[u8]) {
+        self.ops.remove(key);
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter(self.ops.into_iter())
+    }
+}
+
+/// An iterator over the writes of a `WriteBatch`, in key order.
+pub struct IntoIter(btree_map::IntoIter<Bytes, Option<Bytes>>);
+
+impl Iterator for IntoIter {
+    type Item = BatchOp;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(key, value)| match value {
+            Some(value) => BatchOp::Set(key, value),
+            None => BatchOp::Remove(key),
+        })
+    }
+}
@@ -0,0 +1,144 @@
+use crate::{KVStore, StoreHasher, WriteBatch};
+use bytes::Bytes;
+
+/// Returned by `CachedStore`.
+#[derive(Debug)]
+pub enum CachedStoreError<E> {
+    /// The key to remove does not exist.
+    NotFound,
+    /// The inner store returned an error.
+    Store(E),
+}
+
+impl<E: core::fmt::Display> core::fmt::Display for CachedStoreError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            CachedStoreError::NotFound => write!(f, "key not found"),
+            CachedStoreError::Store(e) => write!(f, "store error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: std::error::Error> std::error::Error for CachedStoreError<E> {}
+
+/// A write-back cache over a `KVStore`.
+///
+/// Writes and removals are kept in memory and reads see them, so the nodes written
+/// by one update are not read back from the inner store by the next. Nothing reaches
+/// the inner store until `commit` is called, and `discard` drops the cached writes,
+/// which makes it cheap to apply a block of updates speculatively.
+///
+/// `CachedStore::commit` is not the `KVStore::commit` hook, which the tree calls
+/// after each operation and does nothing here. After discarding, the tree must be
+/// set back to the root it had at the last commit.
+#[derive(Debug, Clone, Default)]
+pub struct CachedStore<S: KVStore> {
+    inner: S,
+    cache: WriteBatch,
+}
+
+impl<S: KVStore> CachedStore<S> {
+    /// Creates an empty cache over a store.
+    #[inline]
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            cache: WriteBatch::new(),
+        }
+    }
+
+    /// Returns the inner store, which does not see the cached writes.
+    #[inline]
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the inner store mutably. Writing to it directly bypasses the cache.
+    #[inline]
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns the inner store, dropping the cached writes.
+    #[inline]
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns the writes not yet committed to the inner store.
+    #[inline]
+    pub fn pending(&self) -> &WriteBatch {
+        &self.cache
+    }
+
+    /// Returns true if there are writes not yet committed to the inner store.
+    #[inline]
+    pub fn is_dirty(&self) -> bool {
+        !self.cache.is_empty()
+    }
+
+    /// Writes the cached writes to the inner store as one batch. If the inner store
+    /// fails, its batch is aborted and the writes stay cached, so the commit can be
+    /// retried.
+    pub fn commit(&mut self) -> Result<(), S::Error> {
+        if self.cache.is_empty() {
+            return Ok(());
+        }
+
+        let result = self.inner.begin_batch().and_then(|_| {
+            self.inner.write_batch(self.cache.clone())?;
+            KVStore::commit(&mut self.inner)
+        });
+        if let Err(e) = result {
+            let _ = self.inner.abort_batch();
+            return Err(e);
+        }
+        self.cache = WriteBatch::new();
+        Ok(())
+    }
+
+    /// Drops the cached writes.
+    #[inline]
+    pub fn discard(&mut self) {
+        self.cache = WriteBatch::new();
+    }
+}
+
+impl<S: KVStore + StoreHasher> StoreHasher for CachedStore<S> {
+    type Hasher = S::Hasher;
+}
+
+impl<S: KVStore> KVStore for CachedStore<S> {
+    type Error = CachedStoreError<S::Error>;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error> {
+        match self.cache.get(key) {
+            Some(value) => Ok(value.cloned()),
+            None => self.inner.get(key).map_err(CachedStoreError::Store),
+        }
+    }
+
+    fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error> {
+        self.cache.set(key, value);
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error> {
+        let value = self.get(key)?.ok_or(CachedStoreError::NotFound)?;
+        if self.inner.contains(key).map_err(CachedStoreError::Store)? {
+            self.cache.remove(Bytes::copy_from_slice(key));
+        } else {
+            // The key was only written to the cache.
+            self.cache.discard(key);
+        }
+        Ok(value)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        match self.cache.get(key) {
+            Some(value) => Ok(value.is_some()),
+            None => self.inner.contains(key).map_err(CachedStoreError::Store),
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{
+    get_bit_at_from_msb, smt::DEFAULT_VALUE, tree_hasher::TreeHasher, BadProof, SparseMerkleProof,
+    TreeHashScheme,
+};
+use alloc::vec::Vec;
+use bytes::Bytes;
+use sha3::{Digest, Sha3_256};
+
+/// `sha3_256(b"DIEM::SparseMerkleLeafNode")`, the salt of the hasher Diem derives
+/// for `SparseMerkleLeafNode`.
+const LEAF_SEED: [u8; 32] = [
+    0xbd, 0xf4, 0xc3, 0x37, 0x44, 0x7d, 0x4f, 0xfa, 0x29, 0x24, 0x91, 0x64, 0x4e, 0x4d, 0x26, 0x60,
+    0x7f, 0x77, 0x0e, 0xad, 0xb3, 0x64, 0xa6, 0xb2, 0x38, 0xfe, 0x0e, 0x19, 0xb2, 0xb8, 0x77, 0x04,
+];
+
+/// `sha3_256(b"DIEM::SparseMerkleInternal")`, the salt of Diem's
+/// `SparseMerkleInternalHasher`.
+const INTERNAL_SEED: [u8; 32] = [
+    0x0b, 0x3e, 0xc0, 0x48, 0x1b, 0xc0, 0x07, 0x08, 0xb0, 0x43, 0xa6, 0xda, 0xdf, 0xf4, 0x82, 0xda,
+    0x85, 0xa9, 0x7a, 0x91, 0xda, 0x1a, 0xc1, 0xb7, 0x29, 0x15, 0x0f, 0x0f, 0x44, 0xba, 0xd4, 0xc6,
+];
+
+/// Diem's `SPARSE_MERKLE_PLACEHOLDER_HASH`: its name, zero-padded to a hash.
+const PLACEHOLDER: [u8; 32] = *b"SPARSE_MERKLE_PLACEHOLDER_HASH\0\0";
+
+/// The hash scheme of the sparse Merkle trees of Diem (formerly Libra) and Aptos.
+///
+/// Hashes are SHA3-256. Leaves hash to `H(leaf_seed || path || value_hash)` and
+/// internal nodes to `H(internal_seed || left || right)`, where each seed is the
+/// hash of `DIEM::` followed by the name of the hashed type, and empty subtrees hash
+/// to `SPARSE_MERKLE_PLACEHOLDER_HASH`. The tree has the same shape as Diem's, so a
+/// `SparseMerkleTree<S, DiemScheme>` has the root Diem computes for leaves with the
+/// same paths and value hashes.
+///
+/// Diem keys and values are hashed with the `CryptoHasher` of their type, which
+/// this crate does not know, so paths are the plain SHA3-256 of keys and value
+/// hashes the plain SHA3-256 of values. The roots of Diem's own state trees, whose
+/// keys and values are hashed with `CryptoHash`, are therefore not reproduced. A Diem
+/// verifier is given the path of the key as the element key and a value whose hash
+/// is the plain SHA3-256 of its bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiemScheme;
+
+impl DiemScheme {
+    fn hash(parts: &[&[u8]]) -> Bytes {
+        let mut hasher = Sha3_256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().to_vec().into()
+    }
+}
+
+impl TreeHashScheme for DiemScheme {
+    #[inline]
+    fn hash_size() -> usize {
+        PLACEHOLDER.len()
+    }
+
+    #[inline]
+    fn path(&self, key: &[u8]) -> Bytes {
+        Self::hash(&[key])
+    }
+
+    #[inline]
+    fn hash_value(&self, value: &[u8]) -> Bytes {
+        Self::hash(&[value])
+    }
+
+    #[inline]
+    fn hash_leaf(&self, path: &[u8], value_hash: &[u8]) -> Bytes {
+        Self::hash(&[&LEAF_SEED, path, value_hash])
+    }
+
+    #[inline]
+    fn hash_node(&self, left: &[u8], right: &[u8]) -> Bytes {
+        Self::hash(&[&INTERNAL_SEED, left, right])
+    }
+
+    #[inline]
+    fn placeholder(&self) -> Bytes {
+        Bytes::from_static(&PLACEHOLDER)
+    }
+}
+
+/// A Merkle proof in the layout of Diem's `SparseMerkleProof`, converted from a
+/// `SparseMerkleProof<DiemScheme>` by `to_diem`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiemSparseMerkleProof {
+    leaf: Option<(Bytes, Bytes)>,
+    siblings: Vec<Bytes>,
+}
+
+impl DiemSparseMerkleProof {
+    /// Returns the `(path, value_hash)` of the leaf found on the path of the key: the
+    /// leaf of the key itself for a membership proof, another leaf or `None` for a
+    /// non-membership proof.
+    #[inline]
+    pub fn leaf(&self) -> Option<(&Bytes, &Bytes)> {
+        self.leaf
+            .as_ref()
+            .map(|(path, value_hash)| (path, value_hash))
+    }
+
+    /// Returns the siblings on the path of the key, from the bottom of the tree to
+    /// the root, placeholders included.
+    #[inline]
+    pub fn siblings(&self) -> &[Bytes] {
+        &self.siblings
+    }
+
+    /// Verifies the proof the way Diem does, given the key and its value, an empty
+    /// value proving that the key is not in the tree.
+    pub fn verify(
+        &self,
+        root: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> bool {
+        let value = value.as_ref();
+        let value_hash = (value != DEFAULT_VALUE.as_ref()).then(|| DiemScheme.hash_value(value));
+        self.verify_by_hash(root, DiemScheme.path(key.as_ref()), value_hash)
+    }
+
+    /// Verifies the proof the way Diem's `SparseMerkleProof::verify_by_hash` does,
+    /// given the path of the key and the hash of its value, `None` proving that the
+    /// key is not in the tree.
+    pub fn verify_by_hash(
+        &self,
+        root: impl AsRef<[u8]>,
+        path: impl AsRef<[u8]>,
+        value_hash: Option<impl AsRef<[u8]>>,
+    ) -> bool {
+        let path = path.as_ref();
+        if path.len() != DiemScheme::hash_size()
+            || self.siblings.len() > DiemScheme::hash_size() * 8
+            || self
+                .siblings
+                .iter()
+                .any(|sibling| sibling.len() != DiemScheme::hash_size())
+        {
+            return false;
+        }
+
+        match (&value_hash, &self.leaf) {
+            // The leaf must be the key's, with the given value.
+            (Some(value_hash), Some((leaf_path, leaf_value_hash))) => {
+                if leaf_path.as_ref() != path || leaf_value_hash.as_ref() != value_hash.as_ref() {
+                    return false;
+                }
+            }
+            (Some(_), None) => return false,
+            // The leaf of another key must take the place of the key, so it must share
+            // the path of the key down to the leaf.
+            (None, Some((leaf_path, _))) => {
+                if leaf_path.as_ref() == path
+                    || leaf_path.len() != path.len()
+                    || (0..self.siblings.len())
+                        .any(|i| get_bit_at_from_msb(leaf_path, i) != get_bit_at_from_msb(path, i))
+                {
+                    return false;
+                }
+            }
+            // The subtree of the key is empty.
+            (None, None) => {}
+        }
+
+        let mut current_hash = match &self.leaf {
+            Some((leaf_path, leaf_value_hash)) => DiemScheme.hash_leaf(leaf_path, leaf_value_hash),
+            None => DiemScheme.placeholder(),
+        };
+        let num = self.siblings.len();
+        for (idx, sibling) in self.siblings.iter().enumerate() {
+            current_hash = if get_bit_at_from_msb(path, num - 1 - idx) == 1 {
+                DiemScheme.hash_node(sibling, &current_hash)
+            } else {
+                DiemScheme.hash_node(&current_hash, sibling)
+            };
+        }
+        current_hash.as_ref() == root.as_ref()
+    }
+}
+
+impl SparseMerkleProof<DiemScheme> {
+    /// Converts the proof of a key with the given value to Diem's layout, an empty
+    /// value for a proof that the key is not in the tree.
+    ///
+    /// Diem's proofs carry the leaf of the key in membership proofs, which is
+    /// rebuilt from the key and its value, and no sibling data.
+    pub fn to_diem(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<DiemSparseMerkleProof, BadProof> {
+        let value = value.as_ref();
+        let leaf = if value != DEFAULT_VALUE.as_ref() {
+            if self.non_membership_leaf_data.is_some() {
+                return Err(BadProof);
+            }
+            Some((DiemScheme.path(key.as_ref()), DiemScheme.hash_value(value)))
+        } else {
+            match &self.non_membership_leaf_data {
+                Some(data) => match TreeHasher::<DiemScheme>::parse_leaf(data) {
+                    Some((path, value_hash)) => Some((
+                        Bytes::copy_from_slice(path),
+                        Bytes::copy_from_slice(value_hash),
+                    )),
+                    None => return Err(BadProof),
+                },
+                None => None,
+            }
+        };
+        Ok(DiemSparseMerkleProof {
+            leaf,
+            siblings: self.side_nodes.clone(),
+        })
+    }
+}
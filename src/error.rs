@@ -0,0 +1,87 @@
+use crate::{BadProof, Inconsistency};
+use bytes::Bytes;
+
+/// Returned when an operation on a `SparseMerkleTree` fails.
+#[derive(Debug)]
+pub enum TreeError<E> {
+    /// A node reachable from the root is missing from the nodes store.
+    MissingNode {
+        /// The hash of the missing node.
+        hash: Bytes,
+    },
+    /// The data of a node in the nodes store is malformed.
+    CorruptNode {
+        /// The hash of the corrupt node.
+        hash: Bytes,
+    },
+    /// The root does not have the length of a hash.
+    InvalidRootLength {
+        /// The length of the root.
+        len: usize,
+    },
+    /// The version records of a versioned tree in the values store are malformed.
+    CorruptVersions,
+    /// A chunk added to a `StateSyncer` does not start at the path the next chunk
+    /// must start at, or the sync is already complete.
+    UnexpectedChunk {
+        /// The path the next chunk must start at, or `None` if the sync is complete.
+        expected: Option<Bytes>,
+    },
+    /// A `StateSyncer` was finished before chunks covering the whole path space were
+    /// added.
+    IncompleteSync {
+        /// The path the next chunk must start at.
+        next_path: Bytes,
+    },
+    /// An invalid Merkle proof was supplied, or a proof could not be generated.
+    BadProof,
+    /// The stores are inconsistent with the root, as found by `verify_integrity`.
+    Inconsistent(Inconsistency),
+    /// The underlying store returned an error.
+    Store(E),
+}
+
+impl<E> From<BadProof> for TreeError<E> {
+    fn from(_: BadProof) -> Self {
+        TreeError::BadProof
+    }
+}
+
+impl<E> From<Inconsistency> for TreeError<E> {
+    fn from(inconsistency: Inconsistency) -> Self {
+        TreeError::Inconsistent(inconsistency)
+    }
+}
+
+impl<E: core::fmt::Display> core::fmt::Display for TreeError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TreeError::MissingNode { hash } => write!(f, "missing node {:?}", hash.as_ref()),
+            TreeError::CorruptNode { hash } => write!(f, "corrupt node {:?}", hash.as_ref()),
+            TreeError::InvalidRootLength { len } => write!(f, "invalid root length {}", len),
+            TreeError::CorruptVersions => write!(f, "corrupt version records"),
+            TreeError::UnexpectedChunk {
+                expected: Some(path),
+            } => write!(f, "unexpected chunk, expected one at {:?}", path.as_ref()),
+            TreeError::UnexpectedChunk { expected: None } => write!(f, "sync already complete"),
+            TreeError::IncompleteSync { next_path } => {
+                write!(f, "incomplete sync, next chunk at {:?}", next_path.as_ref())
+            }
+            TreeError::BadProof => write!(f, "bad proof"),
+            TreeError::Inconsistent(inconsistency) => {
+                write!(f, "inconsistent tree: {}", inconsistency)
+            }
+            TreeError::Store(e) => write!(f, "store error: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: std::error::Error + 'static> std::error::Error for TreeError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TreeError::Store(e) => Some(e),
+            _ => None,
+        }
+    }
+}
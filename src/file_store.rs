@@ -0,0 +1,470 @@
+use crate::{BatchOp, KVStore, StoreHasher, TreeHashScheme, WriteBatch};
+use bytes::Bytes;
+use core::marker::PhantomData;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// The first bytes of a log, the last one being the version of the format.
+const MAGIC: [u8; 8] = *b"LSMTLOG\x01";
+
+const SET: u8 = 0;
+const REMOVE: u8 = 1;
+const COMMIT: u8 = 2;
+
+/// The checksum of a record header, and the one which ends a record.
+const CHECKSUM_LEN: usize = 4;
+/// The tag, key length and value length of a record, and their checksum.
+const HEADER_LEN: usize = 1 + 4 + 4 + CHECKSUM_LEN;
+
+/// The offset and length of the value of each key.
+type Index = HashMap<Bytes, (u64, u32)>;
+
+/// A batch in progress, which is rolled back if it is aborted.
+#[derive(Debug)]
+struct Batch {
+    /// The length of the log when the batch began.
+    start: u64,
+    /// The positions the batch replaced in the index, or `None` for keys it added.
+    replaced: HashMap<Bytes, Option<(u64, u32)>>,
+}
+
+/// Returned by `FileStore`.
+#[derive(Debug)]
+pub enum FileStoreError {
+    /// The key to remove does not exist.
+    NotFound,
+    /// Reading or writing the log failed.
+    Io(io::Error),
+}
+
+impl core::fmt::Display for FileStoreError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FileStoreError::NotFound => write!(f, "key not found"),
+            FileStoreError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FileStoreError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FileStoreError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FileStoreError {
+    fn from(e: io::Error) -> Self {
+        FileStoreError::Io(e)
+    }
+}
+
+/// A durable `KVStore` writing to an append-only log file, for trees hashed with `H`.
+///
+/// Each `set` and `remove` appends a record, and the batch of each tree operation is
+/// followed by a commit record and synced to disk on `KVStore::commit`. Writes made
+/// outside a batch are committed one by one, but not synced until `sync`. An aborted
+/// batch is truncated from the log. The header
+/// of each record, which holds its lengths, is followed by its own checksum, and
+/// records end with a checksum of the whole record.
+///
+/// The keys and the position of their values in the log are kept in memory, and are
+/// rebuilt by `open` by replaying the log up to its last commit record. Whatever
+/// follows it, such as a torn record or a batch interrupted by a crash, is truncated.
+/// A bad record anywhere but at the end of the log is corruption rather than a torn
+/// write, and fails `open` with `io::ErrorKind::InvalidData`.
+///
+/// Removed and overwritten values stay in the log until `compact` is called.
+pub struct FileStore<H> {
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Index,
+    /// The length of the log.
+    end: u64,
+    batch: Option<Batch>,
+    _hasher: PhantomData<fn() -> H>,
+}
+
+impl<H> FileStore<H> {
+    /// Opens the log at the given path, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let (index, end) = if file.metadata()?.len() == 0 {
+            file.write_all(&MAGIC)?;
+            file.sync_all()?;
+            (HashMap::new(), MAGIC.len() as u64)
+        } else {
+            let (index, end) = replay(&mut file)?;
+            // Drop whatever follows the last commit.
+            if file.metadata()?.len() != end {
+                file.set_len(end)?;
+                file.sync_all()?;
+            }
+            (index, end)
+        };
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            index,
+            end,
+            batch: None,
+            _hasher: PhantomData,
+        })
+    }
+
+    /// Returns the path of the log.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of keys in the store.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Returns true if the store is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Returns the length of the log, including removed and overwritten values.
+    #[inline]
+    pub fn log_len(&self) -> u64 {
+        self.end
+    }
+
+    /// Syncs the log to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.lock().sync_data()
+    }
+
+    /// Rewrites the log with only the current value of each key, dropping removed
+    /// and overwritten values.
+    ///
+    /// The new log is written next to the old one and renamed over it once synced,
+    /// so a crash leaves either log intact.
+    ///
+    /// Fails if called during a batch, whose records are not committed yet.
+    pub fn compact(&mut self) -> io::Result<()> {
+        if self.batch.is_some() {
+            return Err(io::Error::other("cannot compact a log during a batch"));
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".compact");
+        let tmp_path = PathBuf::from(tmp_path);
+
+        let mut tmp = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        let mut index = HashMap::with_capacity(self.index.len());
+        let mut log = MAGIC.to_vec();
+        for (key, &(offset, len)) in &self.index {
+            let value = self.read_at(offset, len)?;
+            let value_offset = log.len() + HEADER_LEN + key.len();
+            encode_record(&mut log, SET, key, &value);
+            index.insert(key.clone(), (value_offset as u64, len));
+        }
+        encode_record(&mut log, COMMIT, &[], &[]);
+        tmp.write_all(&log)?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+
+        // The new log is in place even if syncing the directory fails, and the old
+        // one is unlinked, so switch to the new one first.
+        self.file = Mutex::new(tmp);
+        self.index = index;
+        self.end = log.len() as u64;
+        sync_dir(&self.path)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, File> {
+        // The file has no state besides its position, which is set before each use.
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn read_at(&self, offset: u64, len: u32) -> io::Result<Bytes> {
+        let mut file = self.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        let mut value = vec![0; len as usize];
+        file.read_exact(&mut value)?;
+        Ok(value.into())
+    }
+
+    /// Appends records to the log, updating the index.
+    fn append(&mut self, ops: impl IntoIterator<Item = BatchOp>, commit: bool) -> io::Result<()> {
+        let mut log = Vec::new();
+        let mut updates = Vec::new();
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => {
+                    let offset = self.end + (log.len() + HEADER_LEN + key.len()) as u64;
+                    encode_record(&mut log, SET, &key, &value);
+                    updates.push((key, Some((offset, value.len() as u32))));
+                }
+                BatchOp::Remove(key) => {
+                    encode_record(&mut log, REMOVE, &key, &[]);
+                    updates.push((key, None));
+                }
+            }
+        }
+        if commit {
+            encode_record(&mut log, COMMIT, &[], &[]);
+        }
+
+        let mut file = self.lock();
+        file.seek(SeekFrom::Start(self.end))?;
+        if let Err(e) = file.write_all(&log) {
+            // Drop what was written, which later records could leave looking like a
+            // corrupted record in the middle of the log.
+            let _ = file.set_len(self.end);
+            return Err(e);
+        }
+        drop(file);
+
+        self.end += log.len() as u64;
+        for (key, position) in updates {
+            if let Some(batch) = &mut self.batch {
+                let index = &self.index;
+                batch
+                    .replaced
+                    .entry(key.clone())
+                    .or_insert_with(|| index.get(&key).copied());
+            }
+            match position {
+                Some(position) => self.index.insert(key, position),
+                None => self.index.remove(&key),
+            };
+        }
+        Ok(())
+    }
+}
+
+impl<H> core::fmt::Debug for FileStore<H> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("FileStore")
+            .field("path", &self.path)
+            .field("len", &self.index.len())
+            .field("log_len", &self.end)
+            .finish()
+    }
+}
+
+impl<H: TreeHashScheme> StoreHasher for FileStore<H> {
+    type Hasher = H;
+}
+
+impl<H> KVStore for FileStore<H> {
+    type Error = FileStoreError;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error> {
+        match self.index.get(key) {
+            Some(&(offset, len)) => Ok(Some(self.read_at(offset, len)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error> {
+        let commit = self.batch.is_none();
+        self.append([BatchOp::Set(key, value)], commit)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error> {
+        let value = self.get(key)?.ok_or(FileStoreError::NotFound)?;
+        let commit = self.batch.is_none();
+        self.append([BatchOp::Remove(Bytes::copy_from_slice(key))], commit)?;
+        Ok(value)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        Ok(self.index.contains_key(key))
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Self::Error> {
+        self.batch = Some(Batch {
+            start: self.end,
+            replaced: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Self::Error> {
+        let commit = self.batch.is_none();
+        self.append(batch, commit)?;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Self::Error> {
+        // The batch is only over once its commit record is synced, so that it can
+        // still be aborted if writing or syncing it fails.
+        self.append([], true)?;
+        self.sync()?;
+        self.batch = None;
+        Ok(())
+    }
+
+    fn abort_batch(&mut self) -> Result<(), Self::Error> {
+        let batch = match self.batch.take() {
+            Some(batch) => batch,
+            None => return Ok(()),
+        };
+        for (key, position) in batch.replaced {
+            match position {
+                Some(position) => self.index.insert(key, position),
+                None => self.index.remove(&key),
+            };
+        }
+        self.end = batch.start;
+        self.lock().set_len(self.end)?;
+        Ok(())
+    }
+}
+
+/// Syncs the directory containing `path`, making a rename into it durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened as files, nor need to be synced, outside unix.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn encode_record(buf: &mut Vec<u8>, tag: u8, key: &[u8], value: &[u8]) {
+    let start = buf.len();
+    buf.push(tag);
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    let checksum = crc32(&buf[start..]);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(value);
+    let checksum = crc32(&buf[start..]);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+}
+
+/// Replays a log, returning the index as of its last commit record and the end of
+/// that record.
+///
+/// Only the last record of the log may be bad, as it is the one a crash can tear.
+fn replay(file: &mut File) -> io::Result<(Index, u64)> {
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(&*file);
+    reader.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; MAGIC.len()];
+    if reader.read_exact(&mut magic).is_err() || magic != MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a lsmtree log",
+        ));
+    }
+
+    let mut index = HashMap::new();
+    let mut uncommitted = Vec::new();
+    let mut offset = MAGIC.len() as u64;
+    let mut committed = offset;
+    let mut record = Vec::new();
+    loop {
+        let mut header = [0; HEADER_LEN];
+        if reader.read_exact(&mut header).is_err() {
+            break;
+        }
+        // The lengths cannot be trusted to find the end of a record with a bad header,
+        // so it is only torn if nothing follows its header.
+        let (fields, checksum) = header.split_at(HEADER_LEN - CHECKSUM_LEN);
+        if crc32(fields).to_le_bytes() != checksum {
+            if offset + HEADER_LEN as u64 == len {
+                break;
+            }
+            return Err(corrupt(offset));
+        }
+        let tag = header[0];
+        let key_len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+        let value_len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        // With its header intact, a record running past the end of the file is torn.
+        let remaining = len.saturating_sub(offset + HEADER_LEN as u64);
+        if (key_len + value_len + CHECKSUM_LEN) as u64 > remaining {
+            break;
+        }
+
+        record.clear();
+        record.extend_from_slice(&header);
+        record.resize(HEADER_LEN + key_len + value_len + CHECKSUM_LEN, 0);
+        if reader.read_exact(&mut record[HEADER_LEN..]).is_err() {
+            break;
+        }
+        let (body, checksum) = record.split_at(record.len() - CHECKSUM_LEN);
+        if crc32(body).to_le_bytes() != checksum {
+            if offset + record.len() as u64 == len {
+                break;
+            }
+            return Err(corrupt(offset));
+        }
+
+        let key = Bytes::copy_from_slice(&body[HEADER_LEN..HEADER_LEN + key_len]);
+        let value_offset = offset + (HEADER_LEN + key_len) as u64;
+        offset += record.len() as u64;
+        match tag {
+            SET => uncommitted.push((key, Some((value_offset, value_len as u32)))),
+            REMOVE => uncommitted.push((key, None)),
+            COMMIT => {
+                for (key, position) in uncommitted.drain(..) {
+                    match position {
+                        Some(position) => index.insert(key, position),
+                        None => index.remove(&key),
+                    };
+                }
+                committed = offset;
+            }
+            _ => return Err(corrupt(offset - record.len() as u64)),
+        }
+    }
+    Ok((index, committed))
+}
+
+fn corrupt(offset: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt record at offset {} of a lsmtree log", offset),
+    )
+}
+
+/// CRC-32 (IEEE), computed bitwise to avoid a dependency.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
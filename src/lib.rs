@@ -12,28 +12,61 @@ extern crate alloc;
 mod smt;
 #[cfg(test)]
 pub use self::smt::tests::{new_sparse_merkle_tree, Error, SimpleStore};
-pub use self::smt::SparseMerkleTree;
+pub use self::smt::{
+    Inconsistency, InconsistencyKind, Leaf, Leaves, NodeCacheStats, SparseMerkleTree,
+    SparseMerkleTreeView, StateSyncer,
+};
+
+#[cfg(feature = "async")]
+pub use self::smt::AsyncSparseMerkleTree;
+
+mod batch;
+pub use batch::{BatchOp, WriteBatch};
+
+mod cached;
+pub use cached::{CachedStore, CachedStoreError};
+
+mod store;
+#[cfg(feature = "std")]
+pub use store::MemoryStore;
+pub use store::{BTreeStore, StoreError};
+
+#[cfg(feature = "file-store")]
+mod file_store;
+#[cfg(feature = "file-store")]
+pub use file_store::{FileStore, FileStoreError};
+
+#[cfg(feature = "diem")]
+mod diem;
+#[cfg(feature = "diem")]
+pub use diem::{DiemScheme, DiemSparseMerkleProof};
+
+mod error;
+pub use error::TreeError;
 
 mod proofs;
 mod tree_hasher;
+pub use tree_hasher::{KeyedHasher, TreeHashScheme};
 
+#[cfg(all(feature = "async", not(feature = "std")))]
+use alloc::boxed::Box;
 pub use bytes;
 use bytes::Bytes;
 pub use digest;
 pub use proofs::*;
 
 /// Key-Value store
+///
+/// Stores do not depend on the hash function of the tree, which is a type parameter
+/// of `SparseMerkleTree`, so one store type can back trees of any hasher.
 pub trait KVStore {
-    /// The hasher to use for the underlying tree.
-    type Hasher: digest::Digest;
-
     /// The Error type
     #[cfg(not(feature = "std"))]
-    type Error: core::fmt::Debug + core::fmt::Display + From<BadProof>;
+    type Error: core::fmt::Debug + core::fmt::Display;
 
     /// The Error type
     #[cfg(feature = "std")]
-    type Error: std::error::Error + From<BadProof>;
+    type Error: std::error::Error;
 
     /// Gets the value for a key. If not exists, returns `Ok(None)`.
     fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error>;
@@ -43,6 +76,112 @@ pub trait KVStore {
     fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error>;
     /// Returns if key exists in the store.
     fn contains(&self, key: &[u8]) -> Result<bool, Self::Error>;
+
+    /// Called before the writes of a tree operation are written with `write_batch`.
+    /// Does nothing by default.
+    fn begin_batch(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Writes all the writes of a tree operation to the store. The default
+    /// implementation applies them one by one with `set` and `remove`; stores which
+    /// support atomic batches should write the batch atomically.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Self::Error> {
+        for op in batch {
+            match op {
+                BatchOp::Set(key, value) => self.set(key, value)?,
+                BatchOp::Remove(key) => {
+                    self.remove(&key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Called once the writes of a tree operation have been written to both stores.
+    /// Does nothing by default.
+    ///
+    /// The nodes store is committed before the values store. A tree does not make
+    /// the two commits atomic: stores which need that should share one transaction
+    /// and commit it when the values store is committed.
+    fn commit(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called on both stores if writing or committing the batches of a tree
+    /// operation fails after `begin_batch`, so that the store can drop the writes
+    /// of its batch. It is also called on a store which has already committed its
+    /// batch, or whose `begin_batch` failed, and should then do nothing. Errors are
+    /// ignored in favour of the one which failed the batch. Does nothing by default.
+    fn abort_batch(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Names the hasher of the trees backed by a store, so that `SparseMerkleTree<S>`
+/// can leave out the hasher, as it did when the hasher was part of `KVStore`.
+///
+/// Implemented by the stores of this crate for their `H` parameter.
+pub trait StoreHasher {
+    /// The hasher of the trees backed by the store.
+    type Hasher: crate::TreeHashScheme;
+}
+
+/// Asynchronous Key-Value store, for stores backed by an async database client.
+///
+/// Used by `AsyncSparseMerkleTree`.
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait AsyncKVStore: Send + Sync {
+    /// The Error type
+    #[cfg(not(feature = "std"))]
+    type Error: core::fmt::Debug + core::fmt::Display + Send;
+
+    /// The Error type
+    #[cfg(feature = "std")]
+    type Error: std::error::Error + Send;
+
+    /// Gets the value for a key. If not exists, returns `Ok(None)`.
+    async fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error>;
+    /// Updates the value for a key.
+    async fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error>;
+    /// Remove value by key.
+    async fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error>;
+    /// Returns if key exists in the store.
+    async fn contains(&self, key: &[u8]) -> Result<bool, Self::Error>;
+
+    /// Called before the writes of a tree operation are written with `write_batch`.
+    /// Does nothing by default.
+    async fn begin_batch(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Writes all the writes of a tree operation to the store. The default
+    /// implementation applies them one by one with `set` and `remove`; stores which
+    /// support atomic batches should write the batch atomically.
+    async fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Self::Error> {
+        for op in batch {
+            match op {
+                BatchOp::Set(key, value) => self.set(key, value).await?,
+                BatchOp::Remove(key) => {
+                    self.remove(&key).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Called once the writes of a tree operation have been written to both stores.
+    /// Does nothing by default. See `KVStore::commit`.
+    async fn commit(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called on both stores if writing or committing the batches of a tree
+    /// operation fails. Does nothing by default. See `KVStore::abort_batch`.
+    async fn abort_batch(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 /// Gets the bit at an offset from the most significant bit
@@ -62,15 +201,26 @@ fn set_bit_at_from_msb(data: &mut [u8], position: usize) {
     data[position / 8] = n as u8;
 }
 
+/// Returns the number of bytes needed for a bit mask of `bits` bits
+#[inline]
+fn bitmask_len(bits: usize) -> usize {
+    (bits + 7) >> 3
+}
+
+/// Returns the number of set bits among the first `bits` bits of a bit mask, or
+/// `None` if a bit past them is set
 #[inline]
-fn count_set_bits(data: &[u8]) -> usize {
+fn count_set_bits(data: &[u8], bits: usize) -> Option<usize> {
     let mut count = 0;
     for i in 0..data.len() * 8 {
         if get_bit_at_from_msb(data, i) == 1 {
+            if i >= bits {
+                return None;
+            }
             count += 1;
         }
     }
-    count
+    Some(count)
 }
 
 #[inline]
@@ -1,15 +1,24 @@
 #[cfg(test)]
 mod tests;
 
+mod codec;
+mod multi;
+mod range;
+#[cfg(feature = "serde")]
+mod serde;
+pub use multi::*;
+pub use range::SparseMerkleRangeProof;
+pub(crate) use range::{clear_bit_at_from_msb, in_range, overlap, Overlap};
+
 use super::{
-    count_set_bits, get_bit_at_from_msb, set_bit_at_from_msb,
+    bitmask_len, count_set_bits, get_bit_at_from_msb, set_bit_at_from_msb,
     smt::{DEFAULT_VALUE, RIGHT},
     tree_hasher::{TreeHasher, LEAF_PREFIX},
+    TreeHashScheme,
 };
 use alloc::{vec, vec::Vec};
 use bytes::Bytes;
 use core::marker::PhantomData;
-use digest::Digest;
 
 /// Returned when an invalid Merkle proof is supplied.
 pub struct BadProof;
@@ -80,26 +89,103 @@ impl<H> SparseMerkleProof<H> {
     }
 }
 
-impl<H: digest::Digest> SparseMerkleProof<H> {
+impl<H: TreeHashScheme> SparseMerkleProof<H> {
     /// Verifies a Merkle proof
     pub fn verify(
         &self,
         root: impl AsRef<[u8]>,
         key: impl AsRef<[u8]>,
         value: impl AsRef<[u8]>,
+    ) -> bool
+    where
+        H: Default,
+    {
+        self.verify_with(&H::default(), root, key, value)
+    }
+
+    /// Verifies a Merkle proof of a tree hashed with the given scheme instance.
+    pub fn verify_with(
+        &self,
+        scheme: &H,
+        root: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> bool {
+        self.verify_proof(&TreeHasher::new(scheme.clone()), root, key, value)
+    }
+
+    /// Verifies a Merkle proof of the tree with the given identifier (see
+    /// `SparseMerkleTree::with_tree_id`). Proofs of other trees, including trees
+    /// without an identifier, do not verify.
+    pub fn verify_for_tree(
+        &self,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> bool
+    where
+        H: Default,
+    {
+        self.verify_for_tree_with(&H::default(), tree_id, root, key, value)
+    }
+
+    /// Verifies a Merkle proof of the tree with the given identifier, hashed with the
+    /// given scheme instance.
+    pub fn verify_for_tree_with(
+        &self,
+        scheme: &H,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
     ) -> bool {
-        self.verify_proof(root, key, value)
+        let th = TreeHasher::new(scheme.clone()).with_tree_id(Some(tree_id.as_ref()));
+        self.verify_proof(&th, root, key, value)
     }
 
     /// Compacts a proof, to reduce its size.
-    pub fn compact(&self) -> Result<SparseCompactMerkleProof<H>, BadProof> {
-        let mut th = TreeHasher::<H>::new(vec![0; TreeHasher::<H>::path_size()].into());
+    pub fn compact(&self) -> Result<SparseCompactMerkleProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.compact_with(&H::default())
+    }
+
+    /// Compacts a proof of a tree hashed with the given scheme instance.
+    pub fn compact_with(&self, scheme: &H) -> Result<SparseCompactMerkleProof<H>, BadProof> {
+        self.compact_proof(&TreeHasher::new(scheme.clone()))
+    }
+
+    /// Compacts a proof of the tree with the given identifier (see
+    /// `SparseMerkleTree::with_tree_id`), whose sibling data is bound to the tree.
+    pub fn compact_for_tree(
+        &self,
+        tree_id: impl AsRef<[u8]>,
+    ) -> Result<SparseCompactMerkleProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.compact_for_tree_with(&H::default(), tree_id)
+    }
+
+    /// Compacts a proof of the tree with the given identifier, hashed with the given
+    /// scheme instance.
+    pub fn compact_for_tree_with(
+        &self,
+        scheme: &H,
+        tree_id: impl AsRef<[u8]>,
+    ) -> Result<SparseCompactMerkleProof<H>, BadProof> {
+        let th = TreeHasher::new(scheme.clone()).with_tree_id(Some(tree_id.as_ref()));
+        self.compact_proof(&th)
+    }
 
-        if !self.sanity_check(&mut th) {
+    fn compact_proof(&self, th: &TreeHasher<H>) -> Result<SparseCompactMerkleProof<H>, BadProof> {
+        if !self.sanity_check(th) {
             return Err(BadProof);
         }
 
-        let mut bit_mask = vec![0u8; ((self.side_nodes.len() as f64) / 8.0).ceil() as usize];
+        let mut bit_mask = vec![0u8; bitmask_len(self.side_nodes.len())];
 
         let compacted_side_nodes = self
             .side_nodes
@@ -127,10 +213,18 @@ impl<H: digest::Digest> SparseMerkleProof<H> {
     }
 
     /// Compacts a proof, to reduce its size.
-    pub fn compact_into(self) -> Result<SparseCompactMerkleProof<H>, BadProof> {
-        let mut th = TreeHasher::<H>::new(vec![0; TreeHasher::<H>::path_size()].into());
+    pub fn compact_into(self) -> Result<SparseCompactMerkleProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.compact_into_with(&H::default())
+    }
+
+    /// Compacts a proof of a tree hashed with the given scheme instance.
+    pub fn compact_into_with(self, scheme: &H) -> Result<SparseCompactMerkleProof<H>, BadProof> {
+        let th = TreeHasher::new(scheme.clone());
 
-        if !self.sanity_check(&mut th) {
+        if !self.sanity_check(&th) {
             return Err(BadProof);
         }
 
@@ -141,7 +235,7 @@ impl<H: digest::Digest> SparseMerkleProof<H> {
             sibling_data,
             _marker: _,
         } = self;
-        let mut bit_mask = vec![0u8; ((num_side_nodes as f64) / 8.0).ceil() as usize];
+        let mut bit_mask = vec![0u8; bitmask_len(num_side_nodes)];
 
         let compacted_side_nodes = side_nodes
             .into_iter()
@@ -170,14 +264,14 @@ impl<H: digest::Digest> SparseMerkleProof<H> {
     #[inline]
     fn verify_proof(
         &self,
+        th: &TreeHasher<H>,
         root: impl AsRef<[u8]>,
         key: impl AsRef<[u8]>,
         value: impl AsRef<[u8]>,
     ) -> bool {
-        let mut th = TreeHasher::<H>::new(vec![0; TreeHasher::<H>::path_size()].into());
         let path = th.path(key);
 
-        if !self.sanity_check(&mut th) {
+        if !self.sanity_check(th) {
             return false;
         }
 
@@ -187,8 +281,11 @@ impl<H: digest::Digest> SparseMerkleProof<H> {
             // Non-membership proof.
             match &self.non_membership_leaf_data {
                 Some(data) => {
-                    let (actual_path, value_hash) = TreeHasher::<H>::parse_leaf(data);
-                    if actual_path.eq(path.as_slice()) {
+                    let (actual_path, value_hash) = match TreeHasher::<H>::parse_leaf(data) {
+                        Some(leaf) => leaf,
+                        None => return false,
+                    };
+                    if actual_path.eq(&path) {
                         // This is not an unrelated leaf; non-membership proof failed.
                         return false;
                     }
@@ -202,34 +299,34 @@ impl<H: digest::Digest> SparseMerkleProof<H> {
         } else {
             let value_hash = th.digest(value);
 
-            current_hash = th.digest_leaf_hash(path, value_hash);
+            current_hash = th.digest_leaf_hash(&path, value_hash);
         }
         let num = self.side_nodes.len();
         // Recompute root.
-        self.side_nodes.iter().enumerate().for_each(|(idx, path)| {
-            let node = path.slice(..TreeHasher::<H>::path_size());
-            if get_bit_at_from_msb(path, num - 1 - idx) == RIGHT {
-                (current_hash, _) = th.digest_node(node, &current_hash);
-            } else {
-                (current_hash, _) = th.digest_node(&current_hash, node);
-            }
-        });
+        self.side_nodes
+            .iter()
+            .enumerate()
+            .for_each(|(idx, side_node)| {
+                let node = side_node.slice(..TreeHasher::<H>::path_size());
+                if get_bit_at_from_msb(path.as_ref(), num - 1 - idx) == RIGHT {
+                    (current_hash, _) = th.digest_node(node, &current_hash);
+                } else {
+                    (current_hash, _) = th.digest_node(&current_hash, node);
+                }
+            });
 
         current_hash.eq(root.as_ref())
     }
 
     pub(crate) fn verify_proof_with_updates(
         &self,
+        th: &TreeHasher<H>,
         root: impl AsRef<[u8]>,
         key: impl AsRef<[u8]>,
         value: impl AsRef<[u8]>,
-    ) -> (bool, Vec<(Bytes, Bytes)>)
-    where
-        H: Digest,
-    {
-        let mut th = TreeHasher::<H>::new(vec![0; TreeHasher::<H>::path_size()].into());
+    ) -> (bool, Vec<(Bytes, Bytes)>) {
         let path = th.path(key);
-        if !self.sanity_check(&mut th) {
+        if !self.sanity_check(th) {
             return (false, vec![]);
         }
 
@@ -240,8 +337,11 @@ impl<H: digest::Digest> SparseMerkleProof<H> {
             // Non-membership proof.
             match &self.non_membership_leaf_data {
                 Some(data) => {
-                    let (actual_path, value_hash) = TreeHasher::<H>::parse_leaf(data);
-                    if actual_path.eq(path.as_slice()) {
+                    let (actual_path, value_hash) = match TreeHasher::<H>::parse_leaf(data) {
+                        Some(leaf) => leaf,
+                        None => return (false, vec![]),
+                    };
+                    if actual_path.eq(&path) {
                         // This is not an unrelated leaf; non-membership proof failed.
                         return (false, vec![]);
                     }
@@ -282,26 +382,14 @@ impl<H: digest::Digest> SparseMerkleProof<H> {
         (current_hash.eq(root.as_ref()), updates)
     }
 
-    fn sanity_check(&self, th: &mut TreeHasher<H>) -> bool {
+    fn sanity_check(&self, th: &TreeHasher<H>) -> bool {
         // Do a basic sanity check on the proof, so that a malicious proof cannot
         // cause the verifier to fatally exit (e.g. due to an index out-of-range
         // error) or cause a CPU DoS attack.
-
-        // Check that the number of supplied sidenodes does not exceed the maximum possible.
-        if self.side_nodes.len() > TreeHasher::<H>::path_size() * 8 ||
-            // Check that leaf data for non-membership proofs is the correct size.
-            self.check_non_membership_proofs_size(th)
-        {
+        if !self.check_shape() {
             return false;
         }
 
-        // Check that all supplied sidenodes are the correct size.
-        for side_node in &self.side_nodes {
-            if side_node.len() != <H as digest::Digest>::output_size() {
-                return false;
-            }
-        }
-
         if self.side_nodes.is_empty() {
             return true;
         }
@@ -309,20 +397,38 @@ impl<H: digest::Digest> SparseMerkleProof<H> {
         // Check that the sibling data hashes to the first side node if not nil
         match &self.sibling_data {
             Some(sibling_data) => {
-                let sibling_hash = th.digest(sibling_data);
-                self.side_nodes[0].eq(sibling_hash.as_slice())
+                let sibling_hash = th.digest_data(sibling_data);
+                sibling_hash.as_deref() == Some(self.side_nodes[0].as_ref())
             }
             None => true,
         }
     }
 
+    /// Checks the sizes of the fields of the proof, which unlike the sibling data do
+    /// not depend on the scheme instance the proof was made with.
+    fn check_shape(&self) -> bool {
+        // Check that the number of supplied sidenodes does not exceed the maximum possible.
+        if self.side_nodes.len() > TreeHasher::<H>::path_size() * 8 ||
+            // Check that leaf data for non-membership proofs is the correct size.
+            self.check_non_membership_proofs_size()
+        {
+            return false;
+        }
+
+        // Check that all supplied sidenodes are the correct size.
+        for side_node in &self.side_nodes {
+            if side_node.len() != H::hash_size() {
+                return false;
+            }
+        }
+        true
+    }
+
     #[inline]
-    fn check_non_membership_proofs_size(&self, _th: &TreeHasher<H>) -> bool {
+    fn check_non_membership_proofs_size(&self) -> bool {
         if let Some(non_membership_proofs) = &self.non_membership_leaf_data {
             non_membership_proofs.len()
-                != LEAF_PREFIX.len()
-                    + TreeHasher::<H>::path_size()
-                    + <H as digest::Digest>::output_size()
+                != LEAF_PREFIX.len() + TreeHasher::<H>::path_size() + H::hash_size()
         } else {
             false
         }
@@ -400,8 +506,8 @@ impl<H> SparseCompactMerkleProof<H> {
     }
 }
 
-impl<H: digest::Digest> SparseCompactMerkleProof<H> {
-    fn sanity_check(&self, _th: &mut TreeHasher<H>) -> bool {
+impl<H: TreeHashScheme> SparseCompactMerkleProof<H> {
+    fn sanity_check(&self) -> bool {
         // Do a basic sanity check on the proof on the fields of the proof specific to
         // the compact proof only.
         //
@@ -412,10 +518,12 @@ impl<H: digest::Digest> SparseCompactMerkleProof<H> {
         if self.num_side_nodes > TreeHasher::<H>::path_size() * 8 ||
             // Compact proofs: check that the length of the bit mask is as expected
 		    // according to NumSideNodes.
-            self.bitmask.len() != ((self.num_side_nodes as f64 ) / 8f64).ceil() as usize ||
+            self.bitmask.len() != bitmask_len(self.num_side_nodes) ||
             // Compact proofs: check that the correct number of sidenodes have been
-		    // supplied according to the bit mask.
-            (self.num_side_nodes > 0 && self.side_nodes.len() != self.num_side_nodes - count_set_bits(&self.bitmask))
+		    // supplied according to the bit mask, which has no bits set past NumSideNodes.
+            count_set_bits(&self.bitmask, self.num_side_nodes)
+                .map(|set| self.side_nodes.len() + set)
+                != Some(self.num_side_nodes)
         {
             return false;
         }
@@ -423,26 +531,91 @@ impl<H: digest::Digest> SparseCompactMerkleProof<H> {
         true
     }
 
+    /// Checks the compact proof and the sizes of the fields of the proof it
+    /// decompacts to, without the scheme instance the proof was made with.
+    #[cfg(feature = "serde")]
+    fn check_shape(&self) -> bool {
+        // Placeholders have the right size, so only the supplied side nodes are checked.
+        self.sanity_check()
+            && SparseMerkleProof::<H>::new(
+                self.side_nodes.clone(),
+                self.non_membership_leaf_data.clone(),
+                None,
+            )
+            .check_shape()
+    }
+
     /// Verifies a Merkle proof
     pub fn verify(
         &self,
         root: impl AsRef<[u8]>,
         key: impl AsRef<[u8]>,
         value: impl AsRef<[u8]>,
+    ) -> bool
+    where
+        H: Default,
+    {
+        self.verify_with(&H::default(), root, key, value)
+    }
+
+    /// Verifies a Merkle proof of a tree hashed with the given scheme instance.
+    pub fn verify_with(
+        &self,
+        scheme: &H,
+        root: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
     ) -> bool {
-        self.decompact()
-            .map(|proof| proof.verify(root, key, value))
+        self.decompact_with(scheme)
+            .map(|proof| proof.verify_with(scheme, root, key, value))
+            .unwrap_or(false)
+    }
+
+    /// Verifies a Merkle proof of the tree with the given identifier (see
+    /// `SparseMerkleTree::with_tree_id`).
+    pub fn verify_for_tree(
+        &self,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> bool
+    where
+        H: Default,
+    {
+        self.verify_for_tree_with(&H::default(), tree_id, root, key, value)
+    }
+
+    /// Verifies a Merkle proof of the tree with the given identifier, hashed with the
+    /// given scheme instance.
+    pub fn verify_for_tree_with(
+        &self,
+        scheme: &H,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> bool {
+        self.decompact_with(scheme)
+            .map(|proof| proof.verify_for_tree_with(scheme, tree_id, root, key, value))
             .unwrap_or(false)
     }
 
     /// Decompacts a proof, so that it can be used for verify
-    pub fn decompact(&self) -> Result<SparseMerkleProof<H>, BadProof> {
-        let mut th = TreeHasher::<H>::new(vec![0; TreeHasher::<H>::path_size()].into());
+    pub fn decompact(&self) -> Result<SparseMerkleProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.decompact_with(&H::default())
+    }
 
-        if !self.sanity_check(&mut th) {
+    /// Decompacts a proof of a tree hashed with the given scheme instance.
+    pub fn decompact_with(&self, scheme: &H) -> Result<SparseMerkleProof<H>, BadProof> {
+        if !self.sanity_check() {
             return Err(BadProof);
         }
 
+        let th = TreeHasher::new(scheme.clone());
         let mut position = 0;
         let nodes = (0..self.num_side_nodes)
             .map(|idx| {
@@ -464,13 +637,20 @@ impl<H: digest::Digest> SparseCompactMerkleProof<H> {
     }
 
     /// Decompacts a proof, so that it can be used for verify
-    pub fn decompact_into(self) -> Result<SparseMerkleProof<H>, BadProof> {
-        let mut th = TreeHasher::<H>::new(vec![0; TreeHasher::<H>::path_size()].into());
+    pub fn decompact_into(self) -> Result<SparseMerkleProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.decompact_into_with(&H::default())
+    }
 
-        if !self.sanity_check(&mut th) {
+    /// Decompacts a proof of a tree hashed with the given scheme instance.
+    pub fn decompact_into_with(self, scheme: &H) -> Result<SparseMerkleProof<H>, BadProof> {
+        if !self.sanity_check() {
             return Err(BadProof);
         }
 
+        let th = TreeHasher::new(scheme.clone());
         let mut position = 0;
         let SparseCompactMerkleProof {
             side_nodes,
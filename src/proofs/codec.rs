@@ -0,0 +1,260 @@
+use super::{BadProof, SparseCompactMerkleProof, SparseMerkleProof};
+use crate::{tree_hasher::TreeHasher, TreeHashScheme};
+use alloc::vec::Vec;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use core::marker::PhantomData;
+
+/// The version of the proof encoding format.
+const ENCODING_VERSION: u8 = 1;
+
+const SPARSE_MERKLE_PROOF_TAG: u8 = 0;
+const SPARSE_COMPACT_MERKLE_PROOF_TAG: u8 = 1;
+
+// Every variable length field is prefixed with its length as a big-endian u32.
+const LEN_SIZE: usize = core::mem::size_of::<u32>();
+// The version byte followed by the proof type tag.
+const HEADER_SIZE: usize = 2;
+
+impl<H: TreeHashScheme> SparseMerkleProof<H> {
+    /// Returns the length of the encoded proof.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE
+            + LEN_SIZE
+            + self
+                .side_nodes
+                .iter()
+                .map(|node| LEN_SIZE + node.len())
+                .sum::<usize>()
+            + optional_len(&self.non_membership_leaf_data)
+            + optional_len(&self.sibling_data)
+    }
+
+    /// Encodes the proof into a new buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a length does not fit in a `u32`, which no proof passing the
+    /// sanity check does.
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.encode_to(&mut buf);
+        buf.freeze()
+    }
+
+    /// Encodes the proof into the given buffer.
+    ///
+    /// The encoding is a version byte, a type tag, the length-prefixed list of side
+    /// nodes, then the optional non-membership leaf data and sibling data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a length does not fit in a `u32`, which no proof passing the
+    /// sanity check does.
+    pub fn encode_to(&self, buf: &mut impl BufMut) {
+        buf.put_u8(ENCODING_VERSION);
+        buf.put_u8(SPARSE_MERKLE_PROOF_TAG);
+        put_list(buf, &self.side_nodes);
+        put_optional(buf, &self.non_membership_leaf_data);
+        put_optional(buf, &self.sibling_data);
+    }
+
+    /// Decodes a proof from the given buffer, returning `BadProof` if the input is
+    /// malformed or fails the sanity check.
+    pub fn decode(buf: &mut impl Buf) -> Result<Self, BadProof>
+    where
+        H: Default,
+    {
+        Self::decode_with(buf, &H::default())
+    }
+
+    /// Decodes a proof of a tree hashed with the given scheme instance.
+    pub fn decode_with(buf: &mut impl Buf, scheme: &H) -> Result<Self, BadProof> {
+        get_header(buf, SPARSE_MERKLE_PROOF_TAG)?;
+        let side_nodes = get_list(buf, TreeHasher::<H>::path_size() * 8)?;
+        let non_membership_leaf_data = get_optional(buf)?;
+        let sibling_data = get_optional(buf)?;
+
+        let proof = Self {
+            side_nodes,
+            non_membership_leaf_data,
+            sibling_data,
+            _marker: PhantomData,
+        };
+
+        let th = TreeHasher::new(scheme.clone());
+        if !proof.sanity_check(&th) {
+            return Err(BadProof);
+        }
+        Ok(proof)
+    }
+}
+
+impl<H: TreeHashScheme> SparseCompactMerkleProof<H> {
+    /// Returns the length of the encoded proof.
+    pub fn encoded_len(&self) -> usize {
+        HEADER_SIZE
+            + LEN_SIZE
+            + LEN_SIZE
+            + self.bitmask.len()
+            + LEN_SIZE
+            + self
+                .side_nodes
+                .iter()
+                .map(|node| LEN_SIZE + node.len())
+                .sum::<usize>()
+            + optional_len(&self.non_membership_leaf_data)
+            + optional_len(&self.sibling_data)
+    }
+
+    /// Encodes the proof into a new buffer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a length does not fit in a `u32`, which no proof passing the
+    /// sanity check does.
+    pub fn encode(&self) -> Bytes {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.encode_to(&mut buf);
+        buf.freeze()
+    }
+
+    /// Encodes the proof into the given buffer.
+    ///
+    /// The encoding is a version byte, a type tag, the number of side nodes when
+    /// decompacted, the length-prefixed bit mask, the length-prefixed list of side
+    /// nodes, then the optional non-membership leaf data and sibling data.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a length does not fit in a `u32`, which no proof passing the
+    /// sanity check does.
+    pub fn encode_to(&self, buf: &mut impl BufMut) {
+        buf.put_u8(ENCODING_VERSION);
+        buf.put_u8(SPARSE_COMPACT_MERKLE_PROOF_TAG);
+        put_len(buf, self.num_side_nodes);
+        put_bytes(buf, &self.bitmask);
+        put_list(buf, &self.side_nodes);
+        put_optional(buf, &self.non_membership_leaf_data);
+        put_optional(buf, &self.sibling_data);
+    }
+
+    /// Decodes a proof from the given buffer, returning `BadProof` if the input is
+    /// malformed or fails the sanity check.
+    pub fn decode(buf: &mut impl Buf) -> Result<Self, BadProof>
+    where
+        H: Default,
+    {
+        Self::decode_with(buf, &H::default())
+    }
+
+    /// Decodes a proof of a tree hashed with the given scheme instance.
+    pub fn decode_with(buf: &mut impl Buf, scheme: &H) -> Result<Self, BadProof> {
+        get_header(buf, SPARSE_COMPACT_MERKLE_PROOF_TAG)?;
+        let num_side_nodes = get_u32(buf)? as usize;
+        let bitmask = get_bytes(buf)?;
+        let side_nodes = get_list(buf, TreeHasher::<H>::path_size() * 8)?;
+        let non_membership_leaf_data = get_optional(buf)?;
+        let sibling_data = get_optional(buf)?;
+
+        let proof = Self {
+            side_nodes,
+            non_membership_leaf_data,
+            bitmask,
+            num_side_nodes,
+            sibling_data,
+            _marker: PhantomData,
+        };
+
+        let th = TreeHasher::new(scheme.clone());
+        if !proof.decompact_with(scheme)?.sanity_check(&th) {
+            return Err(BadProof);
+        }
+        Ok(proof)
+    }
+}
+
+#[inline]
+fn optional_len(data: &Option<Bytes>) -> usize {
+    1 + data.as_ref().map_or(0, |data| LEN_SIZE + data.len())
+}
+
+/// Writes a length as a big-endian u32. Truncating it would encode a different
+/// proof, so a length which does not fit is a bug of the caller.
+#[inline]
+fn put_len(buf: &mut impl BufMut, len: usize) {
+    let len = u32::try_from(len).expect("proof length does not fit in a u32");
+    buf.put_u32(len);
+}
+
+#[inline]
+fn put_bytes(buf: &mut impl BufMut, data: &[u8]) {
+    put_len(buf, data.len());
+    buf.put_slice(data);
+}
+
+#[inline]
+fn put_list(buf: &mut impl BufMut, list: &[Bytes]) {
+    put_len(buf, list.len());
+    for data in list {
+        put_bytes(buf, data);
+    }
+}
+
+#[inline]
+fn put_optional(buf: &mut impl BufMut, data: &Option<Bytes>) {
+    match data {
+        Some(data) => {
+            buf.put_u8(1);
+            put_bytes(buf, data);
+        }
+        None => buf.put_u8(0),
+    }
+}
+
+#[inline]
+fn get_header(buf: &mut impl Buf, tag: u8) -> Result<(), BadProof> {
+    if buf.remaining() < HEADER_SIZE || buf.get_u8() != ENCODING_VERSION || buf.get_u8() != tag {
+        return Err(BadProof);
+    }
+    Ok(())
+}
+
+#[inline]
+fn get_u32(buf: &mut impl Buf) -> Result<u32, BadProof> {
+    if buf.remaining() < LEN_SIZE {
+        return Err(BadProof);
+    }
+    Ok(buf.get_u32())
+}
+
+#[inline]
+fn get_bytes(buf: &mut impl Buf) -> Result<Bytes, BadProof> {
+    let len = get_u32(buf)? as usize;
+    if buf.remaining() < len {
+        return Err(BadProof);
+    }
+    Ok(buf.copy_to_bytes(len))
+}
+
+#[inline]
+fn get_list(buf: &mut impl Buf, max: usize) -> Result<Vec<Bytes>, BadProof> {
+    let len = get_u32(buf)? as usize;
+    // Check the number of entries before allocating, so that a malicious length
+    // cannot exhaust memory.
+    if len > max {
+        return Err(BadProof);
+    }
+    (0..len).map(|_| get_bytes(buf)).collect()
+}
+
+#[inline]
+fn get_optional(buf: &mut impl Buf) -> Result<Option<Bytes>, BadProof> {
+    if !buf.has_remaining() {
+        return Err(BadProof);
+    }
+    match buf.get_u8() {
+        0 => Ok(None),
+        1 => get_bytes(buf).map(Some),
+        _ => Err(BadProof),
+    }
+}
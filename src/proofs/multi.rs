@@ -0,0 +1,572 @@
+use super::BadProof;
+use crate::{
+    bitmask_len, count_set_bits, get_bit_at_from_msb, set_bit_at_from_msb,
+    smt::{DEFAULT_VALUE, RIGHT},
+    tree_hasher::{TreeHasher, LEAF_PREFIX},
+    TreeHashScheme,
+};
+use alloc::{vec, vec::Vec};
+use bytes::Bytes;
+use core::marker::PhantomData;
+
+/// SparseMerkleMultiProof is a Merkle proof for multiple elements in a SparseMerkleTree.
+///
+/// Side nodes shared by the paths of several keys are only stored once.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleMultiProof<H> {
+    /// The sibling nodes required to recompute the root, in depth-first order.
+    pub(crate) side_nodes: Vec<Bytes>,
+
+    /// For every leaf position where none of the proven keys is present, the data
+    /// of the unrelated leaf found there, or `None` if it is a placeholder. In
+    /// depth-first order.
+    pub(crate) non_membership_leaf_data: Vec<Option<Bytes>>,
+
+    /// The depth at which the path of each proven key ends, in the order the
+    /// keys were given when generating the proof.
+    pub(crate) depths: Vec<usize>,
+
+    pub(crate) _marker: PhantomData<H>,
+}
+
+impl<H> SparseMerkleMultiProof<H> {
+    /// Creates a new SparseMerkleMultiProof.
+    pub fn new(
+        side_nodes: Vec<Bytes>,
+        non_membership_leaf_data: Vec<Option<Bytes>>,
+        depths: Vec<usize>,
+    ) -> Self {
+        Self {
+            side_nodes,
+            non_membership_leaf_data,
+            depths,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get the side nodes for this proof
+    #[inline]
+    pub fn side_nodes(&self) -> &[Bytes] {
+        &self.side_nodes
+    }
+
+    /// Get the non-membership leaf data for this proof
+    #[inline]
+    pub fn non_membership_leaf_data(&self) -> &[Option<Bytes>] {
+        &self.non_membership_leaf_data
+    }
+
+    /// Get the depth at which the path of each proven key ends
+    #[inline]
+    pub fn depths(&self) -> &[usize] {
+        &self.depths
+    }
+}
+
+impl<H: TreeHashScheme> SparseMerkleMultiProof<H> {
+    /// Verifies a Merkle multi proof.
+    ///
+    /// `entries` are the `(key, value)` pairs being proven, in the same order as the keys
+    /// were given to `prove_many`. An empty value proves that the key is not in the tree.
+    pub fn verify<K, V>(
+        &self,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        H: Default,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.verify_with(&H::default(), root, entries)
+    }
+
+    /// Verifies a Merkle multi proof of a tree hashed with the given scheme instance.
+    pub fn verify_with<K, V>(
+        &self,
+        scheme: &H,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.verify_hashed(&TreeHasher::new(scheme.clone()), root, entries)
+    }
+
+    /// Verifies a Merkle multi proof of the tree with the given identifier (see
+    /// `SparseMerkleTree::with_tree_id`).
+    pub fn verify_for_tree<K, V>(
+        &self,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        H: Default,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.verify_for_tree_with(&H::default(), tree_id, root, entries)
+    }
+
+    /// Verifies a Merkle multi proof of the tree with the given identifier, hashed
+    /// with the given scheme instance.
+    pub fn verify_for_tree_with<K, V>(
+        &self,
+        scheme: &H,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let th = TreeHasher::new(scheme.clone()).with_tree_id(Some(tree_id.as_ref()));
+        self.verify_hashed(&th, root, entries)
+    }
+
+    fn verify_hashed<K, V>(
+        &self,
+        th: &TreeHasher<H>,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if !self.sanity_check(th) {
+            return false;
+        }
+
+        let mut items = Vec::with_capacity(self.depths.len());
+        for (idx, (key, value)) in entries.into_iter().enumerate() {
+            let depth = match self.depths.get(idx) {
+                Some(depth) => *depth,
+                None => return false,
+            };
+            let value = value.as_ref();
+            let value_hash = if value.eq(&DEFAULT_VALUE) {
+                None
+            } else {
+                Some(th.digest(value))
+            };
+            items.push(MultiProofItem {
+                path: th.path_into(key),
+                value_hash,
+                depth,
+            });
+        }
+
+        if items.len() != self.depths.len() {
+            return false;
+        }
+
+        if items.is_empty() {
+            // Nothing to prove.
+            return self.side_nodes.is_empty() && self.non_membership_leaf_data.is_empty();
+        }
+
+        items.sort_by(|a, b| a.path.cmp(&b.path));
+        // The same key may be proven more than once, but it must always be proven
+        // at the same depth with the same value.
+        for pair in items.windows(2) {
+            if pair[0].path.eq(&pair[1].path)
+                && (pair[0].depth != pair[1].depth || pair[0].value_hash != pair[1].value_hash)
+            {
+                return false;
+            }
+        }
+        items.dedup_by(|a, b| a.path.eq(&b.path));
+
+        let mut side_nodes = self.side_nodes.iter();
+        let mut leaf_data = self.non_membership_leaf_data.iter();
+        let current_hash = match self.recompute(th, 0, &items, &mut side_nodes, &mut leaf_data) {
+            Some(hash) => hash,
+            None => return false,
+        };
+
+        // Every part of the proof must have been used.
+        side_nodes.next().is_none() && leaf_data.next().is_none() && current_hash.eq(root.as_ref())
+    }
+
+    /// Compacts a proof, to reduce its size.
+    pub fn compact(&self) -> Result<SparseCompactMerkleMultiProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.compact_with(&H::default())
+    }
+
+    /// Compacts a proof of a tree hashed with the given scheme instance.
+    pub fn compact_with(&self, scheme: &H) -> Result<SparseCompactMerkleMultiProof<H>, BadProof> {
+        SparseMerkleMultiProof::new(
+            self.side_nodes.clone(),
+            self.non_membership_leaf_data.clone(),
+            self.depths.clone(),
+        )
+        .compact_into_with(scheme)
+    }
+
+    /// Compacts a proof, to reduce its size.
+    pub fn compact_into(self) -> Result<SparseCompactMerkleMultiProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.compact_into_with(&H::default())
+    }
+
+    /// Compacts a proof of a tree hashed with the given scheme instance.
+    pub fn compact_into_with(
+        self,
+        scheme: &H,
+    ) -> Result<SparseCompactMerkleMultiProof<H>, BadProof> {
+        let th = TreeHasher::new(scheme.clone());
+
+        if !self.sanity_check(&th) {
+            return Err(BadProof);
+        }
+
+        let num_side_nodes = self.side_nodes.len();
+        let SparseMerkleMultiProof {
+            side_nodes,
+            non_membership_leaf_data,
+            depths,
+            _marker,
+        } = self;
+        let mut bit_mask = vec![0u8; bitmask_len(num_side_nodes)];
+
+        let compacted_side_nodes = side_nodes
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, node)| {
+                if node.eq(th.placeholder_ref()) {
+                    set_bit_at_from_msb(bit_mask.as_mut_slice(), idx);
+                    None
+                } else {
+                    Some(node)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(SparseCompactMerkleMultiProof {
+            side_nodes: compacted_side_nodes,
+            non_membership_leaf_data,
+            bitmask: bit_mask.into(),
+            num_side_nodes,
+            depths,
+            _marker,
+        })
+    }
+
+    /// Recomputes the hash of the subtree at `depth`, which contains the paths of `items`.
+    fn recompute<'a>(
+        &self,
+        th: &TreeHasher<H>,
+        depth: usize,
+        items: &[MultiProofItem],
+        side_nodes: &mut impl Iterator<Item = &'a Bytes>,
+        leaf_data: &mut impl Iterator<Item = &'a Option<Bytes>>,
+    ) -> Option<Bytes> {
+        if items.iter().all(|item| item.depth == depth) {
+            // All the remaining paths end at the same leaf position.
+            let mut members = items.iter().filter(|item| item.value_hash.is_some());
+            return match (members.next(), members.next()) {
+                (Some(item), None) => {
+                    Some(th.digest_leaf_hash(&item.path, item.value_hash.as_ref().unwrap()))
+                }
+                // Two different keys cannot share one leaf.
+                (Some(_), Some(_)) => None,
+                // Non-membership proof.
+                (None, _) => match leaf_data.next()? {
+                    Some(data) => {
+                        let (actual_path, value_hash) = TreeHasher::<H>::parse_leaf(data)?;
+                        if items.iter().any(|item| item.path.eq(actual_path)) {
+                            // This is not an unrelated leaf; non-membership proof failed.
+                            return None;
+                        }
+                        Some(th.digest_leaf_hash(actual_path, value_hash))
+                    }
+                    None => Some(th.placeholder()),
+                },
+            };
+        }
+
+        if depth >= TreeHasher::<H>::path_size() * 8 || items.iter().any(|item| item.depth == depth)
+        {
+            return None;
+        }
+
+        let split = items.partition_point(|item| get_bit_at_from_msb(&item.path, depth) != RIGHT);
+        let (left, right) = items.split_at(split);
+        let left = if left.is_empty() {
+            side_nodes.next()?.clone()
+        } else {
+            self.recompute(th, depth + 1, left, side_nodes, leaf_data)?
+        };
+        let right = if right.is_empty() {
+            side_nodes.next()?.clone()
+        } else {
+            self.recompute(th, depth + 1, right, side_nodes, leaf_data)?
+        };
+        let (hash, _) = th.digest_node(left, right);
+        Some(hash)
+    }
+
+    fn sanity_check(&self, _th: &TreeHasher<H>) -> bool {
+        // Do a basic sanity check on the proof, so that a malicious proof cannot
+        // cause the verifier to fatally exit or cause a CPU DoS attack.
+        let max_depth = TreeHasher::<H>::path_size() * 8;
+
+        // Check that the depths and the number of side nodes are within range.
+        if self.depths.iter().any(|depth| *depth > max_depth)
+            || self.side_nodes.len() > self.depths.len() * max_depth
+            || self.non_membership_leaf_data.len() > self.depths.len()
+        {
+            return false;
+        }
+
+        // Check that all supplied sidenodes are the correct size.
+        if self
+            .side_nodes
+            .iter()
+            .any(|side_node| side_node.len() != H::hash_size())
+        {
+            return false;
+        }
+
+        // Check that leaf data for non-membership proofs is the correct size.
+        self.non_membership_leaf_data.iter().flatten().all(|data| {
+            data.len() == LEAF_PREFIX.len() + TreeHasher::<H>::path_size() + H::hash_size()
+        })
+    }
+}
+
+/// SparseCompactMerkleMultiProof is a compact Merkle proof for multiple elements in a SparseMerkleTree.
+#[derive(Debug, Clone)]
+pub struct SparseCompactMerkleMultiProof<H> {
+    /// The non-placeholder sibling nodes required to recompute the root, in depth-first order.
+    pub(crate) side_nodes: Vec<Bytes>,
+
+    /// The data of the unrelated leaves at the positions of the keys being
+    /// proven not to be in the tree, in depth-first order.
+    pub(crate) non_membership_leaf_data: Vec<Option<Bytes>>,
+
+    /// BitMask is a bit mask of the sidenodes of the proof where an on-bit
+    /// indicates that the sidenode at the bit's index is a placeholder.
+    pub(crate) bitmask: Bytes,
+
+    /// Indicates the number of sidenodes in the proof when decompacted.
+    pub(crate) num_side_nodes: usize,
+
+    /// The depth at which the path of each proven key ends.
+    pub(crate) depths: Vec<usize>,
+
+    pub(crate) _marker: PhantomData<H>,
+}
+
+impl<H> SparseCompactMerkleMultiProof<H> {
+    /// Creates a new SparseCompactMerkleMultiProof.
+    pub fn new(
+        side_nodes: Vec<Bytes>,
+        non_membership_leaf_data: Vec<Option<Bytes>>,
+        bitmask: Bytes,
+        num_side_nodes: usize,
+        depths: Vec<usize>,
+    ) -> Self {
+        Self {
+            side_nodes,
+            non_membership_leaf_data,
+            bitmask,
+            num_side_nodes,
+            depths,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get the side nodes for this compacted proof
+    #[inline]
+    pub fn side_nodes(&self) -> &[Bytes] {
+        &self.side_nodes
+    }
+
+    /// Get the non-membership leaf data for this proof
+    #[inline]
+    pub fn non_membership_leaf_data(&self) -> &[Option<Bytes>] {
+        &self.non_membership_leaf_data
+    }
+
+    /// Get the bit mask of the placeholder side nodes
+    #[inline]
+    pub fn bitmask(&self) -> &Bytes {
+        &self.bitmask
+    }
+
+    /// Get the original number of side nodes
+    #[inline]
+    pub fn original_side_nodes_len(&self) -> usize {
+        self.num_side_nodes
+    }
+
+    /// Get the depth at which the path of each proven key ends
+    #[inline]
+    pub fn depths(&self) -> &[usize] {
+        &self.depths
+    }
+}
+
+impl<H: TreeHashScheme> SparseCompactMerkleMultiProof<H> {
+    fn sanity_check(&self, _th: &TreeHasher<H>) -> bool {
+        // Do a basic sanity check on the fields specific to the compact proof.
+        // The sanity check of the de-compacted proof is executed when it is verified.
+
+        // Check that the number of side nodes is within the right range.
+        self.num_side_nodes <= self.depths.len() * TreeHasher::<H>::path_size() * 8
+            // Check that the length of the bit mask is as expected according to
+            // the number of side nodes.
+            && self.bitmask.len() == bitmask_len(self.num_side_nodes)
+            // Check that the correct number of side nodes have been supplied
+            // according to the bit mask, which has no bits set past them.
+            && count_set_bits(&self.bitmask, self.num_side_nodes)
+                .map(|set| self.side_nodes.len() + set)
+                == Some(self.num_side_nodes)
+    }
+
+    /// Verifies a Merkle multi proof
+    pub fn verify<K, V>(
+        &self,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        H: Default,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.verify_with(&H::default(), root, entries)
+    }
+
+    /// Verifies a Merkle multi proof of a tree hashed with the given scheme instance.
+    pub fn verify_with<K, V>(
+        &self,
+        scheme: &H,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.decompact_with(scheme)
+            .map(|proof| proof.verify_with(scheme, root, entries))
+            .unwrap_or(false)
+    }
+
+    /// Verifies a Merkle multi proof of the tree with the given identifier (see
+    /// `SparseMerkleTree::with_tree_id`).
+    pub fn verify_for_tree<K, V>(
+        &self,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        H: Default,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.verify_for_tree_with(&H::default(), tree_id, root, entries)
+    }
+
+    /// Verifies a Merkle multi proof of the tree with the given identifier, hashed
+    /// with the given scheme instance.
+    pub fn verify_for_tree_with<K, V>(
+        &self,
+        scheme: &H,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> bool
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.decompact_with(scheme)
+            .map(|proof| proof.verify_for_tree_with(scheme, tree_id, root, entries))
+            .unwrap_or(false)
+    }
+
+    /// Decompacts a proof, so that it can be used for verify
+    pub fn decompact(&self) -> Result<SparseMerkleMultiProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.decompact_with(&H::default())
+    }
+
+    /// Decompacts a proof of a tree hashed with the given scheme instance.
+    pub fn decompact_with(&self, scheme: &H) -> Result<SparseMerkleMultiProof<H>, BadProof> {
+        SparseCompactMerkleMultiProof::new(
+            self.side_nodes.clone(),
+            self.non_membership_leaf_data.clone(),
+            self.bitmask.clone(),
+            self.num_side_nodes,
+            self.depths.clone(),
+        )
+        .decompact_into_with(scheme)
+    }
+
+    /// Decompacts a proof, so that it can be used for verify
+    pub fn decompact_into(self) -> Result<SparseMerkleMultiProof<H>, BadProof>
+    where
+        H: Default,
+    {
+        self.decompact_into_with(&H::default())
+    }
+
+    /// Decompacts a proof of a tree hashed with the given scheme instance.
+    pub fn decompact_into_with(self, scheme: &H) -> Result<SparseMerkleMultiProof<H>, BadProof> {
+        let th = TreeHasher::new(scheme.clone());
+
+        if !self.sanity_check(&th) {
+            return Err(BadProof);
+        }
+
+        let SparseCompactMerkleMultiProof {
+            side_nodes,
+            non_membership_leaf_data,
+            bitmask,
+            num_side_nodes,
+            depths,
+            _marker,
+        } = self;
+
+        let mut side_nodes = side_nodes.into_iter();
+        let nodes = (0..num_side_nodes)
+            .map(|idx| {
+                if get_bit_at_from_msb(&bitmask, idx) == 1 {
+                    th.placeholder()
+                } else {
+                    // The sanity check guarantees there are enough side nodes.
+                    side_nodes.next().unwrap_or_default()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        Ok(SparseMerkleMultiProof {
+            side_nodes: nodes,
+            non_membership_leaf_data,
+            depths,
+            _marker,
+        })
+    }
+}
+
+struct MultiProofItem {
+    path: Bytes,
+    value_hash: Option<Bytes>,
+    depth: usize,
+}
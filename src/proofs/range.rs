@@ -0,0 +1,413 @@
+use crate::{
+    count_common_prefix, get_bit_at_from_msb, set_bit_at_from_msb,
+    smt::RIGHT,
+    tree_hasher::{TreeHasher, LEAF_PREFIX},
+    TreeHashScheme,
+};
+use alloc::{vec, vec::Vec};
+use bytes::Bytes;
+use core::marker::PhantomData;
+
+/// SparseMerkleRangeProof is a Merkle proof that a list of leaves is exactly the set
+/// of leaves of a SparseMerkleTree whose paths lie in a contiguous range.
+///
+/// Leaves are ordered by path, so a range proof allows a tree to be transferred in
+/// independently verifiable chunks.
+#[derive(Debug, Clone)]
+pub struct SparseMerkleRangeProof<H> {
+    /// The first path of the range, inclusive.
+    pub(crate) start_path: Bytes,
+
+    /// The last path of the range, inclusive.
+    pub(crate) end_path: Bytes,
+
+    /// The roots of the subtrees outside of the range which are siblings of
+    /// subtrees overlapping the range, in depth-first order.
+    pub(crate) side_nodes: Vec<Bytes>,
+
+    /// For every position at the boundary of the range holding no leaf of the
+    /// range, the data of the leaf outside of the range found there, or `None` if
+    /// it is a placeholder. In depth-first order.
+    pub(crate) boundary_leaf_data: Vec<Option<Bytes>>,
+
+    /// The depth at which the path of the first path of the range ends.
+    pub(crate) start_depth: usize,
+
+    /// The depth at which the path of the last path of the range ends.
+    pub(crate) end_depth: usize,
+
+    pub(crate) _marker: PhantomData<H>,
+}
+
+impl<H> SparseMerkleRangeProof<H> {
+    /// Creates a new SparseMerkleRangeProof.
+    pub fn new(
+        start_path: Bytes,
+        end_path: Bytes,
+        side_nodes: Vec<Bytes>,
+        boundary_leaf_data: Vec<Option<Bytes>>,
+        start_depth: usize,
+        end_depth: usize,
+    ) -> Self {
+        Self {
+            start_path,
+            end_path,
+            side_nodes,
+            boundary_leaf_data,
+            start_depth,
+            end_depth,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get the first path of the range, inclusive
+    #[inline]
+    pub fn start_path(&self) -> &Bytes {
+        &self.start_path
+    }
+
+    /// Get the last path of the range, inclusive
+    #[inline]
+    pub fn end_path(&self) -> &Bytes {
+        &self.end_path
+    }
+
+    /// Get the side nodes for this proof
+    #[inline]
+    pub fn side_nodes(&self) -> &[Bytes] {
+        &self.side_nodes
+    }
+
+    /// Get the data of the leaves outside of the range at the boundary of the range
+    #[inline]
+    pub fn boundary_leaf_data(&self) -> &[Option<Bytes>] {
+        &self.boundary_leaf_data
+    }
+
+    /// Get the depth at which the first path of the range ends
+    #[inline]
+    pub fn start_depth(&self) -> usize {
+        self.start_depth
+    }
+
+    /// Get the depth at which the last path of the range ends
+    #[inline]
+    pub fn end_depth(&self) -> usize {
+        self.end_depth
+    }
+}
+
+impl<H: TreeHashScheme> SparseMerkleRangeProof<H> {
+    /// Verifies a Merkle range proof.
+    ///
+    /// `leaves` are the `(path, value)` pairs of the leaves in the range. The proof only
+    /// verifies if they are exactly the leaves of the tree whose paths lie in the range,
+    /// so no leaf was omitted.
+    pub fn verify<P, V>(
+        &self,
+        root: impl AsRef<[u8]>,
+        leaves: impl IntoIterator<Item = (P, V)>,
+    ) -> bool
+    where
+        H: Default,
+        P: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.verify_with(&H::default(), root, leaves)
+    }
+
+    /// Verifies a Merkle range proof of a tree hashed with the given scheme instance.
+    pub fn verify_with<P, V>(
+        &self,
+        scheme: &H,
+        root: impl AsRef<[u8]>,
+        leaves: impl IntoIterator<Item = (P, V)>,
+    ) -> bool
+    where
+        P: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.verify_with_updates(&TreeHasher::new(scheme.clone()), root, leaves)
+            .0
+    }
+
+    /// Verifies a Merkle range proof of the tree with the given identifier (see
+    /// `SparseMerkleTree::with_tree_id`).
+    pub fn verify_for_tree<P, V>(
+        &self,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        leaves: impl IntoIterator<Item = (P, V)>,
+    ) -> bool
+    where
+        H: Default,
+        P: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        self.verify_for_tree_with(&H::default(), tree_id, root, leaves)
+    }
+
+    /// Verifies a Merkle range proof of the tree with the given identifier, hashed
+    /// with the given scheme instance.
+    pub fn verify_for_tree_with<P, V>(
+        &self,
+        scheme: &H,
+        tree_id: impl AsRef<[u8]>,
+        root: impl AsRef<[u8]>,
+        leaves: impl IntoIterator<Item = (P, V)>,
+    ) -> bool
+    where
+        P: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        let th = TreeHasher::new(scheme.clone()).with_tree_id(Some(tree_id.as_ref()));
+        self.verify_with_updates(&th, root, leaves).0
+    }
+
+    /// Verifies a Merkle range proof, also returning the `(hash, data)` of every node of
+    /// the tree recomputed from the leaves of the range.
+    pub(crate) fn verify_with_updates<P, V>(
+        &self,
+        th: &TreeHasher<H>,
+        root: impl AsRef<[u8]>,
+        leaves: impl IntoIterator<Item = (P, V)>,
+    ) -> (bool, Vec<(Bytes, Bytes)>)
+    where
+        P: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if !self.sanity_check(th) {
+            return (false, vec![]);
+        }
+
+        let mut items = Vec::new();
+        for (path, value) in leaves {
+            let (path, value) = (path.as_ref(), value.as_ref());
+            if path.len() != TreeHasher::<H>::path_size()
+                || value.is_empty()
+                || !in_range(path, &self.start_path, &self.end_path)
+            {
+                return (false, vec![]);
+            }
+            items.push(RangeProofLeaf {
+                path: Bytes::copy_from_slice(path),
+                value_hash: th.digest(value),
+            });
+        }
+        items.sort_by(|a, b| a.path.cmp(&b.path));
+        if items.windows(2).any(|pair| pair[0].path.eq(&pair[1].path)) {
+            // A path cannot hold several leaves.
+            return (false, vec![]);
+        }
+
+        let mut side_nodes = self.side_nodes.iter();
+        let mut leaf_data = self.boundary_leaf_data.iter();
+        let mut prefix = vec![0; TreeHasher::<H>::path_size()];
+        let mut updates = Vec::new();
+        let current_hash = match self.recompute(
+            th,
+            0,
+            &mut prefix,
+            &items,
+            &mut side_nodes,
+            &mut leaf_data,
+            &mut updates,
+        ) {
+            Some(hash) => hash,
+            None => return (false, vec![]),
+        };
+
+        // Every part of the proof must have been used.
+        if side_nodes.next().is_none()
+            && leaf_data.next().is_none()
+            && current_hash.eq(root.as_ref())
+        {
+            (true, updates)
+        } else {
+            (false, vec![])
+        }
+    }
+
+    /// Recomputes the hash of the subtree at `depth` whose paths start with the first
+    /// `depth` bits of `prefix`, and which contains the leaves of the range `items`.
+    #[allow(clippy::too_many_arguments)]
+    fn recompute<'a>(
+        &self,
+        th: &TreeHasher<H>,
+        depth: usize,
+        prefix: &mut Vec<u8>,
+        items: &[RangeProofLeaf],
+        side_nodes: &mut impl Iterator<Item = &'a Bytes>,
+        leaf_data: &mut impl Iterator<Item = &'a Option<Bytes>>,
+        updates: &mut Vec<(Bytes, Bytes)>,
+    ) -> Option<Bytes> {
+        match overlap(prefix, depth, &self.start_path, &self.end_path) {
+            // The subtree holds no leaf of the range.
+            Overlap::Disjoint => return side_nodes.next().cloned(),
+            // The subtree only holds leaves of the range, so it is made of `items`.
+            Overlap::Contained => return Some(build(th, depth, items, updates)),
+            Overlap::Partial => {}
+        }
+
+        let on_start = count_common_prefix(prefix, &self.start_path) >= depth;
+        let on_end = count_common_prefix(prefix, &self.end_path) >= depth;
+        let start_ends = on_start && depth == self.start_depth;
+        let end_ends = on_end && depth == self.end_depth;
+        if on_start && on_end && start_ends != end_ends {
+            return None;
+        }
+
+        if start_ends || end_ends {
+            // We've reached a leaf or a placeholder at the boundary of the range.
+            return match items {
+                [] => match leaf_data.next()? {
+                    Some(data) => {
+                        let (actual_path, value_hash) = TreeHasher::<H>::parse_leaf(data)?;
+                        if in_range(actual_path, &self.start_path, &self.end_path)
+                            || count_common_prefix(prefix, actual_path) < depth
+                        {
+                            // This leaf belongs to the range; it was omitted.
+                            return None;
+                        }
+                        Some(push_update(
+                            updates,
+                            th.digest_leaf(actual_path, value_hash),
+                        ))
+                    }
+                    None => Some(th.placeholder()),
+                },
+                [item] => Some(push_update(
+                    updates,
+                    th.digest_leaf(&item.path, &item.value_hash),
+                )),
+                // A leaf position cannot hold several leaves.
+                _ => None,
+            };
+        }
+
+        if depth >= TreeHasher::<H>::path_size() * 8 {
+            return None;
+        }
+
+        let split = items.partition_point(|item| get_bit_at_from_msb(&item.path, depth) != RIGHT);
+        let (left, right) = items.split_at(split);
+        let left = self.recompute(th, depth + 1, prefix, left, side_nodes, leaf_data, updates)?;
+        set_bit_at_from_msb(prefix, depth);
+        let right = self.recompute(th, depth + 1, prefix, right, side_nodes, leaf_data, updates);
+        clear_bit_at_from_msb(prefix, depth);
+        Some(push_update(updates, th.digest_node(left, right?)))
+    }
+
+    fn sanity_check(&self, _th: &TreeHasher<H>) -> bool {
+        // Do a basic sanity check on the proof, so that a malicious proof cannot
+        // cause the verifier to fatally exit or cause a CPU DoS attack.
+        let path_size = TreeHasher::<H>::path_size();
+        let max_depth = path_size * 8;
+
+        // Check that the range is well formed.
+        if self.start_path.len() != path_size
+            || self.end_path.len() != path_size
+            || self.start_path > self.end_path
+        {
+            return false;
+        }
+
+        // Check that the depths and the number of side nodes are within range. Only
+        // the subtrees along the two boundary paths can have side nodes.
+        if self.start_depth > max_depth
+            || self.end_depth > max_depth
+            || self.side_nodes.len() > 2 * max_depth
+            || self.boundary_leaf_data.len() > 2
+        {
+            return false;
+        }
+
+        // Check that all supplied sidenodes are the correct size.
+        if self
+            .side_nodes
+            .iter()
+            .any(|side_node| side_node.len() != H::hash_size())
+        {
+            return false;
+        }
+
+        // Check that the boundary leaf data is the correct size.
+        self.boundary_leaf_data
+            .iter()
+            .flatten()
+            .all(|data| data.len() == LEAF_PREFIX.len() + path_size + H::hash_size())
+    }
+}
+
+/// A leaf of the range being verified.
+struct RangeProofLeaf {
+    path: Bytes,
+    value_hash: Bytes,
+}
+
+/// How the paths of a subtree relate to a range of paths.
+pub(crate) enum Overlap {
+    /// No path of the subtree is in the range.
+    Disjoint,
+    /// Every path of the subtree is in the range.
+    Contained,
+    /// Some paths of the subtree are in the range.
+    Partial,
+}
+
+/// Returns how the subtree at `depth`, whose paths start with the first `depth` bits
+/// of `prefix`, relates to the range `[start, end]`. The remaining bits of `prefix`
+/// must be zero.
+pub(crate) fn overlap(prefix: &[u8], depth: usize, start: &[u8], end: &[u8]) -> Overlap {
+    let first = prefix;
+    let mut last = prefix.to_vec();
+    for i in depth..last.len() * 8 {
+        set_bit_at_from_msb(&mut last, i);
+    }
+
+    if last.as_slice() < start || first > end {
+        Overlap::Disjoint
+    } else if first >= start && last.as_slice() <= end {
+        Overlap::Contained
+    } else {
+        Overlap::Partial
+    }
+}
+
+#[inline]
+pub(crate) fn in_range(path: &[u8], start: &[u8], end: &[u8]) -> bool {
+    start <= path && path <= end
+}
+
+#[inline]
+pub(crate) fn clear_bit_at_from_msb(data: &mut [u8], position: usize) {
+    data[position / 8] &= !(1 << (8 - 1 - (position % 8)));
+}
+
+/// Builds the hash of the subtree at `depth` made of the leaves `items`, sorted by path.
+fn build<H: TreeHashScheme>(
+    th: &TreeHasher<H>,
+    depth: usize,
+    items: &[RangeProofLeaf],
+    updates: &mut Vec<(Bytes, Bytes)>,
+) -> Bytes {
+    match items {
+        [] => th.placeholder(),
+        // A single leaf is bubbled up as high as possible.
+        [item] => push_update(updates, th.digest_leaf(&item.path, &item.value_hash)),
+        _ => {
+            let split =
+                items.partition_point(|item| get_bit_at_from_msb(&item.path, depth) != RIGHT);
+            let left = build(th, depth + 1, &items[..split], updates);
+            let right = build(th, depth + 1, &items[split..], updates);
+            push_update(updates, th.digest_node(left, right))
+        }
+    }
+}
+
+/// Records a recomputed node, returning its hash.
+#[inline]
+fn push_update(updates: &mut Vec<(Bytes, Bytes)>, (hash, data): (Bytes, Bytes)) -> Bytes {
+    updates.push((hash.clone(), data));
+    hash
+}
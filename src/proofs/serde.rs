@@ -0,0 +1,242 @@
+use super::{BadProof, SparseCompactMerkleProof, SparseMerkleProof};
+use crate::{tree_hasher::TreeHasher, TreeHashScheme};
+use alloc::{string::String, vec::Vec};
+use bytes::Bytes;
+use core::{fmt, marker::PhantomData};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+const HEX_CHARS: &[u8; 16] = b"0123456789abcdef";
+
+/// Bytes which are hex encoded in human-readable formats, and raw bytes otherwise.
+struct HexBytes(Bytes);
+
+impl Serialize for HexBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let mut hex = String::with_capacity(self.0.len() * 2);
+            for byte in self.0.iter() {
+                hex.push(HEX_CHARS[(byte >> 4) as usize] as char);
+                hex.push(HEX_CHARS[(byte & 0xf) as usize] as char);
+            }
+            serializer.serialize_str(&hex)
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for HexBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(HexBytesVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(HexBytesVisitor)
+        }
+    }
+}
+
+struct HexBytesVisitor;
+
+impl<'de> de::Visitor<'de> for HexBytesVisitor {
+    type Value = HexBytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a hex string or a byte array")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let v = v.as_bytes();
+        if v.len() & 1 != 0 {
+            return Err(E::invalid_length(v.len(), &self));
+        }
+
+        let nibble = |c: u8| match c {
+            b'0'..=b'9' => Ok(c - b'0'),
+            b'a'..=b'f' => Ok(c - b'a' + 10),
+            b'A'..=b'F' => Ok(c - b'A' + 10),
+            _ => Err(E::invalid_value(de::Unexpected::Char(c as char), &self)),
+        };
+        v.chunks(2)
+            .map(|pair| Ok((nibble(pair[0])? << 4) | nibble(pair[1])?))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|bytes| HexBytes(bytes.into()))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(HexBytes(Bytes::copy_from_slice(v)))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(HexBytes(v.into()))
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(1024));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(HexBytes(bytes.into()))
+    }
+}
+
+/// The side nodes of a proof, of which there are at most as many as the bits of a
+/// path. Longer lists are rejected while deserializing, before they are allocated.
+struct SideNodes<H>(Vec<HexBytes>, PhantomData<H>);
+
+impl<H> SideNodes<H> {
+    fn new(nodes: &[Bytes]) -> Self {
+        Self(nodes.iter().cloned().map(HexBytes).collect(), PhantomData)
+    }
+
+    fn into_inner(self) -> Vec<Bytes> {
+        self.0.into_iter().map(|node| node.0).collect()
+    }
+}
+
+impl<H> Serialize for SideNodes<H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, H: TreeHashScheme> Deserialize<'de> for SideNodes<H> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(SideNodesVisitor(PhantomData))
+    }
+}
+
+struct SideNodesVisitor<H>(PhantomData<H>);
+
+impl<'de, H: TreeHashScheme> de::Visitor<'de> for SideNodesVisitor<H> {
+    type Value = SideNodes<H>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a list of at most {} side nodes",
+            TreeHasher::<H>::path_size() * 8
+        )
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let max = TreeHasher::<H>::path_size() * 8;
+        let mut nodes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(max));
+        while let Some(node) = seq.next_element()? {
+            if nodes.len() == max {
+                return Err(de::Error::invalid_length(max + 1, &self));
+            }
+            nodes.push(node);
+        }
+        Ok(SideNodes(nodes, PhantomData))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "SparseMerkleProof", bound = "H: TreeHashScheme")]
+struct SparseMerkleProofRepr<H> {
+    side_nodes: SideNodes<H>,
+    non_membership_leaf_data: Option<HexBytes>,
+    sibling_data: Option<HexBytes>,
+}
+
+impl<H: TreeHashScheme> Serialize for SparseMerkleProof<H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SparseMerkleProofRepr::<H> {
+            side_nodes: SideNodes::new(&self.side_nodes),
+            non_membership_leaf_data: self.non_membership_leaf_data.clone().map(HexBytes),
+            sibling_data: self.sibling_data.clone().map(HexBytes),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, H: TreeHashScheme> Deserialize<'de> for SparseMerkleProof<H> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = SparseMerkleProofRepr::<H>::deserialize(deserializer)?;
+        let proof = SparseMerkleProof::new(
+            repr.side_nodes.into_inner(),
+            repr.non_membership_leaf_data.map(|data| data.0),
+            repr.sibling_data.map(|data| data.0),
+        );
+
+        // The sibling data is checked against the scheme instance on verification.
+        if !proof.check_shape() {
+            return Err(de::Error::custom(BadProof));
+        }
+        Ok(proof)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "SparseCompactMerkleProof", bound = "H: TreeHashScheme")]
+struct SparseCompactMerkleProofRepr<H> {
+    side_nodes: SideNodes<H>,
+    non_membership_leaf_data: Option<HexBytes>,
+    bitmask: HexBytes,
+    num_side_nodes: u64,
+    sibling_data: Option<HexBytes>,
+}
+
+impl<H: TreeHashScheme> Serialize for SparseCompactMerkleProof<H> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SparseCompactMerkleProofRepr::<H> {
+            side_nodes: SideNodes::new(&self.side_nodes),
+            non_membership_leaf_data: self.non_membership_leaf_data.clone().map(HexBytes),
+            bitmask: HexBytes(self.bitmask.clone()),
+            num_side_nodes: self.num_side_nodes as u64,
+            sibling_data: self.sibling_data.clone().map(HexBytes),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, H: TreeHashScheme> Deserialize<'de> for SparseCompactMerkleProof<H> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let repr = SparseCompactMerkleProofRepr::<H>::deserialize(deserializer)?;
+        // Decompacting allocates `num_side_nodes` side nodes, so check it first.
+        let num_side_nodes = usize::try_from(repr.num_side_nodes)
+            .ok()
+            .filter(|&num| num <= TreeHasher::<H>::path_size() * 8)
+            .ok_or_else(|| de::Error::custom(BadProof))?;
+        let proof = SparseCompactMerkleProof::new(
+            repr.side_nodes.into_inner(),
+            repr.non_membership_leaf_data.map(|data| data.0),
+            repr.bitmask.0,
+            num_side_nodes,
+            repr.sibling_data.map(|data| data.0),
+        );
+
+        // Run the checks of decompacting and then verifying the proof which do not
+        // need the scheme instance.
+        if !proof.check_shape() {
+            return Err(de::Error::custom(BadProof));
+        }
+        Ok(proof)
+    }
+}
+
+impl Serialize for BadProof {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit_struct("BadProof")
+    }
+}
+
+impl<'de> Deserialize<'de> for BadProof {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct BadProofVisitor;
+
+        impl<'de> de::Visitor<'de> for BadProofVisitor {
+            type Value = BadProof;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("unit struct BadProof")
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+                Ok(BadProof)
+            }
+        }
+
+        deserializer.deserialize_unit_struct("BadProof", BadProofVisitor)
+    }
+}
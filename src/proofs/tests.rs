@@ -60,6 +60,22 @@ fn test_proofs_basic() {
 }
 
 // Test sanity check cases for non-compact proofs.
+// Proofs of every leaf of a tree deep enough for side nodes to lie on both sides of
+// the path, which only verify if the path bits are read from the key path.
+#[test]
+fn test_proofs_verify_path_bits() {
+    let mut smt = new_sparse_merkle_tree();
+    for i in 0..32u8 {
+        smt.update(&[i], Bytes::from(vec![i; 4])).unwrap();
+    }
+    let root = smt.root();
+    for i in 0..32u8 {
+        let proof = smt.prove([i]).unwrap();
+        assert!(proof.verify(root.clone(), [i], [i; 4]));
+        assert!(!proof.verify(root.clone(), [i], [i; 3]));
+    }
+}
+
 #[test]
 fn test_proofs_sanity_check() {
     let mut smt = new_sparse_merkle_tree();
@@ -70,7 +86,7 @@ fn test_proofs_sanity_check() {
 
     smt.update(b"testKey4", Bytes::from("testValue4")).unwrap();
     let root = smt.root();
-    let mut th = TreeHasher::<sha2::Sha256>::default();
+    let th = TreeHasher::<sha2::Sha256>::default();
 
     // Case: invalid number of sidenodes.
     let mut proof = smt.prove(b"testKey1").unwrap();
@@ -79,33 +95,28 @@ fn test_proofs_sanity_check() {
         .collect();
 
     proof.side_nodes = side_nodes;
-    assert!(!proof.sanity_check(&mut th));
+    assert!(!proof.sanity_check(&th));
     assert!(!proof.verify(root.clone(), b"testKey1", b"testValue1"));
     assert!(proof.compact().is_err());
 
     // Case: incorrect size for NonMembershipLeafData.
     let mut proof = smt.prove(b"testKey1").unwrap();
     proof.non_membership_leaf_data = Some(Bytes::from(vec![0; 1]));
-    assert!(!proof.sanity_check(&mut th));
+    assert!(!proof.sanity_check(&th));
     assert!(!proof.verify(root.clone(), b"testKey1", b"testValue1"));
     assert!(proof.compact().is_err());
 
     // Case: unexpected sidenode size.
     let mut proof = smt.prove(b"testKey1").unwrap();
     proof.side_nodes[0] = Bytes::from(vec![0; 1]);
-    assert!(!proof.sanity_check(&mut th));
+    assert!(!proof.sanity_check(&th));
     assert!(!proof.verify(root.clone(), b"testKey1", b"testValue1"));
     assert!(proof.compact().is_err());
 
     // Case: incorrect non-nil sibling data
     let mut proof = smt.prove(b"testKey1").unwrap();
-    proof.sibling_data = Some(
-        th.digest(proof.sibling_data.unwrap_or_default())
-            .as_slice()
-            .to_vec()
-            .into(),
-    );
-    assert!(!proof.sanity_check(&mut th));
+    proof.sibling_data = Some(th.digest(proof.sibling_data.unwrap_or_default()));
+    assert!(!proof.sanity_check(&th));
     assert!(!proof.verify(root, b"testKey1", b"testValue1"));
     assert!(proof.compact().is_err());
 }
@@ -125,11 +136,10 @@ fn test_compact_proofs_sanity_check() {
     // Case (compact proofs): NumSideNodes out of range.
     let mut proof = smt.prove_compact(b"testKey1").unwrap();
     proof.num_side_nodes = 0;
-    let mut th = TreeHasher::<sha2::Sha256>::default();
-    assert!(!proof.sanity_check(&mut th));
+    assert!(!proof.sanity_check());
 
     proof.num_side_nodes = TreeHasher::<sha2::Sha256>::path_size() * 8 + 1;
-    assert!(!proof.sanity_check(&mut th));
+    assert!(!proof.sanity_check());
 
     assert!(!proof.verify(root.clone(), b"testKey1", b"testValue1"));
 
@@ -141,11 +151,11 @@ fn test_compact_proofs_sanity_check() {
     // Case (compact proofs): unexpected number of sidenodes for number of side nodes.
     let mut proof = smt.prove_compact(b"testKey1").unwrap();
     proof.side_nodes.extend(proof.side_nodes.clone());
-    assert!(!proof.sanity_check(&mut th));
+    assert!(!proof.sanity_check());
     assert!(!proof.verify(root, b"testKey1", b"testValue1"));
 }
 
-fn check_compact_equivalence<H: digest::Digest>(proof: &SparseMerkleProof<H>) {
+fn check_compact_equivalence<H: crate::TreeHashScheme + Default>(proof: &SparseMerkleProof<H>) {
     let compact = proof.compact().unwrap();
     let decompact = SparseCompactMerkleProof::<H>::decompact(&compact).unwrap();
 
@@ -159,7 +169,9 @@ fn check_compact_equivalence<H: digest::Digest>(proof: &SparseMerkleProof<H>) {
     );
 }
 
-fn randomise_proof<H: digest::Digest>(proof: &SparseMerkleProof<H>) -> SparseMerkleProof<H> {
+fn randomise_proof<H: crate::TreeHashScheme + Default>(
+    proof: &SparseMerkleProof<H>,
+) -> SparseMerkleProof<H> {
     let mut rng = rand::thread_rng();
     let nodes = (0..proof.side_nodes.len())
         .map(|i| {
@@ -171,3 +183,480 @@ fn randomise_proof<H: digest::Digest>(proof: &SparseMerkleProof<H>) -> SparseMer
 
     SparseMerkleProof::new(nodes, proof.non_membership_leaf_data.clone(), None)
 }
+
+// Test multi proofs against a tree with many keys.
+#[test]
+fn test_multi_proofs_basic() {
+    let mut smt = new_sparse_merkle_tree();
+
+    // Proofs against an empty tree.
+    let proof = smt.prove_many([b"testKey1", b"testKey2"]).unwrap();
+    assert!(proof.verify(
+        smt.root(),
+        [(b"testKey1", DEFAULT_VALUE), (b"testKey2", DEFAULT_VALUE)]
+    ));
+
+    let keys = (0..64)
+        .map(|i| Bytes::from(format!("testKey{}", i)))
+        .collect::<Vec<_>>();
+    smt.update_batch(keys.iter().map(|key| (key, key.clone())))
+        .unwrap();
+    let root = smt.root();
+
+    // Mix members with keys that are not in the tree.
+    let absent = (0..16)
+        .map(|i| Bytes::from(format!("absentKey{}", i)))
+        .collect::<Vec<_>>();
+    let proven = keys
+        .iter()
+        .step_by(3)
+        .chain(absent.iter())
+        .cloned()
+        .collect::<Vec<_>>();
+    let entries = |proven: &[Bytes]| {
+        proven
+            .iter()
+            .map(|key| {
+                if key.starts_with(b"absent") {
+                    (key.clone(), DEFAULT_VALUE)
+                } else {
+                    (key.clone(), key.clone())
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let proof = smt.prove_many(&proven).unwrap();
+    assert!(proof.verify(root.clone(), entries(&proven)));
+
+    // Every side node is only included once.
+    let single = proven
+        .iter()
+        .map(|key| smt.prove(key).unwrap().side_nodes().len())
+        .sum::<usize>();
+    assert!(proof.side_nodes().len() < single);
+
+    // Each key verifies on its own too.
+    for (key, value) in entries(&proven) {
+        assert!(smt.prove(&key).unwrap().verify(root.clone(), &key, &value));
+    }
+
+    // Compact proofs are equivalent.
+    let compact = smt.prove_many_compact(&proven).unwrap();
+    assert!(compact.original_side_nodes_len() >= compact.side_nodes().len());
+    assert!(compact.verify(root.clone(), entries(&proven)));
+    let decompact = compact.decompact().unwrap();
+    assert_eq!(decompact.side_nodes(), proof.side_nodes());
+    assert_eq!(
+        decompact.non_membership_leaf_data(),
+        proof.non_membership_leaf_data()
+    );
+
+    // Wrong values, missing keys and unrelated roots fail.
+    let mut bad = entries(&proven);
+    bad[0].1 = Bytes::from("badValue");
+    assert!(!proof.verify(root.clone(), bad));
+
+    let mut bad = entries(&proven);
+    let last = bad.len() - 1;
+    bad[last].1 = Bytes::from("badValue");
+    assert!(!proof.verify(root.clone(), bad));
+
+    let mut bad = entries(&proven);
+    bad.pop();
+    assert!(!proof.verify(root.clone(), bad));
+
+    assert!(!proof.verify(vec![0; root.len()], entries(&proven)));
+
+    let mut bad = proof.clone();
+    bad.side_nodes[0] = vec![0; root.len()].into();
+    assert!(!bad.verify(root.clone(), entries(&proven)));
+
+    let mut bad = proof.clone();
+    bad.side_nodes.push(vec![0; root.len()].into());
+    assert!(!bad.verify(root, entries(&proven)));
+}
+
+// Test sanity check cases for multi proofs.
+#[test]
+fn test_multi_proofs_sanity_check() {
+    let mut smt = new_sparse_merkle_tree();
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    smt.update(b"testKey2", Bytes::from("testValue2")).unwrap();
+    let root = smt.root();
+    let entries = [(b"testKey1", b"testValue1"), (b"testKey2", b"testValue2")];
+
+    // Case: depth out of range.
+    let mut proof = smt.prove_many([b"testKey1", b"testKey2"]).unwrap();
+    proof.depths[0] = TreeHasher::<sha2::Sha256>::path_size() * 8 + 1;
+    assert!(!proof.verify(root.clone(), entries));
+    assert!(proof.compact().is_err());
+
+    // Case: unexpected sidenode size.
+    let mut proof = smt.prove_many([b"testKey1", b"testKey2"]).unwrap();
+    proof.side_nodes.push(Bytes::from(vec![0; 1]));
+    assert!(!proof.verify(root.clone(), entries));
+    assert!(proof.compact().is_err());
+
+    // Case: incorrect size for non-membership leaf data.
+    let mut proof = smt.prove_many([b"testKey1", b"testKey2"]).unwrap();
+    proof
+        .non_membership_leaf_data
+        .push(Some(Bytes::from(vec![0; 1])));
+    assert!(!proof.verify(root.clone(), entries));
+
+    // Case (compact proofs): unexpected number of side nodes.
+    let mut proof = smt.prove_many_compact([b"testKey1", b"testKey2"]).unwrap();
+    proof.num_side_nodes += 1;
+    assert!(proof.decompact().is_err());
+    assert!(!proof.verify(root, entries));
+}
+
+// Test the byte-level encoding of proofs.
+#[test]
+fn test_proofs_encoding() {
+    let mut smt = new_sparse_merkle_tree();
+
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    smt.update(b"testKey2", Bytes::from("testValue2")).unwrap();
+    smt.update(b"testKey3", Bytes::from("testValue3")).unwrap();
+    let root = smt.root();
+
+    for (key, value) in [
+        (b"testKey1".as_ref(), b"testValue1".as_ref()),
+        (b"testKey4", DEFAULT_VALUE.as_ref()),
+    ] {
+        for proof in [smt.prove(key).unwrap(), smt.prove_updatable(key).unwrap()] {
+            let encoded = proof.encode();
+            assert_eq!(encoded.len(), proof.encoded_len());
+            let decoded = SparseMerkleProof::<sha2::Sha256>::decode(&mut encoded.clone()).unwrap();
+            assert_eq!(decoded.side_nodes, proof.side_nodes);
+            assert_eq!(
+                decoded.non_membership_leaf_data,
+                proof.non_membership_leaf_data
+            );
+            assert_eq!(decoded.sibling_data, proof.sibling_data);
+            assert!(decoded.verify(root.clone(), key, value));
+
+            // Truncated input is rejected.
+            for len in 0..encoded.len() {
+                assert!(
+                    SparseMerkleProof::<sha2::Sha256>::decode(&mut encoded.slice(..len)).is_err()
+                );
+            }
+
+            // A compact proof cannot be decoded as a proof.
+            assert!(
+                SparseCompactMerkleProof::<sha2::Sha256>::decode(&mut encoded.clone()).is_err()
+            );
+
+            let compact = proof.compact().unwrap();
+            let encoded = compact.encode();
+            assert_eq!(encoded.len(), compact.encoded_len());
+            let decoded =
+                SparseCompactMerkleProof::<sha2::Sha256>::decode(&mut encoded.clone()).unwrap();
+            assert_eq!(decoded.side_nodes, compact.side_nodes);
+            assert_eq!(decoded.bitmask, compact.bitmask);
+            assert_eq!(decoded.num_side_nodes, compact.num_side_nodes);
+            assert!(decoded.verify(root.clone(), key, value));
+
+            for len in 0..encoded.len() {
+                assert!(SparseCompactMerkleProof::<sha2::Sha256>::decode(
+                    &mut encoded.slice(..len)
+                )
+                .is_err());
+            }
+        }
+    }
+
+    // Trailing bytes are left in the buffer.
+    let proof = smt.prove(b"testKey1").unwrap();
+    let mut buf = bytes::BytesMut::new();
+    proof.encode_to(&mut buf);
+    proof.encode_to(&mut buf);
+    let mut buf = buf.freeze();
+    SparseMerkleProof::<sha2::Sha256>::decode(&mut buf).unwrap();
+    assert_eq!(buf.len(), proof.encoded_len());
+
+    // Unknown versions are rejected.
+    let mut encoded = proof.encode().to_vec();
+    encoded[0] = 2;
+    assert!(SparseMerkleProof::<sha2::Sha256>::decode(&mut encoded.as_slice()).is_err());
+
+    // Proofs failing the sanity check are rejected.
+    let mut proof = smt.prove(b"testKey1").unwrap();
+    proof.side_nodes[0] = Bytes::from(vec![0; 1]);
+    assert!(SparseMerkleProof::<sha2::Sha256>::decode(&mut proof.encode()).is_err());
+
+    let mut proof = smt.prove_compact(b"testKey1").unwrap();
+    proof.num_side_nodes += 1;
+    assert!(SparseCompactMerkleProof::<sha2::Sha256>::decode(&mut proof.encode()).is_err());
+
+    // A bit mask with a set padding bit claims a placeholder past the side nodes.
+    let encoded = [1, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0x40, 0, 0, 0, 0, 0, 0];
+    for len in [15, encoded.len()] {
+        assert!(SparseCompactMerkleProof::<sha2::Sha256>::decode(&mut &encoded[..len]).is_err());
+    }
+    // So does a bit mask with more set bits than the number of side nodes.
+    let encoded = [1, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0xc0, 0, 0, 0, 0, 0, 0];
+    assert!(SparseCompactMerkleProof::<sha2::Sha256>::decode(&mut &encoded[..]).is_err());
+}
+
+// Lengths which do not fit in the u32 length prefixes are not truncated.
+#[test]
+#[cfg(target_pointer_width = "64")]
+#[should_panic(expected = "proof length does not fit in a u32")]
+fn test_proofs_encoding_oversized() {
+    let proof = SparseCompactMerkleProof::<sha2::Sha256>::new(
+        vec![],
+        None,
+        Bytes::new(),
+        u32::MAX as usize + 1,
+        None,
+    );
+    proof.encode();
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_proofs_serde() {
+    let mut smt = new_sparse_merkle_tree();
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    smt.update(b"testKey2", Bytes::from("testValue2")).unwrap();
+    let root = smt.root();
+
+    for key in [&b"testKey1"[..], b"testKey3"] {
+        let value = smt.get(key).unwrap().unwrap_or(DEFAULT_VALUE);
+        let proof = smt.prove(key).unwrap();
+        let compact = smt.prove_compact(key).unwrap();
+
+        // Human-readable formats encode bytes as hex strings.
+        let json = serde_json::to_string(&proof).unwrap();
+        let hex = proof.side_nodes[0]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<String>();
+        assert!(json.contains(&hex));
+        let decoded: SparseMerkleProof<sha2::Sha256> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.side_nodes, proof.side_nodes);
+        assert!(decoded.verify(root.clone(), key, value.clone()));
+
+        let json = serde_json::to_string(&compact).unwrap();
+        let decoded: SparseCompactMerkleProof<sha2::Sha256> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.bitmask, compact.bitmask);
+        assert!(decoded.verify(root.clone(), key, value.clone()));
+
+        // Binary formats encode bytes as raw bytes.
+        let bin = bincode::serialize(&proof).unwrap();
+        assert!(bin.len() < json.len());
+        let decoded: SparseMerkleProof<sha2::Sha256> = bincode::deserialize(&bin).unwrap();
+        assert!(decoded.verify(root.clone(), key, value.clone()));
+
+        let bin = bincode::serialize(&compact).unwrap();
+        let decoded: SparseCompactMerkleProof<sha2::Sha256> = bincode::deserialize(&bin).unwrap();
+        assert!(decoded.verify(root.clone(), key, value));
+    }
+
+    // Proofs failing the sanity check are rejected.
+    let mut proof = smt.prove(b"testKey1").unwrap();
+    proof.side_nodes[0] = Bytes::from(vec![0; 1]);
+    let json = serde_json::to_string(&proof).unwrap();
+    assert!(serde_json::from_str::<SparseMerkleProof<sha2::Sha256>>(&json).is_err());
+    let bin = bincode::serialize(&proof).unwrap();
+    assert!(bincode::deserialize::<SparseMerkleProof<sha2::Sha256>>(&bin).is_err());
+
+    let mut compact = smt.prove_compact(b"testKey1").unwrap();
+    compact.num_side_nodes += 1;
+    let json = serde_json::to_string(&compact).unwrap();
+    assert!(serde_json::from_str::<SparseCompactMerkleProof<sha2::Sha256>>(&json).is_err());
+
+    // A bit mask with a set padding bit claims a placeholder past the side nodes.
+    compact.side_nodes.clear();
+    compact.bitmask = Bytes::from_static(&[0x40]);
+    compact.num_side_nodes = 1;
+    let json = serde_json::to_string(&compact).unwrap();
+    assert!(serde_json::from_str::<SparseCompactMerkleProof<sha2::Sha256>>(&json).is_err());
+    let bin = bincode::serialize(&compact).unwrap();
+    assert!(bincode::deserialize::<SparseCompactMerkleProof<sha2::Sha256>>(&bin).is_err());
+
+    // The sibling data is left to be checked with the scheme of the tree on
+    // verification, so proofs of trees with an identifier or a keyed scheme are
+    // accepted.
+    let mut bound = new_sparse_merkle_tree().with_tree_id(b"alpha");
+    bound
+        .update(b"testKey1", Bytes::from("testValue1"))
+        .unwrap();
+    bound
+        .update(b"testKey2", Bytes::from("testValue2"))
+        .unwrap();
+    let proof = bound.prove_updatable(b"testKey1").unwrap();
+    assert!(proof.sibling_data.is_some());
+    let json = serde_json::to_string(&proof).unwrap();
+    let decoded: SparseMerkleProof<sha2::Sha256> = serde_json::from_str(&json).unwrap();
+    assert!(decoded.verify_for_tree(b"alpha", bound.root(), b"testKey1", b"testValue1"));
+    let mut compact = bound.prove_compact(b"testKey1").unwrap();
+    compact.sibling_data = proof.sibling_data.clone();
+    let json = serde_json::to_string(&compact).unwrap();
+    let decoded: SparseCompactMerkleProof<sha2::Sha256> = serde_json::from_str(&json).unwrap();
+    assert!(decoded.verify_for_tree(b"alpha", bound.root(), b"testKey1", b"testValue1"));
+
+    {
+        use hmac::{Hmac, Mac};
+
+        type HmacScheme = crate::KeyedHasher<Hmac<sha2::Sha256>>;
+        let scheme = HmacScheme::new(Hmac::new_from_slice(b"alpha").unwrap());
+        let mut keyed = crate::SparseMerkleTree::<crate::SimpleStore, _>::new_with_scheme(
+            crate::SimpleStore::new(),
+            crate::SimpleStore::new(),
+            scheme.clone(),
+        );
+        keyed
+            .update(b"testKey1", Bytes::from("testValue1"))
+            .unwrap();
+        keyed
+            .update(b"testKey2", Bytes::from("testValue2"))
+            .unwrap();
+        let proof = keyed.prove_updatable(b"testKey1").unwrap();
+        let bin = bincode::serialize(&proof).unwrap();
+        let decoded: SparseMerkleProof<HmacScheme> = bincode::deserialize(&bin).unwrap();
+        assert!(decoded.verify_with(&scheme, keyed.root(), b"testKey1", b"testValue1"));
+        let mut forged = proof.clone();
+        forged.sibling_data = Some(Bytes::from_static(b"forged"));
+        let bin = bincode::serialize(&forged).unwrap();
+        let decoded: SparseMerkleProof<HmacScheme> = bincode::deserialize(&bin).unwrap();
+        assert!(!decoded.verify_with(&scheme, keyed.root(), b"testKey1", b"testValue1"));
+    }
+
+    // Lists of side nodes longer than a path are rejected while deserializing.
+    let side_nodes = vec![format!("\"{}\"", "00".repeat(32)); 257].join(",");
+    let json = format!(
+        r#"{{"side_nodes":[{}],"non_membership_leaf_data":null,"sibling_data":null}}"#,
+        side_nodes
+    );
+    let err = serde_json::from_str::<SparseMerkleProof<sha2::Sha256>>(&json).unwrap_err();
+    assert!(
+        err.to_string().contains("at most 256 side nodes"),
+        "{}",
+        err
+    );
+    let mut bin = bincode::serialize(&proof).unwrap();
+    bin[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(bincode::deserialize::<SparseMerkleProof<sha2::Sha256>>(&bin).is_err());
+    let mut compact = smt.prove_compact(b"testKey1").unwrap();
+    compact.num_side_nodes = usize::MAX;
+    let json = serde_json::to_string(&compact).unwrap();
+    assert!(serde_json::from_str::<SparseCompactMerkleProof<sha2::Sha256>>(&json).is_err());
+
+    // Malformed hex is rejected.
+    assert!(serde_json::from_str::<SparseMerkleProof<sha2::Sha256>>(
+        r#"{"side_nodes":["0"],"non_membership_leaf_data":null,"sibling_data":null}"#
+    )
+    .is_err());
+    assert!(serde_json::from_str::<SparseMerkleProof<sha2::Sha256>>(
+        r#"{"side_nodes":["zz"],"non_membership_leaf_data":null,"sibling_data":null}"#
+    )
+    .is_err());
+
+    let json = serde_json::to_string(&crate::BadProof).unwrap();
+    assert_eq!(json, "null");
+    serde_json::from_str::<crate::BadProof>(&json).unwrap();
+}
+
+#[test]
+fn test_range_proofs() {
+    use rand::Rng;
+
+    let mut smt = new_sparse_merkle_tree();
+    let path_size = TreeHasher::<sha2::Sha256>::path_size();
+    let (min, max) = (vec![0u8; path_size], vec![0xffu8; path_size]);
+
+    // An empty tree has no leaves in any range.
+    let proof = smt.prove_range(&min, &max).unwrap();
+    assert!(proof.verify(smt.root(), Vec::<(Bytes, Bytes)>::new()));
+
+    let mut rng = rand::thread_rng();
+    for i in 0..100u32 {
+        smt.update(
+            &i.to_be_bytes(),
+            Bytes::from(vec![1 + rng.gen_range(0..255u8); 4]),
+        )
+        .unwrap();
+    }
+    let root = smt.root();
+    let leaves = smt
+        .iter_leaves(root.clone())
+        .with_values()
+        .map(|leaf| {
+            let (path, _, value) = leaf.unwrap().into_parts();
+            (path, value.unwrap())
+        })
+        .collect::<Vec<_>>();
+
+    let check_range = |start: &[u8], end: &[u8]| {
+        let proof = smt.prove_range(start, end).unwrap();
+        let range = leaves
+            .iter()
+            .filter(|(path, _)| start <= path.as_ref() && path.as_ref() <= end)
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!(proof.verify(root.clone(), range.clone()));
+        assert!(!proof.verify(vec![0; path_size], range.clone()));
+
+        // Omitting, adding or modifying a leaf is detected.
+        for idx in 0..range.len() {
+            let mut omitted = range.clone();
+            omitted.remove(idx);
+            assert!(!proof.verify(root.clone(), omitted));
+
+            let mut modified = range.clone();
+            modified[idx].1 = Bytes::from("modified");
+            assert!(!proof.verify(root.clone(), modified));
+        }
+        let mut added = range.clone();
+        added.push((Bytes::copy_from_slice(start), Bytes::from("added")));
+        if !range.iter().any(|(path, _)| path.as_ref() == start) {
+            assert!(!proof.verify(root.clone(), added));
+        }
+        range.len()
+    };
+
+    assert_eq!(check_range(&min, &max), leaves.len());
+    assert_eq!(check_range(&leaves[10].0, &leaves[20].0), 11);
+    assert_eq!(check_range(&leaves[0].0, &leaves[0].0), 1);
+    assert_eq!(check_range(&max, &max), 0);
+
+    for _ in 0..10 {
+        let mut start = vec![0u8; path_size];
+        let mut end = vec![0u8; path_size];
+        rng.fill_bytes(&mut start);
+        rng.fill_bytes(&mut end);
+        if start > end {
+            core::mem::swap(&mut start, &mut end);
+        }
+        check_range(&start, &end);
+    }
+
+    // Adjacent ranges split the tree into independently verifiable chunks.
+    let mut total = 0;
+    for chunk in leaves.chunks(16) {
+        let start = &chunk[0].0;
+        let end = &chunk[chunk.len() - 1].0;
+        let proof = smt.prove_range(start, end).unwrap();
+        assert!(proof.verify(root.clone(), chunk.to_vec()));
+        total += chunk.len();
+    }
+    assert_eq!(total, leaves.len());
+
+    // Malformed ranges are rejected.
+    assert!(smt.prove_range(&max, &min).is_err());
+    assert!(smt.prove_range(&min[1..], &max).is_err());
+    let proof = SparseMerkleRangeProof::<sha2::Sha256>::new(
+        Bytes::from(max.clone()),
+        Bytes::from(min.clone()),
+        Vec::new(),
+        Vec::new(),
+        0,
+        0,
+    );
+    assert!(!proof.verify(root, Vec::<(Bytes, Bytes)>::new()));
+}
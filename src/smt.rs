@@ -1,92 +1,181 @@
-use crate::BadProof;
+use crate::{TreeError, TreeHashScheme, WriteBatch};
 
 use super::{
-    count_common_prefix, get_bit_at_from_msb, tree_hasher::TreeHasher, KVStore,
-    SparseCompactMerkleProof, SparseMerkleProof,
+    get_bit_at_from_msb, tree_hasher::TreeHasher, KVStore, SparseCompactMerkleMultiProof,
+    SparseCompactMerkleProof, SparseMerkleMultiProof, SparseMerkleProof, SparseMerkleRangeProof,
+    StoreHasher,
 };
-use alloc::boxed::Box;
-use alloc::{vec, vec::Vec};
+use alloc::vec::Vec;
 use bytes::Bytes;
-use core::ops::Deref;
+#[cfg(feature = "async")]
+mod async_tree;
+#[cfg(feature = "async")]
+pub use async_tree::AsyncSparseMerkleTree;
+mod changes;
+use changes::Change;
+mod integrity;
+mod pending;
+pub use integrity::{Inconsistency, InconsistencyKind};
+mod iter;
+mod node_cache;
+use node_cache::NodeCache;
+pub use node_cache::NodeCacheStats;
 #[cfg(test)]
 pub mod tests;
+pub use iter::{Leaf, Leaves};
+mod sync;
+pub use sync::StateSyncer;
+mod values;
+mod versioned;
+mod view;
+use versioned::Versions;
+pub use view::SparseMerkleTreeView;
 
 pub(crate) const RIGHT: usize = 1;
 pub(crate) const DEFAULT_VALUE: Bytes = Bytes::new();
 
 /// Sparse Merkle tree.
-pub struct SparseMerkleTree<S: KVStore> {
-    th: TreeHasher<S::Hasher>,
+pub struct SparseMerkleTree<S: KVStore, H: TreeHashScheme = <S as StoreHasher>::Hasher> {
+    th: TreeHasher<H>,
     nodes: S,
     values: S,
     root: Bytes,
+    versions: Option<Versions>,
+    /// The writes of the operation in progress, see `batched`.
+    pending_nodes: WriteBatch,
+    pending_values: WriteBatch,
+    node_cache: Option<NodeCache>,
 }
 
-impl<S: KVStore + core::fmt::Debug> core::fmt::Debug for SparseMerkleTree<S> {
+impl<S: KVStore + core::fmt::Debug, H: TreeHashScheme> core::fmt::Debug for SparseMerkleTree<S, H> {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct(core::any::type_name::<Self>())
             .field("nodes", &self.nodes)
             .field("values", &self.values)
             .field("root", &self.root().as_ref())
             .field("tree_hasher", &self.th)
+            .field("versions", &self.versions)
+            .field("node_cache", &self.node_cache)
             .finish()
     }
 }
 
-impl<S: KVStore + Default> Default for SparseMerkleTree<S> {
+impl<S: KVStore + Default, H: TreeHashScheme + Default> Default for SparseMerkleTree<S, H> {
     fn default() -> Self {
-        let th = TreeHasher::new(vec![0; TreeHasher::<S::Hasher>::path_size()].into());
+        let th = TreeHasher::<H>::default();
         let root = th.placeholder();
         Self {
             th,
             nodes: S::default(),
             values: S::default(),
             root,
+            versions: None,
+            pending_nodes: WriteBatch::new(),
+            pending_values: WriteBatch::new(),
+            node_cache: None,
         }
     }
 }
 
-impl<S: KVStore + Clone> Clone for SparseMerkleTree<S> {
+impl<S: KVStore + Clone, H: TreeHashScheme> Clone for SparseMerkleTree<S, H> {
     fn clone(&self) -> Self {
         Self {
             th: self.th.clone(),
             nodes: self.nodes.clone(),
             values: self.values.clone(),
             root: self.root.clone(),
+            versions: self.versions.clone(),
+            pending_nodes: self.pending_nodes.clone(),
+            pending_values: self.pending_values.clone(),
+            node_cache: self.node_cache.clone(),
         }
     }
 }
 
-impl<S: KVStore + Default> SparseMerkleTree<S> {
+impl<S: KVStore + Default, H: TreeHashScheme + Default> SparseMerkleTree<S, H> {
     /// Create a new sparse merkle tree
     pub fn new() -> Self {
         Self::default()
     }
 }
 
-impl<S: KVStore> SparseMerkleTree<S> {
+impl<S: KVStore, H: TreeHashScheme + Default> SparseMerkleTree<S, H> {
     /// Create a sparse merkle tree based on the given stores
     #[inline]
     pub fn new_with_stores(nodes_store: S, values_store: S) -> Self {
-        let th = TreeHasher::new(vec![0; TreeHasher::<S::Hasher>::path_size()].into());
+        Self::new_with_scheme(nodes_store, values_store, H::default())
+    }
+
+    /// Imports a Sparse Merkle tree from non-empty `KVStore`.
+    #[inline]
+    pub fn import(nodes_store: S, values_store: S, root: impl Into<Bytes>) -> Self {
+        Self::import_with_scheme(nodes_store, values_store, root, H::default())
+    }
+}
+
+impl<S: KVStore, H: TreeHashScheme> SparseMerkleTree<S, H> {
+    /// Create a sparse merkle tree based on the given stores, hashed with the given
+    /// scheme instance, e.g. a `KeyedHasher` holding a key.
+    #[inline]
+    pub fn new_with_scheme(nodes_store: S, values_store: S, scheme: H) -> Self {
+        let th = TreeHasher::new(scheme);
         let root = th.placeholder();
+        Self::with_hasher(th, nodes_store, values_store, root)
+    }
+
+    /// Imports a Sparse Merkle tree from non-empty `KVStore`, hashed with the given
+    /// scheme instance.
+    #[inline]
+    pub fn import_with_scheme(
+        nodes_store: S,
+        values_store: S,
+        root: impl Into<Bytes>,
+        scheme: H,
+    ) -> Self {
+        Self::with_hasher(
+            TreeHasher::new(scheme),
+            nodes_store,
+            values_store,
+            root.into(),
+        )
+    }
+
+    fn with_hasher(th: TreeHasher<H>, nodes: S, values: S, root: Bytes) -> Self {
         Self {
             th,
-            nodes: nodes_store,
-            values: values_store,
+            nodes,
+            values,
             root,
+            versions: None,
+            pending_nodes: WriteBatch::new(),
+            pending_values: WriteBatch::new(),
+            node_cache: None,
         }
     }
 
-    /// Imports a Sparse Merkle tree from non-empty `KVStore`.
+    /// Returns the hash scheme instance of the tree.
     #[inline]
-    pub fn import(nodes_store: S, values_store: S, root: impl Into<Bytes>) -> Self {
-        Self {
-            th: TreeHasher::new(vec![0; TreeHasher::<S::Hasher>::path_size()].into()),
-            nodes: nodes_store,
-            values: values_store,
-            root: root.into(),
-        }
+    pub fn scheme(&self) -> &H {
+        self.th.scheme()
+    }
+
+    /// Binds the tree to the given identifier, which is mixed into the paths of keys
+    /// and the hashes of leaves and nodes, so that proofs of this tree do not verify
+    /// against any other tree, even one with the same hasher and leaves. Its proofs
+    /// are verified with `verify_for_tree`.
+    ///
+    /// Must be called on an empty tree, or on a tree imported from stores written
+    /// with the same identifier.
+    #[inline]
+    pub fn with_tree_id(mut self, tree_id: impl AsRef<[u8]>) -> Self {
+        self.th = self.th.with_tree_id(Some(tree_id.as_ref()));
+        self
+    }
+
+    /// Returns the identifier of the tree, if it has one.
+    #[inline]
+    pub fn tree_id(&self) -> Option<&[u8]> {
+        self.th.tree_id()
     }
 
     /// Returns the root of the sparse merkle tree
@@ -107,36 +196,116 @@ impl<S: KVStore> SparseMerkleTree<S> {
         self.root = root.into();
     }
 
+    /// Returns the nodes store.
+    #[inline]
+    pub fn nodes_store(&self) -> &S {
+        &self.nodes
+    }
+
+    /// Returns the nodes store mutably, e.g. to commit a `CachedStore`.
+    #[inline]
+    pub fn nodes_store_mut(&mut self) -> &mut S {
+        &mut self.nodes
+    }
+
+    /// Returns the values store.
+    #[inline]
+    pub fn values_store(&self) -> &S {
+        &self.values
+    }
+
+    /// Returns the values store mutably, e.g. to commit a `CachedStore`.
+    #[inline]
+    pub fn values_store_mut(&mut self) -> &mut S {
+        &mut self.values
+    }
+
+    /// Returns a read-only view of the tree pinned at the current root.
+    ///
+    /// The view borrows the stores, so it can be shared with other threads while
+    /// proofs and values are served from it, but the tree cannot be updated until
+    /// the view is dropped. Views which stay live across updates are created by
+    /// `SparseMerkleTreeView::new` over handles sharing the stores with the tree.
+    #[inline]
+    pub fn snapshot(&self) -> SparseMerkleTreeView<'_, S, H> {
+        self.snapshot_at(self.root())
+    }
+
+    /// Returns a read-only view of the tree pinned at the given root.
+    #[inline]
+    pub fn snapshot_at(&self, root: impl Into<Bytes>) -> SparseMerkleTreeView<'_, S, H> {
+        let mut view =
+            SparseMerkleTreeView::with_hasher(self.th.clone(), &self.nodes, &self.values, root);
+        view.node_cache = self.node_cache.as_ref();
+        view
+    }
+
     #[inline]
     fn depth(&self) -> usize {
-        TreeHasher::<S::Hasher>::path_size() * 8
+        TreeHasher::<H>::path_size() * 8
+    }
+
+    /// Returns `InvalidRootLength` if the root does not have the length of a hash.
+    #[inline]
+    fn check_root(root: &[u8]) -> Result<(), TreeError<S::Error>> {
+        if root.len() != TreeHasher::<H>::path_size() {
+            return Err(TreeError::InvalidRootLength { len: root.len() });
+        }
+        Ok(())
+    }
+
+    /// Reads a node which must be in the nodes store, checking that its data is well
+    /// formed.
+    fn get_node(&self, hash: &Bytes) -> Result<Bytes, TreeError<S::Error>> {
+        changes::checked_node::<H, _>(hash, self.read_node(hash)?)
+    }
+
+    /// Parses the data of the leaf with the given hash into its path and value hash.
+    #[allow(clippy::type_complexity)]
+    fn parse_leaf<'a>(
+        hash: &Bytes,
+        data: &'a [u8],
+    ) -> Result<(&'a [u8], &'a [u8]), TreeError<S::Error>> {
+        changes::parse_leaf::<H, S::Error>(hash, data)
+    }
+
+    /// Parses the data of the node with the given hash into the hashes of its
+    /// children.
+    fn parse_node(
+        hash: &Bytes,
+        data: &Option<Bytes>,
+    ) -> Result<(Bytes, Bytes), TreeError<S::Error>> {
+        match data {
+            Some(data) => TreeHasher::<H>::parse_node(data)
+                .ok_or_else(|| TreeError::CorruptNode { hash: hash.clone() }),
+            None => Err(TreeError::MissingNode { hash: hash.clone() }),
+        }
     }
 
     /// Gets the value of a key from the tree.
-    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, <S as KVStore>::Error> {
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, TreeError<S::Error>> {
         if self.root.as_ref().eq(self.th.placeholder_ref()) {
             return Ok(None);
         }
 
         let path = self.th.path(key);
-        match self.values.get(path.as_ref()) {
-            Ok(value) => Ok(value),
-            Err(e) => Err(e),
-        }
+        self.values.get(path.as_ref()).map_err(TreeError::Store)
     }
 
     /// Returns true if the value at the given key is non-default, false
     /// otherwise.
-    pub fn contains(&self, key: &[u8]) -> Result<bool, <S as KVStore>::Error> {
+    pub fn contains(&self, key: &[u8]) -> Result<bool, TreeError<S::Error>> {
         if self.root.as_ref().eq(self.th.placeholder_ref()) {
             return Ok(false);
         }
         let path = self.th.path(key);
-        self.values.contains(path.as_ref())
+        self.values
+            .contains(path.as_ref())
+            .map_err(TreeError::Store)
     }
 
     /// Removes a value from tree.
-    pub fn remove(&mut self, key: &[u8]) -> Result<(), <S as KVStore>::Error> {
+    pub fn remove(&mut self, key: &[u8]) -> Result<(), TreeError<S::Error>> {
         self.update(key, DEFAULT_VALUE)
     }
 
@@ -145,83 +314,19 @@ impl<S: KVStore> SparseMerkleTree<S> {
         &mut self,
         key: &[u8],
         root: Bytes,
-    ) -> Result<Bytes, <S as KVStore>::Error> {
+    ) -> Result<Bytes, TreeError<S::Error>> {
         self.update_for_root(key, DEFAULT_VALUE, root)
     }
 
-    fn remove_with_side_nodes(
-        &mut self,
-        path: &[u8],
-        side_nodes: Vec<Bytes>,
-        path_nodes: Vec<Bytes>,
-        old_leaf_data: Option<Bytes>,
-    ) -> Result<Option<Bytes>, <S as KVStore>::Error> {
-        if path_nodes[0].eq(self.th.placeholder_ref()) {
-            // This key is already empty as it is a placeholder; return an None.
-            return Ok(None);
-        }
-
-        let (actual_path, _) = TreeHasher::<S::Hasher>::parse_leaf(old_leaf_data.as_ref().unwrap());
-        if path.ne(actual_path) {
-            // This key is already empty as a different key was found its place; return an error.
-            return Ok(None);
-        }
-
-        // All nodes above the deleted leaf are now orphaned
-        for node in path_nodes {
-            self.nodes.remove(node.as_ref())?;
-        }
-
-        let side_nodes_num = side_nodes.len();
-        let mut current_data = Bytes::new();
-        let mut current_hash = Bytes::new();
-        let mut non_placeholder_reached = false;
-        for (idx, side_node) in side_nodes.into_iter().enumerate() {
-            if current_data.is_empty() {
-                let side_node_value = self.nodes.get(side_node.as_ref())?;
-                if TreeHasher::<S::Hasher>::is_leaf(&side_node_value) {
-                    // This is the leaf sibling that needs to be bubbled up the tree.
-                    current_hash = side_node.clone();
-                    current_data = side_node.clone();
-                    continue;
-                } else {
-                    // This is the node sibling that needs to be left in its place.
-                    current_data = self.th.placeholder();
-                    non_placeholder_reached = true;
-                }
-            }
-
-            if !non_placeholder_reached && side_node.eq(self.th.placeholder_ref()) {
-                // We found another placeholder sibling node, keep going up the
-                // tree until we find the first sibling that is not a placeholder.
-                continue;
-            } else if !non_placeholder_reached {
-                // We found the first sibling node that is not a placeholder, it is
-                // time to insert our leaf sibling node here.
-                non_placeholder_reached = true;
-            }
-
-            if get_bit_at_from_msb(path, side_nodes_num - idx - 1) == RIGHT {
-                (current_hash, current_data) = self.th.digest_node(side_node, &current_data);
-            } else {
-                (current_hash, current_data) = self.th.digest_node(&current_data, side_node);
-            }
-
-            self.nodes.set(current_hash.clone(), current_data.clone())?;
-
-            current_data = current_hash.clone();
-        }
-
-        if current_hash.is_empty() {
-            // The tree is empty; return placeholder value as root.
-            current_hash = self.th.placeholder();
-        }
-        Ok(Some(current_hash))
-    }
-
     /// Sets a new value for a key in the tree.
-    pub fn update(&mut self, key: &[u8], value: Bytes) -> Result<(), <S as KVStore>::Error> {
-        let new_root = self.update_for_root(key, value, self.root())?;
+    pub fn update(&mut self, key: &[u8], value: Bytes) -> Result<(), TreeError<S::Error>> {
+        self.discard_orphans();
+        let root = self.root();
+        let new_root = self.batched(|tree| {
+            let new_root = tree.do_update_for_root(key, value, root)?;
+            tree.commit_version(new_root.clone());
+            Ok(new_root)
+        })?;
         self.set_root(new_root);
         Ok(())
     }
@@ -232,240 +337,302 @@ impl<S: KVStore> SparseMerkleTree<S> {
         key: &[u8],
         value: Bytes,
         root: Bytes,
-    ) -> Result<Bytes, <S as KVStore>::Error> {
-        let path = {
-            let path = self.th.path(key);
-            let len = path.len();
-            let ptr = Box::into_raw(Box::new(path)) as *mut u8;
-            Bytes::from(unsafe { Vec::from_raw_parts(ptr, len, len) })
-        };
+    ) -> Result<Bytes, TreeError<S::Error>> {
+        let result = self.batched(|tree| tree.do_update_for_root(key, value, root));
+        self.discard_orphans();
+        result
+    }
 
-        let UpdateResult {
-            side_nodes,
-            path_nodes,
-            sibling_data: _,
-            current_data: old_leaf_data,
-        } = self.side_nodes_for_root(&path, root.clone(), false)?;
+    fn do_update_for_root(
+        &mut self,
+        key: &[u8],
+        value: Bytes,
+        root: Bytes,
+    ) -> Result<Bytes, TreeError<S::Error>> {
+        let path = self.th.path(key);
+
+        let result = self
+            .snapshot_at(root.clone())
+            .side_nodes_for_root(&path, false)?;
 
-        if value.eq(&DEFAULT_VALUE) {
+        let changes = if value.eq(&DEFAULT_VALUE) {
             // Delete operation.
-            let new_root =
-                self.remove_with_side_nodes(&path, side_nodes, path_nodes, old_leaf_data)?;
-            match new_root {
-                Some(new_root) => {
-                    self.values.remove(&path)?;
-                    Ok(new_root)
-                }
+            if !changes::holds_path(&self.th, &path, &result)? {
                 // This key is already empty; return the old root.
-                None => Ok(root),
+                return Ok(root);
             }
+            let sibling_data = match result.side_nodes.first() {
+                Some(side_node) if side_node.ne(self.th.placeholder_ref()) => {
+                    Some(self.get_node(side_node)?)
+                }
+                _ => None,
+            };
+            changes::remove_changes(&self.th, &path, result, sibling_data)
         } else {
             // Insert operation.
-            self.update_with_side_notes(path, value, side_nodes, path_nodes, old_leaf_data)
-        }
+            match changes::update_changes(&self.th, path, value, result)? {
+                Some(changes) => changes,
+                // The same value is already set.
+                None => return Ok(self.root()),
+            }
+        };
+        self.apply_changes(changes)
     }
 
-    fn update_with_side_notes(
+    /// Applies the changes made by an update to the stores, returning the new root.
+    fn apply_changes(
         &mut self,
-        path: Bytes,
-        value: Bytes,
-        side_nodes: Vec<Bytes>,
-        path_nodes: Vec<Bytes>,
-        old_leaf_data: Option<Bytes>,
-    ) -> Result<Bytes, <S as KVStore>::Error> {
-        let depth = self.depth();
-        let value_hash = self.th.digest(&value);
-        let (mut current_hash, mut current_data) = self.th.digest_leaf(&path, &value_hash);
-        self.nodes.set(current_hash.clone(), current_data.clone())?;
-        current_data = current_hash.clone();
-
-        // If the leaf node that sibling nodes lead to has a different actual path
-        // than the leaf node being updated, we need to create an intermediate node
-        // with this leaf node and the new leaf node as children.
-        //
-        // First, get the number of bits that the paths of the two leaf nodes share
-        // in common as a prefix.
-        let (common_prefix_count, old_value_hash) = if path_nodes[0].eq(self.th.placeholder_ref()) {
-            (depth, None)
-        } else {
-            let (actual_path, value_hash) =
-                TreeHasher::<S::Hasher>::parse_leaf(old_leaf_data.as_ref().unwrap());
-            (count_common_prefix(&path, actual_path), Some(value_hash))
-        };
-
-        if common_prefix_count != depth {
-            if get_bit_at_from_msb(&path, common_prefix_count) == RIGHT {
-                (current_hash, current_data) = self.th.digest_node(&path_nodes[0], &current_data);
-            } else {
-                (current_hash, current_data) = self.th.digest_node(&current_data, &path_nodes[0]);
+        (changes, root): (Vec<Change>, Bytes),
+    ) -> Result<Bytes, TreeError<S::Error>> {
+        for change in changes {
+            match change {
+                Change::PutNode(hash, data) => self.put_node(hash, data)?,
+                Change::RemoveNode(hash) => self.remove_node(&hash)?,
+                Change::PutValue {
+                    path,
+                    value_hash,
+                    value,
+                } => self.put_value(path, &value_hash, value),
+                Change::RemoveValue(path) => self.remove_value(&path)?,
             }
-
-            self.nodes.set(current_hash.clone(), current_data.clone())?;
-            current_data = current_hash.clone();
-        } else if let Some(old_value_hash) = old_value_hash {
-            // Short-circuit if the same value is being set
-            if value_hash.deref().eq(old_value_hash) {
-                return Ok(self.root());
-            }
-
-            // If an old leaf exists, remove it
-            self.nodes.remove(&path_nodes[0])?;
-            self.values.remove(&path)?;
         }
+        Ok(root)
+    }
 
-        // All remaining path nodes are orphaned
-        for node in path_nodes.into_iter().skip(1) {
-            self.nodes.remove(&node)?;
-        }
+    /// Sets new values for a batch of keys in the tree, and returns the new root.
+    ///
+    /// An empty value (`DEFAULT_VALUE`) removes the key. If the same key appears
+    /// more than once, the last value wins. Unlike calling `update` for each key,
+    /// every interior node shared by the paths of the batch is only rehashed once.
+    pub fn update_batch<K, I>(&mut self, iter: I) -> Result<Bytes, TreeError<S::Error>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, Bytes)>,
+    {
+        self.discard_orphans();
+        let root = self.root();
+        let new_root = self.batched(|tree| {
+            let new_root = tree.do_update_batch_for_root(iter, root)?;
+            tree.commit_version(new_root.clone());
+            Ok(new_root)
+        })?;
+        self.set_root(new_root.clone());
+        Ok(new_root)
+    }
 
-        // The offset from the bottom of the tree to the start of the side nodes.
-        // Note: i-offsetOfSideNodes is the index into sideNodes[]
-        let offset_of_side_nodes = depth - side_nodes.len();
-
-        for i in 0..self.depth() {
-            match i.checked_sub(offset_of_side_nodes) {
-                Some(val) => {
-                    if get_bit_at_from_msb(&path, depth - i - 1) == RIGHT {
-                        (current_hash, current_data) =
-                            self.th.digest_node(&side_nodes[val], &current_data);
-                    } else {
-                        (current_hash, current_data) =
-                            self.th.digest_node(&current_data, &side_nodes[val]);
-                    }
+    /// Sets new values for a batch of keys in the tree at a specific root, and returns the new root.
+    pub fn update_batch_for_root<K, I>(
+        &mut self,
+        iter: I,
+        root: Bytes,
+    ) -> Result<Bytes, TreeError<S::Error>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, Bytes)>,
+    {
+        let result = self.batched(|tree| tree.do_update_batch_for_root(iter, root));
+        self.discard_orphans();
+        result
+    }
 
-                    self.nodes.set(current_hash.clone(), current_data.clone())?;
-                    current_data = current_hash.clone();
-                }
-                None => {
-                    if common_prefix_count != depth && common_prefix_count > depth - i - 1 {
-                        // If there are no sidenodes at this height, but the number of
-                        // bits that the paths of the two leaf nodes share in common is
-                        // greater than this depth, then we need to build up the tree
-                        // to this depth with placeholder values at siblings.
-                        if get_bit_at_from_msb(&path, depth - i - 1) == RIGHT {
-                            (current_hash, current_data) = self.th.digest_right_node(&current_data);
-                        } else {
-                            (current_hash, current_data) = self.th.digest_left_node(&current_data);
-                        }
-
-                        self.nodes.set(current_hash.clone(), current_data.clone())?;
-                        current_data = current_hash.clone();
-                    } else {
-                        continue;
-                    }
-                }
-            };
-        }
+    fn do_update_batch_for_root<K, I>(
+        &mut self,
+        iter: I,
+        root: Bytes,
+    ) -> Result<Bytes, TreeError<S::Error>>
+    where
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, Bytes)>,
+    {
+        let mut ops = iter
+            .into_iter()
+            .map(|(key, value)| (self.th.path_into(key), value))
+            .collect::<Vec<_>>();
 
-        self.values.set(path, value).map(|_| current_hash)
+        // Sort by path, keeping only the last write to each path. The sort is
+        // stable, so reversing first leaves the latest write at the front of each run.
+        ops.reverse();
+        ops.sort_by(|a, b| a.0.cmp(&b.0));
+        ops.dedup_by(|a, b| a.0.eq(&b.0));
+
+        Self::check_root(&root)?;
+        let subtree = self.update_batch_subtree(root, 0, &ops)?;
+        Ok(subtree
+            .hash()
+            .cloned()
+            .unwrap_or_else(|| self.th.placeholder()))
     }
 
-    /// Gets the value of a key from the tree by descending it.
-    /// Use if a key was _not_ previously added with AddBranch, otherwise use Get.
-    /// Errors if the key cannot be reached by descending.
-    pub fn get_descend(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>, S::Error> {
-        if self.root.eq(self.th.placeholder_ref()) {
-            // The tree is empty
-            return Ok(None);
+    /// Applies the sorted batch `ops` to the subtree rooted at `hash`, which sits at `depth`.
+    fn update_batch_subtree(
+        &mut self,
+        hash: Bytes,
+        depth: usize,
+        ops: &[(Bytes, Bytes)],
+    ) -> Result<BatchSubtree, TreeError<S::Error>> {
+        if hash.eq(self.th.placeholder_ref()) {
+            return self.update_batch_build(depth, ops, None);
         }
 
-        let path = self.th.path(key);
-        let depth = self.depth();
-
-        // avoid call shallow clone on root
-        let current_data = self.nodes.get(&self.root)?;
-        if TreeHasher::<<S as KVStore>::Hasher>::is_leaf(&current_data) {
-            // We've reached the end. Is this the actual leaf?
-            let (actual_path, _) =
-                TreeHasher::<<S as KVStore>::Hasher>::parse_leaf(current_data.as_ref().unwrap());
-            if path.as_ref().ne(actual_path) {
-                // Nope. Therefore the key is actually empty.
-                return Ok(None);
-            }
-
-            // Otherwise, yes. Return the value.
-            return self.values.get(path.as_ref());
+        if ops.is_empty() {
+            return Ok(BatchSubtree::Unchanged(hash));
         }
 
-        let (left, right) = TreeHasher::<<S as KVStore>::Hasher>::parse_node(&current_data);
+        let data = Some(self.get_node(&hash)?);
+        if TreeHasher::<H>::is_leaf(&data) {
+            // A leaf is the only non-empty node of its subtree, so the subtree can
+            // be rebuilt from the batch plus this leaf.
+            let (leaf_path, _) = Self::parse_leaf(&hash, data.as_deref().unwrap_or_default())?;
+            let leaf_path = Bytes::copy_from_slice(leaf_path);
+            return self.update_batch_build(depth, ops, Some((leaf_path, hash)));
+        }
 
-        let mut current_hash = if get_bit_at_from_msb(path.as_ref(), 0) == RIGHT {
-            right
-        } else {
-            left
-        };
+        let (left, right) = Self::parse_node(&hash, &data)?;
+        let split = ops.partition_point(|(path, _)| get_bit_at_from_msb(path, depth) != RIGHT);
+        let left = self.update_batch_subtree(left, depth + 1, &ops[..split])?;
+        let right = self.update_batch_subtree(right, depth + 1, &ops[split..])?;
+        let subtree = self.update_batch_combine(left, right)?;
 
-        if current_hash.eq(self.th.placeholder_ref()) {
-            // We've hit a placeholder value; this is the end.
-            return Ok(None);
+        if subtree.hash() != Some(&hash) {
+            // The node is orphaned
+            self.remove_node(&hash)?;
         }
+        Ok(subtree)
+    }
 
-        for i in 1..depth {
-            let current_data = self.nodes.get(&current_hash)?;
-            if TreeHasher::<<S as KVStore>::Hasher>::is_leaf(&current_data) {
-                // We've reached the end. Is this the actual leaf?
-                let (actual_path, _) = TreeHasher::<<S as KVStore>::Hasher>::parse_leaf(
-                    current_data.as_ref().unwrap(),
-                );
-                if path.as_ref().ne(actual_path) {
-                    // Nope. Therefore the key is actually empty.
-                    return Ok(None);
+    /// Builds the subtree at `depth` which contains no leaf other than `existing`.
+    fn update_batch_build(
+        &mut self,
+        depth: usize,
+        ops: &[(Bytes, Bytes)],
+        existing: Option<(Bytes, Bytes)>,
+    ) -> Result<BatchSubtree, TreeError<S::Error>> {
+        let mut leaves = Vec::with_capacity(ops.len() + 1);
+        let mut existing_written = false;
+        for (path, value) in ops {
+            let overwrites =
+                matches!(&existing, Some((existing_path, _)) if existing_path.eq(path));
+            if value.eq(&DEFAULT_VALUE) {
+                if overwrites {
+                    // Delete operation on the existing leaf.
+                    let (_, existing_hash) = existing.as_ref().unwrap();
+                    self.remove_node(existing_hash)?;
+                    self.remove_value(path)?;
+                    existing_written = true;
                 }
+                // Otherwise, this key is already empty.
+                continue;
+            }
 
-                // Otherwise, yes. Return the value.
-                return self.values.get(path.as_ref());
+            let value_hash = self.th.digest(value);
+            let (hash, data) = self.th.digest_leaf(path, &value_hash);
+            if overwrites {
+                let (_, existing_hash) = existing.as_ref().unwrap();
+                if existing_hash.ne(&hash) {
+                    self.remove_node(existing_hash)?;
+                }
+                existing_written = true;
             }
+            self.put_node(hash.clone(), data)?;
+            self.put_value(path.clone(), &value_hash, value.clone());
+            leaves.push((path.clone(), hash));
+        }
 
-            let (left, right) = TreeHasher::<<S as KVStore>::Hasher>::parse_node(&current_data);
-            if get_bit_at_from_msb(path.as_ref(), i) == RIGHT {
-                current_hash = right;
-            } else {
-                current_hash = left;
+        if let Some(existing) = existing {
+            if !existing_written {
+                let idx = leaves.partition_point(|(path, _)| path.lt(&existing.0));
+                leaves.insert(idx, existing);
             }
+        }
+
+        self.update_batch_build_leaves(depth, &leaves)
+    }
 
-            if current_hash.eq(self.th.placeholder_ref()) {
-                // We've hit a placeholder value; this is the end.
-                return Ok(None);
+    /// Builds the subtree at `depth` from its leaves, sorted by path.
+    fn update_batch_build_leaves(
+        &mut self,
+        depth: usize,
+        leaves: &[(Bytes, Bytes)],
+    ) -> Result<BatchSubtree, TreeError<S::Error>> {
+        match leaves.len() {
+            0 => Ok(BatchSubtree::Empty),
+            // A single leaf is bubbled up as high as possible.
+            1 => Ok(BatchSubtree::Leaf(leaves[0].1.clone())),
+            _ => {
+                let split =
+                    leaves.partition_point(|(path, _)| get_bit_at_from_msb(path, depth) != RIGHT);
+                let left = self.update_batch_build_leaves(depth + 1, &leaves[..split])?;
+                let right = self.update_batch_build_leaves(depth + 1, &leaves[split..])?;
+                self.update_batch_combine(left, right)
             }
         }
+    }
+
+    fn update_batch_combine(
+        &mut self,
+        left: BatchSubtree,
+        right: BatchSubtree,
+    ) -> Result<BatchSubtree, TreeError<S::Error>> {
+        match (&left, &right) {
+            (BatchSubtree::Empty, other) | (other, BatchSubtree::Empty) => match other {
+                BatchSubtree::Empty => return Ok(BatchSubtree::Empty),
+                // A leaf whose sibling subtree is empty is bubbled up.
+                BatchSubtree::Leaf(hash) => return Ok(BatchSubtree::Leaf(hash.clone())),
+                BatchSubtree::Unchanged(hash) => {
+                    if TreeHasher::<H>::is_leaf(&Some(self.get_node(hash)?)) {
+                        return Ok(BatchSubtree::Leaf(hash.clone()));
+                    }
+                }
+                BatchSubtree::Node(_) => {}
+            },
+            _ => {}
+        }
 
-        // The following lines of code should only be reached if the path is 256
-        // nodes high, which should be very unlikely if the underlying hash function
-        // is collision-resistant.
-        self.values.get(path.as_ref())
+        let placeholder = self.th.placeholder();
+        let (hash, data) = self.th.digest_node(
+            left.hash().unwrap_or(&placeholder),
+            right.hash().unwrap_or(&placeholder),
+        );
+        self.put_node(hash.clone(), data)?;
+        Ok(BatchSubtree::Node(hash))
+    }
+
+    /// Gets the value of a key from the tree by descending it.
+    /// Use if a key was _not_ previously added with AddBranch, otherwise use Get.
+    /// Errors if the key cannot be reached by descending.
+    pub fn get_descend(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        self.snapshot().get_descend(key)
     }
 
     /// Returns true if the value at the given key is non-default, false
     /// otherwise.
     /// Use if a key was _not_ previously added with AddBranch, otherwise use Has.
     /// Errors if the key cannot be reached by descending.
-    pub fn has_descend(&self, key: impl AsRef<[u8]>) -> Result<bool, S::Error> {
-        self.get_descend(key).map(|v| v.is_some())
+    pub fn has_descend(&self, key: impl AsRef<[u8]>) -> Result<bool, TreeError<S::Error>> {
+        self.snapshot().has_descend(key)
     }
 
     /// Adds a branch to the tree.
     /// These branches are generated by `prove_for_root`.
-    /// If the proof is invalid, a ErrBadProof is returned.
+    /// If the proof is invalid, `TreeError::BadProof` is returned.
     ///
     /// If the leaf may be updated (e.g. during a state transition fraud proof),
     /// an updatable proof should be used. See SparseMerkleTree.ProveUpdatable.
     pub fn add_branch(
         &mut self,
-        proof: SparseMerkleProof<S::Hasher>,
+        proof: SparseMerkleProof<H>,
         key: impl AsRef<[u8]>,
         val: impl Into<Bytes> + AsRef<[u8]>,
-    ) -> Result<(), S::Error> {
+    ) -> Result<(), TreeError<S::Error>> {
         let val_ref = val.as_ref();
-        let (result, updates) = proof.verify_proof_with_updates(&self.root, key.as_ref(), val_ref);
+        let (result, updates) =
+            proof.verify_proof_with_updates(&self.th, &self.root, key.as_ref(), val_ref);
         if !result {
-            return Err(BadProof.into());
-        }
-
-        if val.as_ref().ne(DEFAULT_VALUE.as_ref()) {
-            // Membership proof.
-            self.values.set(self.th.path_into(key), val.into())?;
+            return Err(TreeError::BadProof);
         }
 
+        let value_hash = self.th.digest(val_ref);
+        let path = self.th.path_into(key);
         let SparseMerkleProof {
             side_nodes,
             non_membership_leaf_data: _,
@@ -473,20 +640,25 @@ impl<S: KVStore> SparseMerkleTree<S> {
             _marker,
         } = proof;
 
-        // Update nodes along branch
-        for (hash, data) in updates {
-            self.nodes.set(hash, data)?;
-        }
+        self.batched(|tree| {
+            if val.as_ref().ne(DEFAULT_VALUE.as_ref()) {
+                // Membership proof.
+                tree.put_value(path, &value_hash, val.into());
+            }
 
-        // Update sibling node
-        if let Some(sibling) = sibling_data {
-            if !side_nodes.is_empty() {
-                self.nodes
-                    .set(side_nodes.into_iter().take(1).next().unwrap(), sibling)?;
+            // Update nodes along branch
+            for (hash, data) in updates {
+                tree.put_node(hash, data)?;
             }
-        }
 
-        Ok(())
+            // Update sibling node
+            if let (Some(sibling), Some(side_node)) = (sibling_data, side_nodes.into_iter().next())
+            {
+                tree.write_node(side_node, sibling);
+            }
+
+            Ok(())
+        })
     }
 
     /// Generates a Merkle proof for a key against the current root.
@@ -494,8 +666,11 @@ impl<S: KVStore> SparseMerkleTree<S> {
     /// This proof can be used for read-only applications, but should not be used if
     /// the leaf may be updated (e.g. in a state transition fraud proof). For
     /// updatable proofs, see `prove_updatable`.
-    pub fn prove(&self, key: impl AsRef<[u8]>) -> Result<SparseMerkleProof<S::Hasher>, S::Error> {
-        self.prove_for_root(key, self.root())
+    pub fn prove(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        self.snapshot().prove(key)
     }
 
     /// ProveForRoot generates a Merkle proof for a key, against a specific node.
@@ -508,16 +683,16 @@ impl<S: KVStore> SparseMerkleTree<S> {
         &self,
         key: impl AsRef<[u8]>,
         root: Bytes,
-    ) -> Result<SparseMerkleProof<S::Hasher>, S::Error> {
-        self.do_prove_for_root(key, root, false)
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        self.snapshot_at(root).prove(key)
     }
 
     /// Generates an updatable Merkle proof for a key against the current root.
     pub fn prove_updatable(
         &self,
         key: impl AsRef<[u8]>,
-    ) -> Result<SparseMerkleProof<S::Hasher>, S::Error> {
-        self.prove_updatable_for_root(key, self.root())
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        self.snapshot().prove_updatable(key)
     }
 
     /// Generates an updatable Merkle proof for a key, against a specific node.
@@ -526,16 +701,16 @@ impl<S: KVStore> SparseMerkleTree<S> {
         &self,
         key: impl AsRef<[u8]>,
         root: Bytes,
-    ) -> Result<SparseMerkleProof<S::Hasher>, S::Error> {
-        self.do_prove_for_root(key, root, true)
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        self.snapshot_at(root).prove_updatable(key)
     }
 
     /// Generates a compacted Merkle proof for a key against the current root.
     pub fn prove_compact(
         &self,
         key: impl AsRef<[u8]>,
-    ) -> Result<SparseCompactMerkleProof<S::Hasher>, S::Error> {
-        self.prove_compact_for_root(key, self.root())
+    ) -> Result<SparseCompactMerkleProof<H>, TreeError<S::Error>> {
+        self.snapshot().prove_compact(key)
     }
 
     /// Generates a compacted Merkle proof for a key, at a specific root.
@@ -543,171 +718,70 @@ impl<S: KVStore> SparseMerkleTree<S> {
         &self,
         key: impl AsRef<[u8]>,
         root: Bytes,
-    ) -> Result<SparseCompactMerkleProof<S::Hasher>, S::Error> {
-        let proof = self.do_prove_for_root(key, root, false)?;
-        proof.compact_into().map_err(Into::into)
+    ) -> Result<SparseCompactMerkleProof<H>, TreeError<S::Error>> {
+        self.snapshot_at(root).prove_compact(key)
     }
 
-    #[inline]
-    fn do_prove_for_root(
+    /// Generates a Merkle multi proof for several keys against the current root.
+    ///
+    /// Side nodes shared by the paths of the keys are only included once.
+    pub fn prove_many<K: AsRef<[u8]>>(
         &self,
-        key: impl AsRef<[u8]>,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<SparseMerkleMultiProof<H>, TreeError<S::Error>> {
+        self.snapshot().prove_many(keys)
+    }
+
+    /// Generates a Merkle multi proof for several keys, against a specific node.
+    pub fn prove_many_for_root<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
         root: Bytes,
-        is_updatable: bool,
-    ) -> Result<SparseMerkleProof<S::Hasher>, S::Error> {
-        let path = self.th.path(key);
-        let UpdateResult {
-            side_nodes,
-            path_nodes,
-            sibling_data,
-            current_data: leaf_data,
-        } = self.side_nodes_for_root(path.as_ref(), root, is_updatable)?;
+    ) -> Result<SparseMerkleMultiProof<H>, TreeError<S::Error>> {
+        self.snapshot_at(root).prove_many(keys)
+    }
 
-        let non_empty_side_nodes = side_nodes
-            .into_iter()
-            .filter(|n| !n.is_empty())
-            .collect::<Vec<_>>();
+    /// Generates a compacted Merkle multi proof for several keys against the current root.
+    pub fn prove_many_compact<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<SparseCompactMerkleMultiProof<H>, TreeError<S::Error>> {
+        self.snapshot().prove_many_compact(keys)
+    }
 
-        // Deal with non-membership proofs. If the leaf hash is the placeholder
-        // value, we do not need to add anything else to the proof.
-        let non_membership_leaf_data = leaf_data.and_then(|leaf_data| {
-            if path_nodes[0].ne(self.th.placeholder_ref()) {
-                let (actual_path, _) = TreeHasher::<<S as KVStore>::Hasher>::parse_leaf(&leaf_data);
-                if actual_path.ne(path.as_ref()) {
-                    // This is a non-membership proof that involves showing a different leaf.
-                    // Add the leaf data to the proof.
-                    return Some(leaf_data);
-                }
-            }
-            None
-        });
+    /// Generates a compacted Merkle multi proof for several keys, at a specific root.
+    pub fn prove_many_compact_for_root<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+        root: Bytes,
+    ) -> Result<SparseCompactMerkleMultiProof<H>, TreeError<S::Error>> {
+        self.snapshot_at(root).prove_many_compact(keys)
+    }
 
-        Ok(SparseMerkleProof::new(
-            non_empty_side_nodes,
-            non_membership_leaf_data,
-            sibling_data,
-        ))
+    /// Generates a Merkle range proof for the leaves whose paths lie in
+    /// `[start_path, end_path]`, against the current root.
+    ///
+    /// The leaves themselves can be read with `iter_leaves`.
+    pub fn prove_range(
+        &self,
+        start_path: impl AsRef<[u8]>,
+        end_path: impl AsRef<[u8]>,
+    ) -> Result<SparseMerkleRangeProof<H>, TreeError<S::Error>> {
+        self.snapshot().prove_range(start_path, end_path)
     }
 
-    /// Get all the sibling nodes (sidenodes) for a given path from a given root.
-    /// Returns an array of sibling nodes, the leaf hash found at that path, the
-    /// leaf data, and the sibling data.
+    /// Generates a Merkle range proof for the leaves whose paths lie in
+    /// `[start_path, end_path]`, against a specific node.
     ///
-    /// If the leaf is a placeholder, the leaf data is nil.
-    fn side_nodes_for_root(
+    /// Returns `TreeError::BadProof` if the paths do not have the length of a path,
+    /// or if `start_path` is greater than `end_path`.
+    pub fn prove_range_for_root(
         &self,
-        path: &[u8],
+        start_path: impl AsRef<[u8]>,
+        end_path: impl AsRef<[u8]>,
         root: Bytes,
-        get_sibling_data: bool,
-    ) -> Result<UpdateResult, <S as KVStore>::Error> {
-        // Side nodes for the path. Nodes are inserted in reverse order, then the
-        // slice is reversed at the end.
-        let mut side_nodes = Vec::with_capacity(self.depth());
-        let mut path_nodes = Vec::with_capacity(self.depth() + 1);
-        path_nodes.push(root.clone());
-
-        if root.eq(self.th.placeholder_ref()) {
-            return Ok(UpdateResult {
-                side_nodes,
-                path_nodes,
-                sibling_data: None,
-                current_data: None,
-            });
-        }
-
-        let mut current_data = self.nodes.get(&root)?;
-        if TreeHasher::<S::Hasher>::is_leaf(&current_data) {
-            // If the root is a leaf, there are also no sidenodes to return.
-            return Ok(UpdateResult {
-                side_nodes,
-                path_nodes,
-                sibling_data: None,
-                current_data,
-            });
-        }
-
-        for i in 0..self.depth() {
-            let (left_node, right_node) = TreeHasher::<S::Hasher>::parse_node(&current_data);
-
-            // Get sidenode depending on whether the path bit is on or off.
-            let (side_node, node_hash) = if get_bit_at_from_msb(path, i) == RIGHT {
-                (left_node, right_node)
-            } else {
-                (right_node, left_node)
-            };
-
-            if node_hash.eq(self.th.placeholder_ref()) {
-                // If the node is a placeholder, we've reached the end.
-                if get_sibling_data {
-                    let sibling_data = self.nodes.get(&side_node)?;
-
-                    side_nodes.push(side_node);
-                    path_nodes.push(node_hash);
-                    side_nodes.reverse();
-                    path_nodes.reverse();
-                    return Ok(UpdateResult {
-                        side_nodes,
-                        path_nodes,
-                        sibling_data,
-                        current_data: None,
-                    });
-                }
-
-                side_nodes.push(side_node);
-                path_nodes.push(node_hash);
-                side_nodes.reverse();
-                path_nodes.reverse();
-
-                return Ok(UpdateResult {
-                    side_nodes,
-                    path_nodes,
-                    sibling_data: None,
-                    current_data: None,
-                });
-            }
-
-            current_data = self.nodes.get(&node_hash)?;
-            if TreeHasher::<S::Hasher>::is_leaf(&current_data) {
-                // If the node is a leaf, we've reached the end.
-                if get_sibling_data {
-                    let sibling_data = self.nodes.get(&side_node)?;
-
-                    side_nodes.push(side_node);
-                    path_nodes.push(node_hash);
-                    side_nodes.reverse();
-                    path_nodes.reverse();
-                    return Ok(UpdateResult {
-                        side_nodes,
-                        path_nodes,
-                        sibling_data,
-                        current_data,
-                    });
-                }
-
-                side_nodes.push(side_node);
-                path_nodes.push(node_hash);
-                side_nodes.reverse();
-                path_nodes.reverse();
-                return Ok(UpdateResult {
-                    side_nodes,
-                    path_nodes,
-                    sibling_data: None,
-                    current_data,
-                });
-            }
-
-            side_nodes.push(side_node);
-            path_nodes.push(node_hash);
-        }
-
-        side_nodes.reverse();
-        path_nodes.reverse();
-        Ok(UpdateResult {
-            side_nodes,
-            path_nodes,
-            sibling_data: None,
-            current_data,
-        })
+    ) -> Result<SparseMerkleRangeProof<H>, TreeError<S::Error>> {
+        self.snapshot_at(root).prove_range(start_path, end_path)
     }
 }
 
@@ -717,3 +791,26 @@ struct UpdateResult {
     sibling_data: Option<Bytes>,
     current_data: Option<Bytes>,
 }
+
+/// The result of applying a batch of updates to a subtree.
+enum BatchSubtree {
+    /// The subtree is empty.
+    Empty,
+    /// The subtree only contains a single leaf.
+    Leaf(Bytes),
+    /// The subtree root is a newly created node.
+    Node(Bytes),
+    /// The subtree was not touched by the batch.
+    Unchanged(Bytes),
+}
+
+impl BatchSubtree {
+    fn hash(&self) -> Option<&Bytes> {
+        match self {
+            BatchSubtree::Empty => None,
+            BatchSubtree::Leaf(hash) | BatchSubtree::Node(hash) | BatchSubtree::Unchanged(hash) => {
+                Some(hash)
+            }
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use super::{
+    changes::{self, Change, SideNodesWalk},
+    view::proof_from_side_nodes,
+    UpdateResult, DEFAULT_VALUE,
+};
+use crate::{
+    tree_hasher::TreeHasher, AsyncKVStore, SparseCompactMerkleProof, SparseMerkleProof,
+    StoreHasher, TreeError, TreeHashScheme, WriteBatch,
+};
+use alloc::vec::Vec;
+use bytes::Bytes;
+
+/// Sparse Merkle tree over an `AsyncKVStore`.
+///
+/// The tree computes the same roots and proofs as `SparseMerkleTree`, and lays out
+/// its stores the same way, so the stores can be imported by either tree. Only
+/// unversioned trees are supported. Like `SparseMerkleTree`, the writes of each
+/// operation are handed to each store as one `WriteBatch` once the operation has
+/// succeeded.
+pub struct AsyncSparseMerkleTree<
+    S: AsyncKVStore,
+    H: TreeHashScheme + Send + Sync = <S as StoreHasher>::Hasher,
+> {
+    th: TreeHasher<H>,
+    pub(super) nodes: S,
+    pub(super) values: S,
+    root: Bytes,
+    /// The writes of the operation in progress, see `write_pending`.
+    pending_nodes: WriteBatch,
+    pending_values: WriteBatch,
+}
+
+impl<S: AsyncKVStore + core::fmt::Debug, H: TreeHashScheme + Send + Sync> core::fmt::Debug
+    for AsyncSparseMerkleTree<S, H>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct(core::any::type_name::<Self>())
+            .field("nodes", &self.nodes)
+            .field("values", &self.values)
+            .field("root", &self.root().as_ref())
+            .field("tree_hasher", &self.th)
+            .finish()
+    }
+}
+
+impl<S: AsyncKVStore + Default, H: TreeHashScheme + Default + Send + Sync> Default
+    for AsyncSparseMerkleTree<S, H>
+{
+    fn default() -> Self {
+        Self::new_with_stores(S::default(), S::default())
+    }
+}
+
+impl<S: AsyncKVStore + Default, H: TreeHashScheme + Default + Send + Sync>
+    AsyncSparseMerkleTree<S, H>
+{
+    /// Create a new sparse merkle tree
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S: AsyncKVStore, H: TreeHashScheme + Default + Send + Sync> AsyncSparseMerkleTree<S, H> {
+    /// Create a sparse merkle tree based on the given stores
+    #[inline]
+    pub fn new_with_stores(nodes_store: S, values_store: S) -> Self {
+        Self::new_with_scheme(nodes_store, values_store, H::default())
+    }
+
+    /// Imports a Sparse Merkle tree from non-empty `AsyncKVStore`.
+    #[inline]
+    pub fn import(nodes_store: S, values_store: S, root: impl Into<Bytes>) -> Self {
+        Self::import_with_scheme(nodes_store, values_store, root, H::default())
+    }
+}
+
+impl<S: AsyncKVStore, H: TreeHashScheme + Send + Sync> AsyncSparseMerkleTree<S, H> {
+    /// Create a sparse merkle tree based on the given stores, hashed with the given
+    /// scheme instance.
+    #[inline]
+    pub fn new_with_scheme(nodes_store: S, values_store: S, scheme: H) -> Self {
+        let th = TreeHasher::new(scheme);
+        let root = th.placeholder();
+        Self::with_hasher(th, nodes_store, values_store, root)
+    }
+
+    /// Imports a Sparse Merkle tree from non-empty `AsyncKVStore`, hashed with the
+    /// given scheme instance.
+    #[inline]
+    pub fn import_with_scheme(
+        nodes_store: S,
+        values_store: S,
+        root: impl Into<Bytes>,
+        scheme: H,
+    ) -> Self {
+        Self::with_hasher(
+            TreeHasher::new(scheme),
+            nodes_store,
+            values_store,
+            root.into(),
+        )
+    }
+
+    fn with_hasher(th: TreeHasher<H>, nodes: S, values: S, root: Bytes) -> Self {
+        Self {
+            th,
+            nodes,
+            values,
+            root,
+            pending_nodes: WriteBatch::new(),
+            pending_values: WriteBatch::new(),
+        }
+    }
+
+    /// Returns the hash scheme instance of the tree.
+    #[inline]
+    pub fn scheme(&self) -> &H {
+        self.th.scheme()
+    }
+
+    /// Binds the tree to the given identifier (see `SparseMerkleTree::with_tree_id`).
+    #[inline]
+    pub fn with_tree_id(mut self, tree_id: impl AsRef<[u8]>) -> Self {
+        self.th = self.th.with_tree_id(Some(tree_id.as_ref()));
+        self
+    }
+
+    /// Returns the identifier of the tree, if it has one.
+    #[inline]
+    pub fn tree_id(&self) -> Option<&[u8]> {
+        self.th.tree_id()
+    }
+
+    /// Returns the root of the sparse merkle tree
+    #[inline]
+    pub fn root(&self) -> Bytes {
+        self.root.clone()
+    }
+
+    /// Returns the root reference of the sparse merkle tree
+    #[inline]
+    pub fn root_ref(&self) -> &[u8] {
+        &self.root
+    }
+
+    /// Set new root for the tree
+    #[inline]
+    pub fn set_root(&mut self, root: impl Into<Bytes>) {
+        self.root = root.into();
+    }
+
+    /// Gets the value of a key from the tree.
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        if self.root.as_ref().eq(self.th.placeholder_ref()) {
+            return Ok(None);
+        }
+
+        let path = self.th.path(key);
+        self.values
+            .get(path.as_ref())
+            .await
+            .map_err(TreeError::Store)
+    }
+
+    /// Returns true if the value at the given key is non-default, false
+    /// otherwise.
+    pub async fn contains(&self, key: &[u8]) -> Result<bool, TreeError<S::Error>> {
+        if self.root.as_ref().eq(self.th.placeholder_ref()) {
+            return Ok(false);
+        }
+
+        let path = self.th.path(key);
+        self.values
+            .contains(path.as_ref())
+            .await
+            .map_err(TreeError::Store)
+    }
+
+    /// Removes a value from tree.
+    pub async fn remove(&mut self, key: &[u8]) -> Result<(), TreeError<S::Error>> {
+        self.update(key, DEFAULT_VALUE).await
+    }
+
+    /// Removes a value from tree at a specific root. It returns the new root of the tree.
+    pub async fn remove_for_root(
+        &mut self,
+        key: &[u8],
+        root: Bytes,
+    ) -> Result<Bytes, TreeError<S::Error>> {
+        self.update_for_root(key, DEFAULT_VALUE, root).await
+    }
+
+    /// Sets a new value for a key in the tree.
+    pub async fn update(&mut self, key: &[u8], value: Bytes) -> Result<(), TreeError<S::Error>> {
+        let new_root = self.update_for_root(key, value, self.root()).await?;
+        self.set_root(new_root);
+        Ok(())
+    }
+
+    /// Sets a new value for a key in the tree at a specific root, and returns the new root.
+    pub async fn update_for_root(
+        &mut self,
+        key: &[u8],
+        value: Bytes,
+        root: Bytes,
+    ) -> Result<Bytes, TreeError<S::Error>> {
+        let result = self.do_update_for_root(key, value, root).await;
+        self.write_pending(result).await
+    }
+
+    async fn do_update_for_root(
+        &mut self,
+        key: &[u8],
+        value: Bytes,
+        root: Bytes,
+    ) -> Result<Bytes, TreeError<S::Error>> {
+        let path = self.th.path_into(key);
+        let result = self.side_nodes_for_root(&path, root.clone(), false).await?;
+
+        let changes = if value.eq(&DEFAULT_VALUE) {
+            // Delete operation.
+            if !changes::holds_path(&self.th, &path, &result)? {
+                // This key is already empty; return the old root.
+                return Ok(root);
+            }
+            let sibling_data = match result.side_nodes.first() {
+                Some(side_node) if side_node.ne(self.th.placeholder_ref()) => {
+                    Some(self.get_node(side_node).await?)
+                }
+                _ => None,
+            };
+            changes::remove_changes(&self.th, &path, result, sibling_data)
+        } else {
+            // Insert operation.
+            match changes::update_changes(&self.th, path, value, result)? {
+                Some(changes) => changes,
+                // The same value is already set.
+                None => return Ok(self.root()),
+            }
+        };
+        self.apply_changes(changes).await
+    }
+
+    /// Adds a branch to the tree.
+    /// These branches are generated by `prove_for_root`.
+    /// If the proof is invalid, `TreeError::BadProof` is returned.
+    ///
+    /// If the leaf may be updated (e.g. during a state transition fraud proof),
+    /// an updatable proof should be used. See `prove_updatable`.
+    pub async fn add_branch(
+        &mut self,
+        proof: SparseMerkleProof<H>,
+        key: impl AsRef<[u8]>,
+        val: impl Into<Bytes> + AsRef<[u8]>,
+    ) -> Result<(), TreeError<S::Error>> {
+        let val_ref = val.as_ref();
+        let (result, updates) =
+            proof.verify_proof_with_updates(&self.th, &self.root, key.as_ref(), val_ref);
+        if !result {
+            return Err(TreeError::BadProof);
+        }
+
+        if val_ref.ne(DEFAULT_VALUE.as_ref()) {
+            // Membership proof.
+            self.put_value(self.th.path_into(key), val.into());
+        }
+
+        // Update nodes along branch
+        for (hash, data) in updates {
+            self.put_node(hash, data);
+        }
+
+        // Update sibling node
+        if let (Some(sibling), Some(side_node)) = (proof.sibling_data, proof.side_nodes.first()) {
+            self.put_node(side_node.clone(), sibling);
+        }
+
+        self.write_pending(Ok(())).await
+    }
+
+    /// Generates a Merkle proof for a key against the current root.
+    ///
+    /// This proof can be used for read-only applications, but should not be used if
+    /// the leaf may be updated (e.g. in a state transition fraud proof). For
+    /// updatable proofs, see `prove_updatable`.
+    pub async fn prove(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        self.prove_for_root(key, self.root()).await
+    }
+
+    /// Generates a Merkle proof for a key, against a specific node.
+    pub async fn prove_for_root(
+        &self,
+        key: impl AsRef<[u8]>,
+        root: Bytes,
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        let path = self.th.path(key);
+        let result = self.side_nodes_for_root(path.as_ref(), root, false).await?;
+        proof_from_side_nodes(&self.th, path.as_ref(), result)
+    }
+
+    /// Generates an updatable Merkle proof for a key against the current root.
+    pub async fn prove_updatable(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        self.prove_updatable_for_root(key, self.root()).await
+    }
+
+    /// Generates an updatable Merkle proof for a key, against a specific node.
+    pub async fn prove_updatable_for_root(
+        &self,
+        key: impl AsRef<[u8]>,
+        root: Bytes,
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        let path = self.th.path(key);
+        let result = self.side_nodes_for_root(path.as_ref(), root, true).await?;
+        proof_from_side_nodes(&self.th, path.as_ref(), result)
+    }
+
+    /// Generates a compacted Merkle proof for a key against the current root.
+    pub async fn prove_compact(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<SparseCompactMerkleProof<H>, TreeError<S::Error>> {
+        self.prove_compact_for_root(key, self.root()).await
+    }
+
+    /// Generates a compacted Merkle proof for a key, against a specific node.
+    pub async fn prove_compact_for_root(
+        &self,
+        key: impl AsRef<[u8]>,
+        root: Bytes,
+    ) -> Result<SparseCompactMerkleProof<H>, TreeError<S::Error>> {
+        let proof = self.prove_for_root(key, root).await?;
+        proof
+            .compact_into_with(self.th.scheme())
+            .map_err(Into::into)
+    }
+
+    /// Writes the writes buffered by an operation to the stores if it succeeded, as
+    /// `SparseMerkleTree` does: `begin_batch` is called on both stores, then
+    /// `write_batch`, then `commit`, and `abort_batch` on both stores if any of them
+    /// fails. Nothing is written if the operation failed.
+    async fn write_pending<T>(
+        &mut self,
+        result: Result<T, TreeError<S::Error>>,
+    ) -> Result<T, TreeError<S::Error>> {
+        let nodes = core::mem::take(&mut self.pending_nodes);
+        let values = core::mem::take(&mut self.pending_values);
+        let result = result?;
+        if nodes.is_empty() && values.is_empty() {
+            return Ok(result);
+        }
+
+        if let Err(e) = self.write_stores(nodes, values).await {
+            let _ = self.nodes.abort_batch().await;
+            let _ = self.values.abort_batch().await;
+            return Err(TreeError::Store(e));
+        }
+        Ok(result)
+    }
+
+    async fn write_stores(
+        &mut self,
+        nodes: WriteBatch,
+        values: WriteBatch,
+    ) -> Result<(), S::Error> {
+        self.nodes.begin_batch().await?;
+        self.values.begin_batch().await?;
+        self.nodes.write_batch(nodes).await?;
+        self.values.write_batch(values).await?;
+        self.nodes.commit().await?;
+        self.values.commit().await
+    }
+
+    /// Reads a node, seeing the writes of the operation in progress.
+    async fn read_node(&self, hash: &[u8]) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        match self.pending_nodes.get(hash) {
+            Some(data) => Ok(data.cloned()),
+            None => self.nodes.get(hash).await.map_err(TreeError::Store),
+        }
+    }
+
+    /// Reads a node which must be in the nodes store, checking that its data is well
+    /// formed.
+    async fn get_node(&self, hash: &Bytes) -> Result<Bytes, TreeError<S::Error>> {
+        changes::checked_node::<H, _>(hash, self.read_node(hash).await?)
+    }
+
+    /// Get all the sibling nodes (sidenodes) for a given path from a given root,
+    /// as `SparseMerkleTreeView::side_nodes_for_root` does.
+    async fn side_nodes_for_root(
+        &self,
+        path: &[u8],
+        root: Bytes,
+        get_sibling_data: bool,
+    ) -> Result<UpdateResult, TreeError<S::Error>> {
+        let mut walk = SideNodesWalk::new(&self.th, path, root, get_sibling_data)?;
+        while let Some(hash) = walk.next_read().cloned() {
+            walk.feed(self.read_node(&hash).await?)?;
+        }
+        Ok(walk.finish())
+    }
+
+    /// Applies the changes made by an update to the stores, returning the new root.
+    async fn apply_changes(
+        &mut self,
+        (changes, root): (Vec<Change>, Bytes),
+    ) -> Result<Bytes, TreeError<S::Error>> {
+        for change in changes {
+            match change {
+                Change::PutNode(hash, data) => self.put_node(hash, data),
+                Change::RemoveNode(hash) => self.remove_node(&hash).await?,
+                Change::PutValue { path, value, .. } => self.put_value(path, value),
+                Change::RemoveValue(path) => {
+                    take(&mut self.pending_values, &self.values, &path).await?;
+                }
+            }
+        }
+        Ok(root)
+    }
+
+    /// Writes a node as part of the operation in progress.
+    #[inline]
+    fn put_node(&mut self, hash: Bytes, data: Bytes) {
+        self.pending_nodes.set(hash, data);
+    }
+
+    /// Removes a node orphaned by an update as part of the operation in progress.
+    async fn remove_node(&mut self, hash: &Bytes) -> Result<(), TreeError<S::Error>> {
+        let data = take(&mut self.pending_nodes, &self.nodes, hash).await?;
+        changes::checked_node::<H, _>(hash, data).map(|_| ())
+    }
+
+    /// Writes the latest value of a path as part of the operation in progress.
+    #[inline]
+    fn put_value(&mut self, path: Bytes, value: Bytes) {
+        self.pending_values.set(path, value);
+    }
+}
+
+/// Removes a key, if it exists, as part of the operation in progress, returning its
+/// value, as `SparseMerkleTree` does.
+async fn take<S: AsyncKVStore>(
+    pending: &mut WriteBatch,
+    store: &S,
+    key: &Bytes,
+) -> Result<Option<Bytes>, TreeError<S::Error>> {
+    let value = match pending.get(key) {
+        Some(value) => value.cloned(),
+        None => store.get(key).await.map_err(TreeError::Store)?,
+    };
+    if value.is_some() {
+        if store.contains(key).await.map_err(TreeError::Store)? {
+            pending.remove(key.clone());
+        } else {
+            // The key was only written by the operation in progress.
+            pending.discard(key);
+        }
+    }
+    Ok(value)
+}
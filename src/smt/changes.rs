@@ -0,0 +1,377 @@
+//! The changes made to the stores by a single update, computed from the nodes read
+//! along the path of the key, and the descent reading those nodes. Shared by the
+//! synchronous and asynchronous trees, which only differ in how they read and write
+//! the stores.
+use super::{UpdateResult, RIGHT};
+use crate::{
+    count_common_prefix, get_bit_at_from_msb, tree_hasher::TreeHasher, TreeError, TreeHashScheme,
+};
+use alloc::vec::Vec;
+use bytes::Bytes;
+
+/// A change to the stores, applied in order.
+pub(super) enum Change {
+    /// Writes a node to the nodes store.
+    PutNode(Bytes, Bytes),
+    /// Removes a node orphaned by the update.
+    RemoveNode(Bytes),
+    /// Writes the latest value of a path, and in versioned trees the value
+    /// content-addressed by its hash.
+    PutValue {
+        path: Bytes,
+        value_hash: Bytes,
+        value: Bytes,
+    },
+    /// Removes the latest value of a path.
+    RemoveValue(Bytes),
+}
+
+/// Checks the data read for a node which must be in the nodes store, returning
+/// `MissingNode` if there is none and `CorruptNode` if it is malformed.
+pub(super) fn checked_node<H: TreeHashScheme, E>(
+    hash: &Bytes,
+    data: Option<Bytes>,
+) -> Result<Bytes, TreeError<E>> {
+    match data {
+        Some(data) if TreeHasher::<H>::is_well_formed(&data) => Ok(data),
+        Some(_) => Err(TreeError::CorruptNode { hash: hash.clone() }),
+        None => Err(TreeError::MissingNode { hash: hash.clone() }),
+    }
+}
+
+/// The descent from a root along a path, collecting the side nodes and the nodes on
+/// the path for `side_nodes_for_root`, independently of how nodes are read.
+///
+/// `next_read` returns the hash of the node to read next, whose data (or `None` if
+/// it is not in the nodes store) is handed to `feed`, until there is nothing left
+/// to read and `finish` returns the result.
+pub(super) struct SideNodesWalk<'a, H> {
+    th: &'a TreeHasher<H>,
+    path: &'a [u8],
+    get_sibling_data: bool,
+    side_nodes: Vec<Bytes>,
+    path_nodes: Vec<Bytes>,
+    current_data: Option<Bytes>,
+    sibling_data: Option<Bytes>,
+    /// The node to read next, and whether it is the sibling of the last path node,
+    /// which may be missing.
+    next: Option<(Bytes, bool)>,
+}
+
+impl<'a, H: TreeHashScheme> SideNodesWalk<'a, H> {
+    /// Starts the descent from `root`, which must have the length of a hash.
+    pub(super) fn new<E>(
+        th: &'a TreeHasher<H>,
+        path: &'a [u8],
+        root: Bytes,
+        get_sibling_data: bool,
+    ) -> Result<Self, TreeError<E>> {
+        if root.len() != TreeHasher::<H>::path_size() {
+            return Err(TreeError::InvalidRootLength { len: root.len() });
+        }
+        let depth = Self::depth();
+        let next = root.ne(th.placeholder_ref()).then(|| (root.clone(), false));
+        let mut path_nodes = Vec::with_capacity(depth + 1);
+        path_nodes.push(root);
+        Ok(Self {
+            th,
+            path,
+            get_sibling_data,
+            side_nodes: Vec::with_capacity(depth),
+            path_nodes,
+            current_data: None,
+            sibling_data: None,
+            next,
+        })
+    }
+
+    #[inline]
+    fn depth() -> usize {
+        TreeHasher::<H>::path_size() * 8
+    }
+
+    /// Returns the hash of the node to read next, or `None` once the descent is over.
+    #[inline]
+    pub(super) fn next_read(&self) -> Option<&Bytes> {
+        self.next.as_ref().map(|(hash, _)| hash)
+    }
+
+    /// Hands the data read for the node returned by `next_read` to the descent.
+    pub(super) fn feed<E>(&mut self, data: Option<Bytes>) -> Result<(), TreeError<E>> {
+        let (hash, is_sibling) = self.next.take().expect("no node to read");
+        if is_sibling {
+            self.sibling_data = data;
+            return Ok(());
+        }
+
+        let data = checked_node::<H, E>(&hash, data)?;
+        if TreeHasher::<H>::is_leaf(&Some(&data)) {
+            // If the node is a leaf, we've reached the end. A leaf at the root has
+            // no sibling.
+            self.current_data = Some(data);
+            if self.get_sibling_data && !self.side_nodes.is_empty() {
+                self.read_sibling();
+            }
+            return Ok(());
+        }
+        if self.side_nodes.len() == Self::depth() {
+            self.current_data = Some(data);
+            return Ok(());
+        }
+
+        // The last path node is the node being descended.
+        let (left_node, right_node) =
+            TreeHasher::<H>::parse_node(&data).ok_or(TreeError::CorruptNode { hash })?;
+        self.current_data = Some(data);
+
+        // Get sidenode depending on whether the path bit is on or off.
+        let (side_node, node_hash) =
+            if get_bit_at_from_msb(self.path, self.side_nodes.len()) == RIGHT {
+                (left_node, right_node)
+            } else {
+                (right_node, left_node)
+            };
+        self.side_nodes.push(side_node);
+        self.path_nodes.push(node_hash.clone());
+
+        if node_hash.eq(self.th.placeholder_ref()) {
+            // If the node is a placeholder, we've reached the end.
+            self.current_data = None;
+            if self.get_sibling_data {
+                self.read_sibling();
+            }
+        } else {
+            self.next = Some((node_hash, false));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn read_sibling(&mut self) {
+        self.next = self
+            .side_nodes
+            .last()
+            .map(|side_node| (side_node.clone(), true));
+    }
+
+    /// Returns the nodes found along the path, ordered from the bottom up.
+    pub(super) fn finish(self) -> UpdateResult {
+        debug_assert!(self.next.is_none(), "the descent is not over");
+        let Self {
+            mut side_nodes,
+            mut path_nodes,
+            sibling_data,
+            current_data,
+            ..
+        } = self;
+        side_nodes.reverse();
+        path_nodes.reverse();
+        UpdateResult {
+            side_nodes,
+            path_nodes,
+            sibling_data,
+            current_data,
+        }
+    }
+}
+
+/// Parses the data of the leaf with the given hash into its path and value hash.
+#[allow(clippy::type_complexity)]
+pub(super) fn parse_leaf<'a, H: TreeHashScheme, E>(
+    hash: &Bytes,
+    data: &'a [u8],
+) -> Result<(&'a [u8], &'a [u8]), TreeError<E>> {
+    TreeHasher::<H>::parse_leaf(data).ok_or_else(|| TreeError::CorruptNode { hash: hash.clone() })
+}
+
+/// Returns true if the leaf found at the end of the path of a key holds that key, so
+/// removing the key changes the tree.
+pub(super) fn holds_path<H: TreeHashScheme, E>(
+    th: &TreeHasher<H>,
+    path: &[u8],
+    result: &UpdateResult,
+) -> Result<bool, TreeError<E>> {
+    if result.path_nodes[0].eq(th.placeholder_ref()) {
+        // This key is already empty as it is a placeholder.
+        return Ok(false);
+    }
+
+    let (actual_path, _) = parse_leaf::<H, E>(
+        &result.path_nodes[0],
+        result.current_data.as_deref().unwrap_or_default(),
+    )?;
+    // Otherwise the key is empty if a different key was found in its place.
+    Ok(path.eq(actual_path))
+}
+
+/// Computes the changes removing the leaf of `path`, which must hold the key (see
+/// `holds_path`), and returns them with the new root.
+///
+/// `sibling_data` is the data of the first side node, or `None` if it is a
+/// placeholder.
+pub(super) fn remove_changes<H: TreeHashScheme>(
+    th: &TreeHasher<H>,
+    path: &[u8],
+    result: UpdateResult,
+    sibling_data: Option<Bytes>,
+) -> (Vec<Change>, Bytes) {
+    let UpdateResult {
+        side_nodes,
+        path_nodes,
+        ..
+    } = result;
+
+    // All nodes above the deleted leaf are now orphaned
+    let mut changes = path_nodes
+        .into_iter()
+        .map(Change::RemoveNode)
+        .collect::<Vec<_>>();
+
+    let side_nodes_num = side_nodes.len();
+    let mut current_data = Bytes::new();
+    let mut current_hash = Bytes::new();
+    let mut non_placeholder_reached = false;
+    for (idx, side_node) in side_nodes.into_iter().enumerate() {
+        if current_data.is_empty() {
+            // Only the first side node is read, as `current_data` is set below.
+            if TreeHasher::<H>::is_leaf(&sibling_data) {
+                // This is the leaf sibling that needs to be bubbled up the tree.
+                current_hash = side_node.clone();
+                current_data = side_node.clone();
+                continue;
+            } else {
+                // This is the node sibling that needs to be left in its place.
+                current_data = th.placeholder();
+                non_placeholder_reached = true;
+            }
+        }
+
+        if !non_placeholder_reached && side_node.eq(th.placeholder_ref()) {
+            // We found another placeholder sibling node, keep going up the
+            // tree until we find the first sibling that is not a placeholder.
+            continue;
+        } else if !non_placeholder_reached {
+            // We found the first sibling node that is not a placeholder, it is
+            // time to insert our leaf sibling node here.
+            non_placeholder_reached = true;
+        }
+
+        if get_bit_at_from_msb(path, side_nodes_num - idx - 1) == RIGHT {
+            (current_hash, current_data) = th.digest_node(side_node, &current_data);
+        } else {
+            (current_hash, current_data) = th.digest_node(&current_data, side_node);
+        }
+
+        changes.push(Change::PutNode(current_hash.clone(), current_data.clone()));
+
+        current_data = current_hash.clone();
+    }
+
+    if current_hash.is_empty() {
+        // The tree is empty; return placeholder value as root.
+        current_hash = th.placeholder();
+    }
+    changes.push(Change::RemoveValue(Bytes::copy_from_slice(path)));
+    (changes, current_hash)
+}
+
+/// Computes the changes setting `value` at `path`, and returns them with the new
+/// root. Returns `None` if the same value is already set.
+pub(super) fn update_changes<H: TreeHashScheme, E>(
+    th: &TreeHasher<H>,
+    path: Bytes,
+    value: Bytes,
+    result: UpdateResult,
+) -> Result<Option<(Vec<Change>, Bytes)>, TreeError<E>> {
+    let UpdateResult {
+        side_nodes,
+        path_nodes,
+        current_data: old_leaf_data,
+        ..
+    } = result;
+    let depth = TreeHasher::<H>::path_size() * 8;
+    let value_hash = th.digest(&value);
+    let (mut current_hash, mut current_data) = th.digest_leaf(&path, &value_hash);
+    let mut changes = Vec::with_capacity(2 * depth);
+    changes.push(Change::PutNode(current_hash.clone(), current_data.clone()));
+    current_data = current_hash.clone();
+
+    // If the leaf node that sibling nodes lead to has a different actual path
+    // than the leaf node being updated, we need to create an intermediate node
+    // with this leaf node and the new leaf node as children.
+    //
+    // First, get the number of bits that the paths of the two leaf nodes share
+    // in common as a prefix.
+    let (common_prefix_count, old_value_hash) = if path_nodes[0].eq(th.placeholder_ref()) {
+        (depth, None)
+    } else {
+        let (actual_path, value_hash) =
+            parse_leaf::<H, E>(&path_nodes[0], old_leaf_data.as_deref().unwrap_or_default())?;
+        (count_common_prefix(&path, actual_path), Some(value_hash))
+    };
+
+    if common_prefix_count != depth {
+        if get_bit_at_from_msb(&path, common_prefix_count) == RIGHT {
+            (current_hash, current_data) = th.digest_node(&path_nodes[0], &current_data);
+        } else {
+            (current_hash, current_data) = th.digest_node(&current_data, &path_nodes[0]);
+        }
+
+        changes.push(Change::PutNode(current_hash.clone(), current_data.clone()));
+        current_data = current_hash.clone();
+    } else if let Some(old_value_hash) = old_value_hash {
+        // Short-circuit if the same value is being set
+        if value_hash.eq(old_value_hash) {
+            return Ok(None);
+        }
+
+        // If an old leaf exists, remove it
+        changes.push(Change::RemoveNode(path_nodes[0].clone()));
+        changes.push(Change::RemoveValue(path.clone()));
+    }
+
+    // All remaining path nodes are orphaned
+    changes.extend(path_nodes.into_iter().skip(1).map(Change::RemoveNode));
+
+    // The offset from the bottom of the tree to the start of the side nodes.
+    // Note: i-offsetOfSideNodes is the index into sideNodes[]
+    let offset_of_side_nodes = depth - side_nodes.len();
+
+    for i in 0..depth {
+        match i.checked_sub(offset_of_side_nodes) {
+            Some(val) => {
+                if get_bit_at_from_msb(&path, depth - i - 1) == RIGHT {
+                    (current_hash, current_data) = th.digest_node(&side_nodes[val], &current_data);
+                } else {
+                    (current_hash, current_data) = th.digest_node(&current_data, &side_nodes[val]);
+                }
+
+                changes.push(Change::PutNode(current_hash.clone(), current_data.clone()));
+                current_data = current_hash.clone();
+            }
+            None => {
+                if common_prefix_count != depth && common_prefix_count > depth - i - 1 {
+                    // If there are no sidenodes at this height, but the number of
+                    // bits that the paths of the two leaf nodes share in common is
+                    // greater than this depth, then we need to build up the tree
+                    // to this depth with placeholder values at siblings.
+                    if get_bit_at_from_msb(&path, depth - i - 1) == RIGHT {
+                        (current_hash, current_data) = th.digest_right_node(&current_data);
+                    } else {
+                        (current_hash, current_data) = th.digest_left_node(&current_data);
+                    }
+
+                    changes.push(Change::PutNode(current_hash.clone(), current_data.clone()));
+                    current_data = current_hash.clone();
+                }
+            }
+        };
+    }
+
+    changes.push(Change::PutValue {
+        path,
+        value_hash: Bytes::copy_from_slice(&value_hash),
+        value,
+    });
+    Ok(Some((changes, current_hash)))
+}
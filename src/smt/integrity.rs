@@ -0,0 +1,175 @@
+use super::{values::value_key, SparseMerkleTree};
+use crate::{
+    count_common_prefix, set_bit_at_from_msb, tree_hasher::TreeHasher, KVStore, TreeError,
+    TreeHashScheme,
+};
+use alloc::vec;
+use bytes::Bytes;
+
+/// The kind of an inconsistency found in the stores of a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InconsistencyKind {
+    /// A reachable node is missing from the nodes store.
+    MissingNode,
+    /// The data of a node is malformed, or the node is deeper than the tree.
+    CorruptNode,
+    /// The data of a node does not hash to the hash it is stored under.
+    HashMismatch,
+    /// The path of a leaf does not lie in the subtree the leaf is found in.
+    MisplacedLeaf,
+    /// The value of a leaf is missing from the values store.
+    MissingValue,
+    /// The value of a leaf does not hash to the value hash of the leaf.
+    ValueMismatch,
+}
+
+/// An inconsistency found in the stores of a tree, at the position of the tree
+/// given by the first `depth` bits of `path`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inconsistency {
+    kind: InconsistencyKind,
+    path: Bytes,
+    depth: usize,
+    hash: Bytes,
+}
+
+impl Inconsistency {
+    /// Returns the kind of the inconsistency.
+    #[inline]
+    pub fn kind(&self) -> InconsistencyKind {
+        self.kind
+    }
+
+    /// Returns the path leading to the inconsistent node. Only the first `depth`
+    /// bits are meaningful, except for leaves, whose full path is returned.
+    #[inline]
+    pub fn path(&self) -> &Bytes {
+        &self.path
+    }
+
+    /// Returns the depth of the inconsistent node.
+    #[inline]
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the hash of the inconsistent node.
+    #[inline]
+    pub fn hash(&self) -> &Bytes {
+        &self.hash
+    }
+}
+
+impl core::fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:?} at depth {} of path {:?}",
+            self.kind,
+            self.depth,
+            self.path.as_ref()
+        )
+    }
+}
+
+impl<S: KVStore, H: TreeHashScheme> SparseMerkleTree<S, H> {
+    /// Imports a Sparse Merkle tree from non-empty `KVStore`, after checking that
+    /// the stores hold the whole tree with the given root (see `verify_integrity`).
+    pub fn import_verified(
+        nodes_store: S,
+        values_store: S,
+        root: impl Into<Bytes>,
+    ) -> Result<Self, TreeError<S::Error>>
+    where
+        H: Default,
+    {
+        let tree = Self::import(nodes_store, values_store, root);
+        tree.verify_integrity(tree.root())?;
+        Ok(tree)
+    }
+
+    /// Checks that the stores hold the whole tree with the given root.
+    ///
+    /// Walks every node reachable from `root` in path order, recomputing its hash
+    /// from its data, and checks that the value of every leaf is in the values
+    /// store and matches the value hash of the leaf. For the latest root of the
+    /// tree, the value stored under the path of every leaf must be its value, and
+    /// versioned trees must also have every value stored by its hash. Returns the
+    /// first inconsistency found as `TreeError::Inconsistent`.
+    pub fn verify_integrity(&self, root: impl Into<Bytes>) -> Result<(), TreeError<S::Error>> {
+        let path_size = TreeHasher::<H>::path_size();
+        let root = root.into();
+        Self::check_root(&root)?;
+        let is_latest = root.eq(&self.root);
+        let mut stack = vec![(root, 0, Bytes::from(vec![0; path_size]))];
+        while let Some((hash, depth, path)) = stack.pop() {
+            if hash.eq(self.th.placeholder_ref()) {
+                continue;
+            }
+
+            let inconsistency = |kind, path| Inconsistency {
+                kind,
+                path,
+                depth,
+                hash: hash.clone(),
+            };
+            let data = match self.nodes.get(&hash).map_err(TreeError::Store)? {
+                Some(data) => data,
+                None => return Err(inconsistency(InconsistencyKind::MissingNode, path).into()),
+            };
+
+            if !TreeHasher::<H>::is_well_formed(&data) {
+                return Err(inconsistency(InconsistencyKind::CorruptNode, path).into());
+            }
+            if self.th.digest_data(&data).as_deref() != Some(hash.as_ref()) {
+                return Err(inconsistency(InconsistencyKind::HashMismatch, path).into());
+            }
+
+            if TreeHasher::<H>::is_leaf(&Some(&data)) {
+                let (leaf_path, value_hash) = Self::parse_leaf(&hash, &data)?;
+                let leaf_path = Bytes::copy_from_slice(leaf_path);
+                if count_common_prefix(&path, &leaf_path) < depth {
+                    return Err(inconsistency(InconsistencyKind::MisplacedLeaf, leaf_path).into());
+                }
+
+                let check_value = |value: Option<Bytes>| match value {
+                    None => Err(inconsistency(
+                        InconsistencyKind::MissingValue,
+                        leaf_path.clone(),
+                    )),
+                    Some(value) if self.th.digest(&value) != value_hash => Err(inconsistency(
+                        InconsistencyKind::ValueMismatch,
+                        leaf_path.clone(),
+                    )),
+                    Some(_) => Ok(()),
+                };
+
+                // The value of the path is the value of the leaf, unless the path
+                // has been updated since `root`, in which case only a versioned tree
+                // still has the value, by its hash.
+                if is_latest || !self.is_versioned() {
+                    check_value(self.values.get(&leaf_path).map_err(TreeError::Store)?)?;
+                }
+                if self.is_versioned() {
+                    check_value(
+                        self.values
+                            .get(&value_key(value_hash))
+                            .map_err(TreeError::Store)?,
+                    )?;
+                }
+                continue;
+            }
+
+            if depth >= self.depth() {
+                return Err(inconsistency(InconsistencyKind::CorruptNode, path).into());
+            }
+            let (left, right) = Self::parse_node(&hash, &Some(data))?;
+            let mut right_path = path.to_vec();
+            set_bit_at_from_msb(&mut right_path, depth);
+            // Visit the left subtree first, so inconsistencies are found in path order.
+            stack.push((right, depth + 1, right_path.into()));
+            stack.push((left, depth + 1, path));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+use super::{SparseMerkleTree, SparseMerkleTreeView};
+use crate::{tree_hasher::TreeHasher, KVStore, StoreHasher, TreeError, TreeHashScheme};
+use alloc::{vec, vec::Vec};
+use bytes::Bytes;
+
+/// A leaf of a sparse merkle tree, yielded by `Leaves`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Leaf {
+    path: Bytes,
+    value_hash: Bytes,
+    value: Option<Bytes>,
+}
+
+impl Leaf {
+    /// Returns the path of the leaf, that is the hash of its key.
+    #[inline]
+    pub fn path(&self) -> &Bytes {
+        &self.path
+    }
+
+    /// Returns the hash of the value of the leaf.
+    #[inline]
+    pub fn value_hash(&self) -> &Bytes {
+        &self.value_hash
+    }
+
+    /// Returns the value of the leaf, if the iterator was asked to read values (see
+    /// `Leaves::with_values`).
+    #[inline]
+    pub fn value(&self) -> Option<&Bytes> {
+        self.value.as_ref()
+    }
+
+    /// Consumes the leaf, returning its path, value hash and value.
+    #[inline]
+    pub fn into_parts(self) -> (Bytes, Bytes, Option<Bytes>) {
+        (self.path, self.value_hash, self.value)
+    }
+}
+
+/// An iterator over the leaves of the tree rooted at a given hash, in path order.
+///
+/// Created by `SparseMerkleTree::iter_leaves` or `SparseMerkleTreeView::iter_leaves`.
+pub struct Leaves<'a, S: KVStore, H: TreeHashScheme = <S as StoreHasher>::Hasher> {
+    view: SparseMerkleTreeView<'a, S, H>,
+    stack: Vec<Bytes>,
+    with_values: bool,
+}
+
+impl<'a, S: KVStore, H: TreeHashScheme> Leaves<'a, S, H> {
+    pub(super) fn new(view: SparseMerkleTreeView<'a, S, H>) -> Self {
+        let stack = if view.root_ref().eq(view.th.placeholder_ref()) {
+            Vec::new()
+        } else {
+            vec![view.root()]
+        };
+        Self {
+            view,
+            stack,
+            with_values: false,
+        }
+    }
+
+    /// Also reads the value of every leaf from the values store.
+    #[inline]
+    pub fn with_values(mut self) -> Self {
+        self.with_values = true;
+        self
+    }
+
+    fn next_leaf(&mut self) -> Result<Option<Leaf>, TreeError<S::Error>> {
+        while let Some(hash) = self.stack.pop() {
+            // Only the root can have the wrong length, as it is not read from a node.
+            SparseMerkleTree::<S, H>::check_root(&hash)?;
+            let data = self.view.get_node(&hash)?;
+            if TreeHasher::<H>::is_leaf(&Some(&data)) {
+                let (path, value_hash) = SparseMerkleTree::<S, H>::parse_leaf(&hash, &data)?;
+                let (path, value_hash) = (
+                    Bytes::copy_from_slice(path),
+                    Bytes::copy_from_slice(value_hash),
+                );
+                let value = if self.with_values {
+                    super::values::read_leaf_value(
+                        &self.view.th,
+                        self.view.values,
+                        &path,
+                        &value_hash,
+                    )?
+                } else {
+                    None
+                };
+                return Ok(Some(Leaf {
+                    path,
+                    value_hash,
+                    value,
+                }));
+            }
+
+            // Visit the left child first, so that leaves are yielded in path order.
+            let (left, right) = SparseMerkleTree::<S, H>::parse_node(&hash, &Some(data))?;
+            for child in [right, left] {
+                if child.ne(self.view.th.placeholder_ref()) {
+                    self.stack.push(child);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, S: KVStore, H: TreeHashScheme> Iterator for Leaves<'a, S, H> {
+    type Item = Result<Leaf, TreeError<S::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_leaf() {
+            Ok(leaf) => leaf.map(Ok),
+            Err(e) => {
+                // Stop after the first error.
+                self.stack.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<S: KVStore, H: TreeHashScheme> SparseMerkleTree<S, H> {
+    /// Returns an iterator over the leaves of the tree rooted at `root`, walking the
+    /// nodes store depth-first. Leaves are yielded in path order, as their path and
+    /// value hash; call `Leaves::with_values` to also read their values.
+    ///
+    /// The iterator yields an error and stops if a node is missing from the nodes
+    /// store or is corrupt.
+    pub fn iter_leaves(&self, root: Bytes) -> Leaves<'_, S, H> {
+        self.snapshot_at(root).iter_leaves()
+    }
+}
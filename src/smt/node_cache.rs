@@ -0,0 +1,258 @@
+//! A bounded LRU cache of node data keyed by hash, so the upper levels of the tree,
+//! which every descent reads, are not read from the nodes store each time.
+use super::SparseMerkleTree;
+use crate::{KVStore, TreeError, TreeHashScheme};
+use alloc::collections::BTreeMap;
+use bytes::Bytes;
+
+/// The counters of the node cache of a `SparseMerkleTree`, returned by
+/// `SparseMerkleTree::node_cache_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NodeCacheStats {
+    /// The number of node reads served by the cache.
+    pub hits: u64,
+    /// The number of node reads which went to the nodes store.
+    pub misses: u64,
+    /// The number of nodes in the cache.
+    pub len: usize,
+    /// The maximum number of nodes in the cache.
+    pub capacity: usize,
+}
+
+/// The node cache of a tree, shared by its views.
+///
+/// Nodes are content-addressed, so the data cached for a hash never goes stale. A
+/// node is only evicted when the tree removes it from the nodes store, or when the
+/// least recently used node makes room for a new one.
+pub(super) struct NodeCache {
+    lru: Lock<Lru>,
+}
+
+impl NodeCache {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self {
+            lru: Lock::new(Lru::new(capacity)),
+        }
+    }
+
+    /// Returns the data of a node if it is cached, counting a hit or a miss.
+    pub(super) fn get(&self, hash: &[u8]) -> Option<Bytes> {
+        self.lru.with(|lru| lru.get(hash))
+    }
+
+    /// Caches the data of a node read from the nodes store.
+    pub(super) fn insert(&self, hash: &[u8], data: Bytes) {
+        self.lru.with(|lru| lru.insert(hash, data))
+    }
+
+    /// Evicts a node removed from the nodes store.
+    pub(super) fn evict(&self, hash: &[u8]) {
+        self.lru.with(|lru| lru.evict(hash))
+    }
+
+    pub(super) fn clear(&self) {
+        self.lru.with(|lru| *lru = Lru::new(lru.capacity))
+    }
+
+    pub(super) fn stats(&self) -> NodeCacheStats {
+        self.lru.with(|lru| NodeCacheStats {
+            hits: lru.hits,
+            misses: lru.misses,
+            len: lru.entries.len(),
+            capacity: lru.capacity,
+        })
+    }
+}
+
+impl Clone for NodeCache {
+    fn clone(&self) -> Self {
+        Self {
+            lru: Lock::new(self.lru.with(|lru| lru.clone())),
+        }
+    }
+}
+
+impl core::fmt::Debug for NodeCache {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("NodeCache").field(&self.stats()).finish()
+    }
+}
+
+/// Reads a node from the nodes store through the cache, if enabled.
+pub(super) fn read_through<S: KVStore>(
+    cache: Option<&NodeCache>,
+    nodes: &S,
+    hash: &[u8],
+) -> Result<Option<Bytes>, TreeError<S::Error>> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return nodes.get(hash).map_err(TreeError::Store),
+    };
+    if let Some(data) = cache.get(hash) {
+        return Ok(Some(data));
+    }
+    let data = nodes.get(hash).map_err(TreeError::Store)?;
+    if let Some(data) = &data {
+        cache.insert(hash, data.clone());
+    }
+    Ok(data)
+}
+
+#[derive(Clone)]
+struct Lru {
+    capacity: usize,
+    /// Incremented on each use, so the smallest tick is the least recently used.
+    tick: u64,
+    /// The data of each cached node with the tick of its last use.
+    entries: BTreeMap<Bytes, (Bytes, u64)>,
+    /// The cached nodes by the tick of their last use.
+    order: BTreeMap<u64, Bytes>,
+    hits: u64,
+    misses: u64,
+}
+
+impl Lru {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: BTreeMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, hash: &[u8]) -> Option<Bytes> {
+        match self.entries.get_mut(hash) {
+            Some((data, tick)) => {
+                self.hits += 1;
+                self.tick += 1;
+                let hash = self.order.remove(tick).expect("cached node has a tick");
+                self.order.insert(self.tick, hash);
+                *tick = self.tick;
+                Some(data.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, hash: &[u8], data: Bytes) {
+        if self.capacity == 0 || self.entries.contains_key(hash) {
+            return;
+        }
+
+        if self.entries.len() == self.capacity {
+            if let Some(oldest) = self.order.keys().next().copied() {
+                let hash = self.order.remove(&oldest).expect("tick was just found");
+                self.entries.remove(&hash);
+            }
+        }
+
+        self.tick += 1;
+        let hash = Bytes::copy_from_slice(hash);
+        self.order.insert(self.tick, hash.clone());
+        self.entries.insert(hash, (data, self.tick));
+    }
+
+    fn evict(&mut self, hash: &[u8]) {
+        if let Some((_, tick)) = self.entries.remove(hash) {
+            self.order.remove(&tick);
+        }
+    }
+}
+
+/// A mutex, so that views of a tree can be shared with other threads.
+#[cfg(feature = "std")]
+struct Lock<T>(std::sync::Mutex<T>);
+
+#[cfg(feature = "std")]
+impl<T> Lock<T> {
+    fn new(value: T) -> Self {
+        Self(std::sync::Mutex::new(value))
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        // The cache is left consistent between operations, so a poisoned lock is
+        // still usable.
+        let mut guard = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        f(&mut guard)
+    }
+}
+
+/// A spin lock, as `no_std` has no mutex. It is only held for a few map operations.
+#[cfg(not(feature = "std"))]
+struct Lock<T> {
+    locked: core::sync::atomic::AtomicBool,
+    value: core::cell::UnsafeCell<T>,
+}
+
+// SAFETY: the value is only accessed by the holder of the lock.
+#[cfg(not(feature = "std"))]
+unsafe impl<T: Send> Sync for Lock<T> {}
+
+#[cfg(not(feature = "std"))]
+impl<T> Lock<T> {
+    fn new(value: T) -> Self {
+        Self {
+            locked: core::sync::atomic::AtomicBool::new(false),
+            value: core::cell::UnsafeCell::new(value),
+        }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        use core::sync::atomic::Ordering;
+
+        struct Unlock<'a>(&'a core::sync::atomic::AtomicBool);
+
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+        let _unlock = Unlock(&self.locked);
+        // SAFETY: the lock is held until `_unlock` is dropped.
+        f(unsafe { &mut *self.value.get() })
+    }
+}
+
+impl<S: KVStore, H: TreeHashScheme> SparseMerkleTree<S, H> {
+    /// Enables a bounded LRU cache of node data keyed by hash, holding up to
+    /// `capacity` nodes.
+    ///
+    /// Every `get_descend` and proof reads the nodes at the top of the tree, so
+    /// caching them saves a read of the nodes store per level for disk-backed stores.
+    /// The cache is shared with the views returned by `snapshot`. Nodes removed from
+    /// the nodes store other than by the tree must be evicted with
+    /// `clear_node_cache`.
+    #[inline]
+    pub fn with_node_cache(mut self, capacity: usize) -> Self {
+        self.node_cache = Some(NodeCache::new(capacity));
+        self
+    }
+
+    /// Returns the counters of the node cache, or `None` if it is not enabled.
+    #[inline]
+    pub fn node_cache_stats(&self) -> Option<NodeCacheStats> {
+        self.node_cache.as_ref().map(NodeCache::stats)
+    }
+
+    /// Empties the node cache and resets its counters.
+    #[inline]
+    pub fn clear_node_cache(&self) {
+        if let Some(cache) = &self.node_cache {
+            cache.clear();
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Buffers the writes of a tree operation, so that each store receives them as a
+//! single `WriteBatch` once the operation has succeeded.
+use super::{node_cache, SparseMerkleTree};
+use crate::{KVStore, TreeError, TreeHashScheme, WriteBatch};
+use bytes::Bytes;
+
+impl<S: KVStore, H: TreeHashScheme> SparseMerkleTree<S, H> {
+    /// Runs a tree operation, buffering its writes, then writes them to the stores
+    /// if it succeeds. Nothing is written if the operation fails, and the changes it
+    /// made to the versions of a versioned tree are undone if either the operation
+    /// or writing to the stores fails.
+    ///
+    /// The records of the versions changed by the operation are written in the same
+    /// batch as its nodes.
+    ///
+    /// `begin_batch` is called on both stores, then `write_batch`, then `commit`, so
+    /// that stores sharing a database can commit both batches in one transaction.
+    /// If any of them fails, `abort_batch` is called on both stores.
+    /// The nodes store is committed before the values store, so atomicity across
+    /// the two stores is up to them: stores which do not share a transaction leave
+    /// the nodes of the batch in the nodes store if the values store fails to
+    /// commit. Such nodes are unreachable from the root of the tree.
+    pub(super) fn batched<T>(
+        &mut self,
+        op: impl FnOnce(&mut Self) -> Result<T, TreeError<S::Error>>,
+    ) -> Result<T, TreeError<S::Error>> {
+        if let Some(versions) = &mut self.versions {
+            versions.begin();
+        }
+        let result = self.write_batched(op);
+        if let Some(versions) = &mut self.versions {
+            match result {
+                Ok(_) => versions.end(),
+                Err(_) => versions.rollback(),
+            }
+        }
+        result
+    }
+
+    fn write_batched<T>(
+        &mut self,
+        op: impl FnOnce(&mut Self) -> Result<T, TreeError<S::Error>>,
+    ) -> Result<T, TreeError<S::Error>> {
+        let result = op(self).and_then(|result| self.write_versions().map(|_| result));
+        let nodes = core::mem::take(&mut self.pending_nodes);
+        let values = core::mem::take(&mut self.pending_values);
+        let result = result?;
+        if nodes.is_empty() && values.is_empty() {
+            return Ok(result);
+        }
+
+        if let Some(cache) = &self.node_cache {
+            for hash in nodes.removed() {
+                cache.evict(hash);
+            }
+        }
+
+        if let Err(e) = self.write_stores(nodes, values) {
+            let _ = self.nodes.abort_batch();
+            let _ = self.values.abort_batch();
+            return Err(TreeError::Store(e));
+        }
+        Ok(result)
+    }
+
+    fn write_stores(&mut self, nodes: WriteBatch, values: WriteBatch) -> Result<(), S::Error> {
+        self.nodes.begin_batch()?;
+        self.values.begin_batch()?;
+        self.nodes.write_batch(nodes)?;
+        self.values.write_batch(values)?;
+        self.nodes.commit()?;
+        self.values.commit()
+    }
+
+    /// Reads a node, seeing the writes of the operation in progress.
+    pub(super) fn read_node(&self, hash: &[u8]) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        match self.pending_nodes.get(hash) {
+            Some(data) => Ok(data.cloned()),
+            None => node_cache::read_through(self.node_cache.as_ref(), &self.nodes, hash),
+        }
+    }
+
+    /// Returns true if a node exists, seeing the writes of the operation in progress.
+    pub(super) fn has_node(&self, hash: &[u8]) -> Result<bool, TreeError<S::Error>> {
+        match self.pending_nodes.get(hash) {
+            Some(data) => Ok(data.is_some()),
+            None => self.nodes.contains(hash).map_err(TreeError::Store),
+        }
+    }
+
+    /// Writes a node as part of the operation in progress.
+    #[inline]
+    pub(super) fn write_node(&mut self, hash: Bytes, data: Bytes) {
+        self.pending_nodes.set(hash, data);
+    }
+
+    /// Removes a node as part of the operation in progress, returning its data, or
+    /// `None` if it does not exist.
+    pub(super) fn take_node(&mut self, hash: &Bytes) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        take(&mut self.pending_nodes, &self.nodes, hash)
+    }
+
+    /// Reads a value, seeing the writes of the operation in progress.
+    pub(super) fn read_value(&self, key: &[u8]) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        read(&self.pending_values, &self.values, key)
+    }
+
+    /// Writes a value as part of the operation in progress.
+    #[inline]
+    pub(super) fn write_value(&mut self, key: Bytes, value: Bytes) {
+        self.pending_values.set(key, value);
+    }
+
+    /// Removes a value, if it exists, as part of the operation in progress.
+    pub(super) fn remove_value(&mut self, key: &Bytes) -> Result<(), TreeError<S::Error>> {
+        take(&mut self.pending_values, &self.values, key).map(|_| ())
+    }
+}
+
+fn read<S: KVStore>(
+    pending: &WriteBatch,
+    store: &S,
+    key: &[u8],
+) -> Result<Option<Bytes>, TreeError<S::Error>> {
+    match pending.get(key) {
+        Some(value) => Ok(value.cloned()),
+        None => store.get(key).map_err(TreeError::Store),
+    }
+}
+
+fn take<S: KVStore>(
+    pending: &mut WriteBatch,
+    store: &S,
+    key: &Bytes,
+) -> Result<Option<Bytes>, TreeError<S::Error>> {
+    let value = read(pending, store, key)?;
+    if value.is_some() {
+        if store.contains(key).map_err(TreeError::Store)? {
+            pending.remove(key.clone());
+        } else {
+            // The key was only written by the operation in progress.
+            pending.discard(key);
+        }
+    }
+    Ok(value)
+}
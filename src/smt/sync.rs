@@ -0,0 +1,156 @@
+use super::SparseMerkleTree;
+use crate::{
+    tree_hasher::TreeHasher, KVStore, SparseMerkleRangeProof, StoreHasher, TreeError,
+    TreeHashScheme,
+};
+use alloc::{vec, vec::Vec};
+use bytes::Bytes;
+
+/// Rebuilds a sparse merkle tree with a known root from chunks of leaves received
+/// from a peer.
+///
+/// Each chunk is the list of `(path, value)` pairs of the leaves whose paths lie in a
+/// contiguous range, together with a `SparseMerkleRangeProof` for that range (see
+/// `SparseMerkleTree::prove_range`). Chunks must cover the whole path space in
+/// order: the first one starts at the all-zeros path, and each following one starts
+/// right after the end of the previous one. Every chunk is verified against the
+/// target root before any of its nodes is written.
+pub struct StateSyncer<S: KVStore, H: TreeHashScheme = <S as StoreHasher>::Hasher> {
+    tree: SparseMerkleTree<S, H>,
+    next_path: Option<Bytes>,
+}
+
+impl<S: KVStore, H: TreeHashScheme> StateSyncer<S, H> {
+    /// Creates a new StateSyncer, writing the nodes and values of the tree with the
+    /// given root into the given stores, which should be empty.
+    pub fn new(nodes_store: S, values_store: S, root: impl Into<Bytes>) -> Self
+    where
+        H: Default,
+    {
+        Self::new_with_scheme(nodes_store, values_store, root, H::default())
+    }
+
+    /// Creates a new StateSyncer for a tree hashed with the given scheme instance.
+    pub fn new_with_scheme(
+        nodes_store: S,
+        values_store: S,
+        root: impl Into<Bytes>,
+        scheme: H,
+    ) -> Self {
+        let tree = SparseMerkleTree::import_with_scheme(nodes_store, values_store, root, scheme);
+        let next_path = if tree.root_ref() == tree.th.placeholder().as_ref() {
+            // The empty tree has no nodes to sync.
+            None
+        } else {
+            Some(vec![0; TreeHasher::<H>::path_size()].into())
+        };
+        Self { tree, next_path }
+    }
+
+    /// Syncs the tree with the given identifier (see `SparseMerkleTree::with_tree_id`).
+    #[inline]
+    pub fn with_tree_id(mut self, tree_id: impl AsRef<[u8]>) -> Self {
+        self.tree = self.tree.with_tree_id(tree_id);
+        self
+    }
+
+    /// Returns the root of the tree being synced.
+    #[inline]
+    pub fn target_root(&self) -> Bytes {
+        self.tree.root()
+    }
+
+    /// Returns the path the next chunk must start at, or `None` if the sync is
+    /// complete.
+    #[inline]
+    pub fn next_start_path(&self) -> Option<&Bytes> {
+        self.next_path.as_ref()
+    }
+
+    /// Returns true once chunks covering the whole path space have been added.
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.next_path.is_none()
+    }
+
+    /// Verifies a chunk of leaves against the target root, and writes the nodes and
+    /// values it proves into the stores.
+    ///
+    /// Returns `TreeError::UnexpectedChunk` if the sync is already complete or if the
+    /// proof does not start at `next_start_path`, and `TreeError::BadProof` if the
+    /// leaves do not verify against the target root. Nothing is written in that case.
+    pub fn add_chunk<P, V>(
+        &mut self,
+        proof: &SparseMerkleRangeProof<H>,
+        leaves: impl IntoIterator<Item = (P, V)>,
+    ) -> Result<(), TreeError<S::Error>>
+    where
+        P: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        match &self.next_path {
+            Some(next_path) if next_path.eq(proof.start_path()) => {}
+            expected => {
+                return Err(TreeError::UnexpectedChunk {
+                    expected: expected.clone(),
+                })
+            }
+        }
+
+        let leaves = leaves
+            .into_iter()
+            .map(|(path, value)| {
+                (
+                    Bytes::copy_from_slice(path.as_ref()),
+                    Bytes::copy_from_slice(value.as_ref()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let (valid, updates) = proof.verify_with_updates(
+            &self.tree.th,
+            self.tree.root_ref(),
+            leaves.iter().map(|(path, value)| (path, value)),
+        );
+        if !valid {
+            return Err(TreeError::BadProof);
+        }
+
+        self.tree.batched(|tree| {
+            for (hash, data) in updates {
+                tree.put_node(hash, data)?;
+            }
+            for (path, value) in leaves {
+                let value_hash = tree.th.digest(&value);
+                tree.put_value(path, &value_hash, value);
+            }
+            Ok(())
+        })?;
+
+        self.next_path = next_path(proof.end_path());
+        Ok(())
+    }
+
+    /// Consumes the StateSyncer, returning the synced tree.
+    ///
+    /// Returns `TreeError::IncompleteSync` if the sync is not complete.
+    pub fn finish(self) -> Result<SparseMerkleTree<S, H>, TreeError<S::Error>> {
+        match self.next_path {
+            Some(next_path) => Err(TreeError::IncompleteSync { next_path }),
+            None => Ok(self.tree),
+        }
+    }
+}
+
+/// Returns the path following `path`, or `None` if `path` is the last path.
+fn next_path(path: &[u8]) -> Option<Bytes> {
+    let mut next = path.to_vec();
+    for byte in next.iter_mut().rev() {
+        if *byte == u8::MAX {
+            *byte = 0;
+        } else {
+            *byte += 1;
+            return Some(next.into());
+        }
+    }
+    None
+}
@@ -1,37 +1,21 @@
 use digest::generic_array::GenericArray;
 use hashbrown::HashMap;
 
-use crate::proofs::BadProof;
-
 use super::*;
+use crate::{count_common_prefix, BatchOp, CachedStore};
 
-#[derive(Debug)]
-pub enum Error {
-    NotFound,
-    BadProof(BadProof),
-}
-
-impl From<BadProof> for Error {
-    fn from(e: BadProof) -> Self {
-        Error::BadProof(e)
-    }
-}
-
-impl core::fmt::Display for Error {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        write!(f, "Error")
-    }
-}
+/// Error returned by the test stores.
+pub type Error = crate::StoreError;
 
-#[cfg(feature = "std")]
-impl std::error::Error for Error {}
+/// A `HashMap` backed store for tests.
+pub type SimpleStore = crate::MemoryStore<sha2::Sha256>;
 
 #[derive(Debug, Clone, Default)]
-pub struct SimpleStore {
+pub struct DummyStore {
     data: HashMap<Bytes, Bytes>,
 }
 
-impl SimpleStore {
+impl DummyStore {
     pub fn new() -> Self {
         Self {
             data: HashMap::new(),
@@ -39,12 +23,15 @@ impl SimpleStore {
     }
 }
 
-impl KVStore for SimpleStore {
+impl crate::StoreHasher for DummyStore {
+    type Hasher = DummyHasher<sha2::Sha256>;
+}
+
+impl KVStore for DummyStore {
     type Error = Error;
-    type Hasher = sha2::Sha256;
 
     fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error> {
-        Ok(self.data.get(key).map(core::clone::Clone::clone))
+        Ok(self.data.get(key).cloned())
     }
 
     fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error> {
@@ -61,46 +48,147 @@ impl KVStore for SimpleStore {
     }
 }
 
+/// A handle to a `SimpleStore` shared by all its clones, so that a tree writing
+/// through one handle and views reading through others see the same data.
 #[derive(Debug, Clone, Default)]
-pub struct DummyStore {
-    data: HashMap<Bytes, Bytes>,
+pub struct SharedStore(std::sync::Arc<std::sync::RwLock<SimpleStore>>);
+
+impl crate::StoreHasher for SharedStore {
+    type Hasher = sha2::Sha256;
 }
 
-impl DummyStore {
-    pub fn new() -> Self {
-        Self {
-            data: HashMap::new(),
-        }
+impl KVStore for SharedStore {
+    type Error = Error;
+
+    fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error> {
+        self.0.read().unwrap().get(key)
+    }
+
+    fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error> {
+        self.0.write().unwrap().set(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error> {
+        self.0.write().unwrap().remove(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        self.0.read().unwrap().contains(key)
     }
 }
 
-impl KVStore for DummyStore {
+/// A `SimpleStore` recording the batches written to it, which can be made to fail
+/// writing them.
+#[derive(Debug, Clone, Default)]
+pub struct BatchStore {
+    store: SimpleStore,
+    batches: Vec<WriteBatch>,
+    in_batch: bool,
+    fail: bool,
+}
+
+impl crate::StoreHasher for BatchStore {
+    type Hasher = sha2::Sha256;
+}
+
+impl KVStore for BatchStore {
     type Error = Error;
-    type Hasher = DummyHasher<sha2::Sha256>;
 
     fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error> {
-        Ok(self.data.get(key).map(core::clone::Clone::clone))
+        self.store.get(key)
     }
 
     fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error> {
-        self.data.insert(key, value);
-        Ok(())
+        assert!(self.in_batch, "writes must go through a batch");
+        self.store.set(key, value)
     }
 
     fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error> {
-        self.data.remove(key).ok_or(Error::NotFound)
+        assert!(self.in_batch, "writes must go through a batch");
+        self.store.remove(key)
     }
 
     fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
-        Ok(self.data.contains_key(key))
+        self.store.contains(key)
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Self::Error> {
+        self.in_batch = true;
+        Ok(())
+    }
+
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Self::Error> {
+        if self.fail {
+            return Err(Error::NotFound);
+        }
+        self.batches.push(batch.clone());
+        for op in batch {
+            match op {
+                BatchOp::Set(key, value) => self.set(key, value)?,
+                BatchOp::Remove(key) => {
+                    self.remove(&key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Self::Error> {
+        self.in_batch = false;
+        Ok(())
+    }
+
+    fn abort_batch(&mut self) -> Result<(), Self::Error> {
+        self.in_batch = false;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+impl crate::AsyncKVStore for BatchStore {
+    type Error = Error;
+
+    async fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error> {
+        KVStore::get(self, key)
+    }
+
+    async fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error> {
+        KVStore::set(self, key, value)
+    }
+
+    async fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error> {
+        KVStore::remove(self, key)
+    }
+
+    async fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+        KVStore::contains(self, key)
+    }
+
+    async fn begin_batch(&mut self) -> Result<(), Self::Error> {
+        KVStore::begin_batch(self)
+    }
+
+    async fn write_batch(&mut self, batch: WriteBatch) -> Result<(), Self::Error> {
+        KVStore::write_batch(self, batch)
+    }
+
+    async fn commit(&mut self) -> Result<(), Self::Error> {
+        KVStore::commit(self)
+    }
+
+    async fn abort_batch(&mut self) -> Result<(), Self::Error> {
+        KVStore::abort_batch(self)
     }
 }
 
+/// Creates an empty tree backed by `SimpleStore`s.
 pub fn new_sparse_merkle_tree() -> SparseMerkleTree<SimpleStore> {
     let (smn, smv) = (SimpleStore::new(), SimpleStore::new());
     SparseMerkleTree::<SimpleStore>::new_with_stores(smn, smv)
 }
 
+#[derive(Clone)]
 pub struct DummyHasher<D: digest::Digest + digest::OutputSizeUser> {
     base_hasher: D,
     data: Vec<u8>,
@@ -115,6 +203,12 @@ impl<D: digest::Digest> DummyHasher<D> {
     }
 }
 
+impl<D: digest::Digest> Default for DummyHasher<D> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<D: digest::Digest + digest::OutputSizeUser> digest::OutputSizeUser for DummyHasher<D> {
     type OutputSize = D::OutputSize;
 }
@@ -433,7 +527,11 @@ fn test_deep_sparse_merkle_sub_tree_basic() {
     let val = dsmst.get_descend(b"testKey5").unwrap();
     assert!(val.is_none());
 
-    assert!(dsmst.get_descend(b"testKey6").unwrap().is_none());
+    // The branch of testKey6 was not added, so it cannot be descended.
+    assert!(matches!(
+        dsmst.get_descend(b"testKey6"),
+        Err(TreeError::MissingNode { .. })
+    ));
 
     dsmst
         .update(b"testKey1", Bytes::from("testValue3"))
@@ -483,6 +581,1964 @@ fn test_deep_sparse_merkle_sub_tree_bad_input() {
 #[test]
 fn test_orphan_removal() {}
 
+#[test]
+fn test_smt_update_batch_basic() {
+    let mut smt = new_sparse_merkle_tree();
+    let mut expected = new_sparse_merkle_tree();
+
+    // Batch insert into an empty tree.
+    let root = smt
+        .update_batch([
+            (b"testKey1".as_ref(), Bytes::from("testValue1")),
+            (b"testKey2", Bytes::from("testValue2")),
+            (b"foo", Bytes::from("testValue3")),
+        ])
+        .unwrap();
+    expected
+        .update(b"testKey1", Bytes::from("testValue1"))
+        .unwrap();
+    expected
+        .update(b"testKey2", Bytes::from("testValue2"))
+        .unwrap();
+    expected.update(b"foo", Bytes::from("testValue3")).unwrap();
+    assert_eq!(root, smt.root());
+    assert_eq!(smt.root(), expected.root());
+    assert_eq!(smt.get(b"foo").unwrap(), Some(Bytes::from("testValue3")));
+
+    // Updates, deletions, deletions of absent keys and repeated keys in one batch.
+    smt.update_batch([
+        (b"testKey1".as_ref(), Bytes::from("ignored")),
+        (b"testKey2", DEFAULT_VALUE),
+        (b"testKey1", Bytes::from("testValue4")),
+        (b"absent", DEFAULT_VALUE),
+    ])
+    .unwrap();
+    expected
+        .update(b"testKey1", Bytes::from("testValue4"))
+        .unwrap();
+    expected.remove(b"testKey2").unwrap();
+    assert_eq!(smt.root(), expected.root());
+    assert!(smt.get(b"testKey2").unwrap().is_none());
+    assert_eq!(
+        smt.get_descend(b"testKey1").unwrap(),
+        Some(Bytes::from("testValue4"))
+    );
+
+    // Remove everything.
+    smt.update_batch([
+        (b"testKey1".as_ref(), DEFAULT_VALUE),
+        (b"foo", DEFAULT_VALUE),
+    ])
+    .unwrap();
+    assert_eq!(smt.root(), smt.th.placeholder());
+    assert!(smt.nodes.data.is_empty());
+    assert!(smt.values.data.is_empty());
+}
+
+#[test]
+fn test_smt_update_batch_matches_update() {
+    use rand::{Rng, RngCore};
+
+    let mut rng = rand::thread_rng();
+    let mut smt = new_sparse_merkle_tree();
+    let mut expected = new_sparse_merkle_tree();
+    let mut keys: Vec<Bytes> = Vec::new();
+
+    for _ in 0..20 {
+        let mut batch = Vec::new();
+        for _ in 0..rng.gen_range(1..50) {
+            let key = if !keys.is_empty() && rng.gen_bool(0.5) {
+                keys[rng.gen_range(0..keys.len())].clone()
+            } else {
+                let mut key = vec![0; 16];
+                rng.fill_bytes(&mut key);
+                let key = Bytes::from(key);
+                keys.push(key.clone());
+                key
+            };
+
+            let value = if rng.gen_bool(0.3) {
+                DEFAULT_VALUE
+            } else {
+                let mut value = vec![0; 1 + rng.gen_range(0..32)];
+                rng.fill_bytes(&mut value);
+                Bytes::from(value)
+            };
+            batch.push((key, value));
+        }
+
+        for (key, value) in &batch {
+            expected.update(key, value.clone()).unwrap();
+        }
+        let root = smt.update_batch(batch).unwrap();
+        assert_eq!(root, expected.root());
+
+        for key in &keys {
+            assert_eq!(smt.get(key).unwrap(), expected.get(key).unwrap());
+            assert_eq!(smt.get_descend(key).unwrap(), expected.get(key).unwrap());
+        }
+        // Orphaned nodes are removed just like with sequential updates.
+        assert_eq!(smt.nodes.data.len(), expected.nodes.data.len());
+    }
+}
+
+#[test]
+fn test_smt_get_for_root() {
+    let mut smt = new_sparse_merkle_tree();
+    smt.update(b"testKey1", Bytes::from("testValue")).unwrap();
+    smt.update(b"testKey2", Bytes::from("testValue")).unwrap();
+    smt.update(b"testKey3", Bytes::from("testValue3")).unwrap();
+    for key in [b"testKey1", b"testKey2", b"testKey3", b"testKey4"] {
+        assert_eq!(
+            smt.get_for_root(key, smt.root()).unwrap(),
+            smt.get(key).unwrap()
+        );
+    }
+
+    // Trees that are not versioned keep the store layout of 0.1.0, with only the
+    // latest value of each path, which is read back by descending the tree.
+    assert!(smt.values.data.keys().all(|key| key.len() == 32));
+    let imported =
+        SparseMerkleTree::<SimpleStore>::import(smt.nodes.clone(), smt.values.clone(), smt.root());
+    assert_eq!(
+        imported.get_for_root(b"testKey3", smt.root()).unwrap(),
+        Some(Bytes::from("testValue3"))
+    );
+    let leaves = imported
+        .iter_leaves(smt.root())
+        .with_values()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert!(leaves.iter().all(|leaf| leaf.value().is_some()));
+
+    // A value shared by several keys is kept until the last of them is updated.
+    smt.remove(b"testKey1").unwrap();
+    assert_eq!(smt.get_for_root(b"testKey1", smt.root()).unwrap(), None);
+    assert_eq!(
+        smt.get_for_root(b"testKey2", smt.root()).unwrap(),
+        Some(Bytes::from("testValue"))
+    );
+    smt.update(b"testKey2", Bytes::from("testValue2")).unwrap();
+    assert_eq!(
+        smt.get_for_root(b"testKey2", smt.root()).unwrap(),
+        Some(Bytes::from("testValue2"))
+    );
+    assert!(!smt
+        .values
+        .data
+        .values()
+        .any(|value| value.eq(&Bytes::from("testValue"))));
+
+    // The value committed under an old root is returned as long as its nodes exist.
+    let mut versioned = SparseMerkleTree::<SimpleStore>::new_versioned();
+    versioned
+        .update(b"testKey1", Bytes::from("testValue1"))
+        .unwrap();
+    let old_root = versioned.root();
+    versioned
+        .update(b"testKey1", Bytes::from("testValue2"))
+        .unwrap();
+    assert_eq!(
+        versioned
+            .get_for_root(b"testKey1", old_root.clone())
+            .unwrap(),
+        Some(Bytes::from("testValue1"))
+    );
+    assert_eq!(
+        versioned.get_for_root(b"testKey1", old_root).unwrap(),
+        Some(Bytes::from("testValue1"))
+    );
+    assert_eq!(
+        versioned.get(b"testKey1").unwrap(),
+        Some(Bytes::from("testValue2"))
+    );
+}
+
+#[test]
+fn test_smt_iter_leaves() {
+    use rand::RngCore;
+
+    let mut smt = new_sparse_merkle_tree();
+    assert!(smt.iter_leaves(smt.root()).next().is_none());
+
+    let mut rng = rand::thread_rng();
+    let mut expected = Vec::new();
+    for _ in 0..100 {
+        let mut key = vec![0; 16];
+        rng.fill_bytes(&mut key);
+        let mut value = vec![0; 8];
+        rng.fill_bytes(&mut value);
+        smt.update(&key, Bytes::from(value.clone())).unwrap();
+        expected.push((
+            smt.th.path_into(&key),
+            smt.th.path_into(&value),
+            Bytes::from(value),
+        ));
+    }
+    expected.sort();
+
+    let leaves = smt
+        .iter_leaves(smt.root())
+        .map(|leaf| leaf.unwrap().into_parts())
+        .collect::<Vec<_>>();
+    assert_eq!(leaves.len(), expected.len());
+    for (leaf, (path, value_hash, _)) in leaves.iter().zip(&expected) {
+        assert_eq!(leaf, &(path.clone(), value_hash.clone(), None));
+    }
+
+    let leaves = smt
+        .iter_leaves(smt.root())
+        .with_values()
+        .map(|leaf| leaf.unwrap().into_parts())
+        .collect::<Vec<_>>();
+    for (leaf, (path, value_hash, value)) in leaves.iter().zip(&expected) {
+        assert_eq!(
+            leaf,
+            &(path.clone(), value_hash.clone(), Some(value.clone()))
+        );
+    }
+
+    // A subtree can be iterated from its root.
+    let data = smt.nodes.get(&smt.root()).unwrap().unwrap();
+    let (left, _) = TreeHasher::<sha2::Sha256>::parse_node(&data).unwrap();
+    let left_leaves = smt.iter_leaves(left).count();
+    assert_eq!(
+        left_leaves,
+        expected
+            .iter()
+            .filter(|(path, _, _)| get_bit_at_from_msb(path, 0) != RIGHT)
+            .count()
+    );
+
+    // A missing node is reported.
+    smt.nodes.data.remove(&smt.root());
+    let mut leaves = smt.iter_leaves(smt.root());
+    assert!(leaves.next().unwrap().is_err());
+    assert!(leaves.next().is_none());
+}
+
+#[test]
+fn test_versioned_smt_basic() {
+    let mut smt = SparseMerkleTree::<SimpleStore>::new_versioned();
+    assert!(smt.is_versioned());
+    assert_eq!(smt.version(), 0);
+    assert_eq!(smt.root_at_version(0), Some(smt.th.placeholder()));
+
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    smt.update(b"testKey2", Bytes::from("testValue2")).unwrap();
+    let root2 = smt.root();
+    smt.update(b"testKey1", Bytes::from("testValue3")).unwrap();
+    let root3 = smt.root();
+    smt.remove(b"testKey2").unwrap();
+    let root4 = smt.root();
+    assert_eq!(smt.version(), 4);
+    assert_eq!(smt.root_at_version(2), Some(root2.clone()));
+    assert_eq!(smt.root_at_version(4), Some(root4.clone()));
+    assert_eq!(smt.root_at_version(5), None);
+
+    // Historical roots remain readable.
+    assert_eq!(
+        smt.get_for_root(b"testKey1", root2.clone()).unwrap(),
+        Some(Bytes::from("testValue1"))
+    );
+    assert_eq!(
+        smt.get_for_root(b"testKey2", root2.clone()).unwrap(),
+        Some(Bytes::from("testValue2"))
+    );
+    assert_eq!(
+        smt.get_for_root(b"testKey1", root3.clone()).unwrap(),
+        Some(Bytes::from("testValue3"))
+    );
+    assert_eq!(smt.get_for_root(b"testKey2", root4.clone()).unwrap(), None);
+    assert_eq!(smt.get_for_root(b"testKey3", root2.clone()).unwrap(), None);
+    assert_eq!(smt.get(b"testKey2").unwrap(), None);
+
+    let proof = smt.prove_for_root(b"testKey2", root2.clone()).unwrap();
+    assert!(proof.verify(root2.clone(), b"testKey2", b"testValue2"));
+    let proof = smt.prove_for_root(b"testKey1", root2.clone()).unwrap();
+    assert!(proof.verify(root2.clone(), b"testKey1", b"testValue1"));
+
+    // The nodes orphaned by each version are recorded in the stale node index.
+    assert!(smt.stale_nodes_at_version(1).is_empty());
+    // The first leaf becomes a child of the new root.
+    assert!(smt.stale_nodes_at_version(2).is_empty());
+    // The first leaf and the root are replaced.
+    assert_eq!(smt.stale_nodes_at_version(3).len(), 2);
+    assert!(smt.stale_nodes_at_version(3).contains(&root2));
+    // The second leaf and the root are removed.
+    assert_eq!(smt.stale_nodes_at_version(4).len(), 2);
+    assert!(smt.stale_nodes_at_version(4).contains(&root3));
+
+    // Nodes which become reachable again are no longer stale.
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    let stale = smt.stale_nodes_at_version(3);
+    assert!(!stale.contains(&smt.root()));
+    let leaf = smt
+        .th
+        .digest_leaf(smt.th.path(b"testKey1"), smt.th.digest(b"testValue1"));
+    assert!(!stale.contains(&leaf.0));
+}
+
+#[test]
+fn test_versioned_smt_matches_unversioned() {
+    use rand::{Rng, RngCore};
+
+    let mut rng = rand::thread_rng();
+    let mut smt = SparseMerkleTree::<SimpleStore>::new_versioned();
+    let mut expected = new_sparse_merkle_tree();
+    let mut keys: Vec<Bytes> = Vec::new();
+    let mut snapshots = vec![(smt.root(), hashbrown::HashMap::new())];
+    let mut kv = hashbrown::HashMap::new();
+
+    for _ in 0..50 {
+        let mut batch = Vec::new();
+        for _ in 0..rng.gen_range(1..10) {
+            let key = if !keys.is_empty() && rng.gen_bool(0.5) {
+                keys[rng.gen_range(0..keys.len())].clone()
+            } else {
+                let mut key = vec![0; 16];
+                rng.fill_bytes(&mut key);
+                let key = Bytes::from(key);
+                keys.push(key.clone());
+                key
+            };
+
+            let value = if rng.gen_bool(0.3) {
+                DEFAULT_VALUE
+            } else {
+                // Draw from a few values, so that values and nodes are reused.
+                Bytes::from(vec![rng.gen_range(1..4u8); 8])
+            };
+            batch.push((key, value));
+        }
+
+        for (key, value) in &batch {
+            expected.update(key, value.clone()).unwrap();
+            if value.is_empty() {
+                kv.remove(key);
+            } else {
+                kv.insert(key.clone(), value.clone());
+            }
+        }
+        if rng.gen_bool(0.5) {
+            smt.update_batch(batch).unwrap();
+        } else {
+            for (key, value) in batch {
+                smt.update(&key, value).unwrap();
+            }
+        }
+        assert_eq!(smt.root(), expected.root());
+        snapshots.push((smt.root(), kv.clone()));
+
+        // The nodes store holds the live nodes plus the stale ones.
+        let versions = smt.versions.as_ref().unwrap();
+        assert!(versions.orphaned.is_empty());
+        assert_eq!(
+            smt.nodes.data.len(),
+            expected.nodes.data.len() + versions.stale_since.len()
+        );
+        for hash in versions.stale_since.keys() {
+            assert!(!expected.nodes.data.contains_key(hash));
+        }
+    }
+
+    for (root, kv) in snapshots {
+        for key in &keys {
+            assert_eq!(
+                smt.get_for_root(key, root.clone()).unwrap(),
+                kv.get(key).cloned()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_versioned_smt_prune() {
+    use rand::Rng;
+
+    let mut rng = rand::thread_rng();
+    let mut smt = SparseMerkleTree::<SimpleStore>::new_versioned();
+    let keys = (0..20u8)
+        .map(|i| Bytes::from(vec![i; 4]))
+        .collect::<Vec<_>>();
+    let mut snapshots = vec![hashbrown::HashMap::new()];
+    let mut kv = hashbrown::HashMap::new();
+
+    for _ in 0..30 {
+        let mut batch = Vec::new();
+        for _ in 0..rng.gen_range(1..6) {
+            let key = keys[rng.gen_range(0..keys.len())].clone();
+            let value = if rng.gen_bool(0.2) {
+                DEFAULT_VALUE
+            } else {
+                Bytes::from(vec![rng.gen_range(1..4u8); 8])
+            };
+            if value.is_empty() {
+                kv.remove(&key);
+            } else {
+                kv.insert(key.clone(), value.clone());
+            }
+            batch.push((key, value));
+        }
+        smt.update_batch(batch).unwrap();
+        snapshots.push(kv.clone());
+    }
+
+    // Prune incrementally, a few nodes at a time.
+    loop {
+        let nodes = smt.nodes.data.len();
+        let done = smt.prune_versions_before(20, 3).unwrap();
+        assert!(nodes - smt.nodes.data.len() <= 3);
+        if done {
+            break;
+        }
+    }
+    let versions = smt.versions.as_ref().unwrap();
+    assert!(versions.stale.range(..=20).next().is_none());
+    assert!(smt.prune_versions_before(20, 0).unwrap());
+
+    // The pruned versions are gone, the retained ones are still readable.
+    assert_eq!(smt.root_at_version(19), None);
+    for (version, kv) in snapshots.iter().enumerate().skip(20) {
+        let root = smt.root_at_version(version as u64).unwrap();
+        for key in &keys {
+            assert_eq!(
+                smt.get_for_root(key, root.clone()).unwrap(),
+                kv.get(key).cloned()
+            );
+        }
+    }
+
+    // Only the nodes reachable from the retained roots are left.
+    let mut reachable = hashbrown::HashSet::new();
+    for version in 20..=smt.version() {
+        let mut stack = vec![smt.root_at_version(version).unwrap()];
+        while let Some(hash) = stack.pop() {
+            if hash.eq(smt.th.placeholder_ref()) || !reachable.insert(hash.clone()) {
+                continue;
+            }
+            let data = smt.nodes.get(&hash).unwrap().unwrap();
+            if !TreeHasher::<sha2::Sha256>::is_leaf(&Some(&data)) {
+                let (left, right) = TreeHasher::<sha2::Sha256>::parse_node(&data).unwrap();
+                stack.push(left);
+                stack.push(right);
+            }
+        }
+    }
+    assert_eq!(smt.nodes.data.len(), reachable.len());
+
+    // Only the values referenced by the remaining leaves are left.
+    let mut value_hashes = hashbrown::HashSet::new();
+    for data in smt.nodes.data.values() {
+        if TreeHasher::<sha2::Sha256>::is_leaf(&Some(data)) {
+            let (_, value_hash) = TreeHasher::<sha2::Sha256>::parse_leaf(data).unwrap();
+            value_hashes.insert(values::value_key(value_hash));
+        }
+    }
+    for key in smt.values.data.keys() {
+        if key.len() == 33 && key[0] == 0 {
+            assert!(value_hashes.contains(key));
+        }
+    }
+    for key in value_hashes {
+        assert!(smt.values.data.contains_key(&key));
+    }
+
+    // Pruning every version but the latest keeps the latest root readable.
+    assert!(smt.prune_versions_before(u64::MAX, usize::MAX).unwrap());
+    assert_eq!(smt.root_at_version(smt.version()), Some(smt.root()));
+    for key in &keys {
+        assert_eq!(
+            smt.get_for_root(key, smt.root()).unwrap(),
+            kv.get(key).cloned()
+        );
+    }
+}
+
+#[test]
+fn test_versioned_smt_failed_batch() {
+    let mut smt = SparseMerkleTree::<BatchStore>::new_versioned();
+    for i in 0..8u8 {
+        smt.update(&[i], Bytes::from(vec![i; 4])).unwrap();
+    }
+    smt.update(&[0], Bytes::from(vec![8; 4])).unwrap();
+    let state = |tree: &SparseMerkleTree<BatchStore>| {
+        let versions = tree.versions.as_ref().unwrap();
+        (
+            tree.root(),
+            versions.version,
+            versions.roots.clone(),
+            versions.stale.clone(),
+            versions.stale_since.clone(),
+        )
+    };
+    let before = state(&smt);
+
+    // Failed operations leave the root and the versions unchanged, whichever store
+    // fails to write its batch.
+    for fail_values in [false, true] {
+        let mut tree = smt.clone();
+        if fail_values {
+            tree.values.fail = true;
+        } else {
+            tree.nodes.fail = true;
+        }
+        assert!(tree.update(&[0], Bytes::from(vec![9; 4])).is_err());
+        assert!(tree.remove(&[1]).is_err());
+        assert!(tree
+            .update_batch((0..8u8).map(|i| ([i], Bytes::from(vec![i + 1; 4]))))
+            .is_err());
+        assert!(tree.prune_versions_before(5, usize::MAX).is_err());
+        assert!(state(&tree) == before);
+        // Both batches were aborted.
+        assert!(!tree.nodes.in_batch && !tree.values.in_batch);
+    }
+
+    // Nothing was written by the operations failing on the nodes store, so the
+    // tree carries on as if they never happened.
+    let mut tree = smt.clone();
+    tree.nodes.fail = true;
+    assert!(tree.update(&[0], Bytes::from(vec![9; 4])).is_err());
+    assert!(tree.prune_versions_before(5, usize::MAX).is_err());
+    tree.nodes.fail = false;
+    for tree in [&mut tree, &mut smt] {
+        tree.update(&[1], Bytes::from(vec![9; 4])).unwrap();
+        assert!(tree.prune_versions_before(5, usize::MAX).unwrap());
+    }
+    assert!(state(&tree) == state(&smt));
+    assert_eq!(tree.nodes.store.data, smt.nodes.store.data);
+    assert_eq!(tree.values.store.data, smt.values.store.data);
+}
+
+#[test]
+fn test_versioned_smt_reopen() {
+    let mut smt = SparseMerkleTree::<SimpleStore>::new_versioned();
+    let mut roots = vec![smt.root()];
+    for i in 0..10u8 {
+        smt.update(&[i % 4], Bytes::from(vec![i; 4])).unwrap();
+        roots.push(smt.root());
+    }
+    smt.remove(&[0]).unwrap();
+    roots.push(smt.root());
+    let stale = (0..=smt.version())
+        .map(|version| smt.stale_nodes_at_version(version))
+        .collect::<Vec<_>>();
+
+    // Reopening the dropped tree restores its roots and stale node index.
+    let (nodes, values) = (smt.nodes.clone(), smt.values.clone());
+    drop(smt);
+    let mut smt = SparseMerkleTree::<SimpleStore>::open_versioned(nodes, values).unwrap();
+    assert!(smt.is_versioned());
+    assert_eq!(smt.version(), 11);
+    assert_eq!(smt.root(), roots[11]);
+    for (version, root) in roots.iter().enumerate() {
+        assert_eq!(smt.root_at_version(version as u64).as_ref(), Some(root));
+        assert_eq!(smt.stale_nodes_at_version(version as u64), stale[version]);
+    }
+    assert_eq!(
+        smt.get_for_root([1], roots[2].clone()).unwrap(),
+        Some(Bytes::from(vec![1; 4]))
+    );
+
+    // Partial pruning of the reopened tree is also persisted.
+    assert!(!smt.prune_versions_before(8, 1).unwrap());
+    let (nodes, values) = (smt.nodes.clone(), smt.values.clone());
+    drop(smt);
+    let mut smt = SparseMerkleTree::<SimpleStore>::open_versioned(nodes, values).unwrap();
+    assert_eq!(smt.root_at_version(7), None);
+    assert_eq!(smt.root_at_version(8), Some(roots[8].clone()));
+    while !smt.prune_versions_before(8, 1).unwrap() {}
+    assert!(smt
+        .versions
+        .as_ref()
+        .unwrap()
+        .stale
+        .range(..=8)
+        .next()
+        .is_none());
+    for (version, root) in roots.iter().enumerate().skip(8) {
+        smt.verify_integrity(root.clone()).unwrap();
+        assert_eq!(smt.root_at_version(version as u64).as_ref(), Some(root));
+    }
+
+    // Updates continue from the reopened version.
+    smt.update(b"testKey", Bytes::from_static(b"testValue"))
+        .unwrap();
+    assert_eq!(smt.version(), 12);
+    let (nodes, values) = (smt.nodes.clone(), smt.values.clone());
+    let root = smt.root();
+    drop(smt);
+    let smt = SparseMerkleTree::<SimpleStore>::open_versioned(nodes, values).unwrap();
+    assert_eq!(smt.root(), root);
+    assert_eq!(smt.root_at_version(8), Some(roots[8].clone()));
+
+    // Stores without version records open as a new versioned tree, and corrupt
+    // records are reported.
+    let smt =
+        SparseMerkleTree::<SimpleStore>::open_versioned(SimpleStore::new(), SimpleStore::new())
+            .unwrap();
+    assert_eq!(smt.version(), 0);
+    assert_eq!(smt.root_at_version(0), Some(smt.root()));
+    let mut values = SimpleStore::new();
+    values
+        .set(Bytes::from_static(&[2]), Bytes::from_static(&[0; 7]))
+        .unwrap();
+    assert!(matches!(
+        SparseMerkleTree::<SimpleStore>::open_versioned(SimpleStore::new(), values),
+        Err(TreeError::CorruptVersions)
+    ));
+}
+
+#[test]
+fn test_state_syncer() {
+    use rand::RngCore;
+
+    let mut smt = new_sparse_merkle_tree();
+    let mut rng = rand::thread_rng();
+    for _ in 0..50 {
+        let mut key = vec![0; 16];
+        rng.fill_bytes(&mut key);
+        let mut value = vec![0; 8];
+        rng.fill_bytes(&mut value);
+        smt.update(&key, Bytes::from(value)).unwrap();
+    }
+    let leaves = smt
+        .iter_leaves(smt.root())
+        .with_values()
+        .map(|leaf| {
+            let (path, _, value) = leaf.unwrap().into_parts();
+            (path, value.unwrap())
+        })
+        .collect::<Vec<_>>();
+
+    // Split the leaves into chunks, each ending at its last leaf, the last one
+    // ending at the last path.
+    let mut chunks = Vec::new();
+    let mut start = Bytes::from(vec![0; 32]);
+    for (i, chunk) in leaves.chunks(7).enumerate() {
+        let end = if (i + 1) * 7 >= leaves.len() {
+            Bytes::from(vec![0xff; 32])
+        } else {
+            chunk.last().unwrap().0.clone()
+        };
+        let proof = smt.prove_range(&start, &end).unwrap();
+        // The next chunk starts right after this one.
+        let mut next = end.to_vec();
+        for byte in next.iter_mut().rev() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        start = next.into();
+        chunks.push((proof, chunk.to_vec()));
+    }
+
+    let mut syncer = StateSyncer::new(SimpleStore::new(), SimpleStore::new(), smt.root());
+    assert_eq!(syncer.target_root(), smt.root());
+
+    // Out of order and tampered chunks are rejected.
+    assert!(matches!(
+        syncer.add_chunk(&chunks[1].0, chunks[1].1.clone()),
+        Err(TreeError::UnexpectedChunk { expected: Some(path) }) if path.eq(chunks[0].0.start_path())
+    ));
+    let mut tampered = chunks[0].1.clone();
+    tampered[0].1 = Bytes::from_static(b"tampered");
+    assert!(matches!(
+        syncer.add_chunk(&chunks[0].0, tampered),
+        Err(TreeError::BadProof)
+    ));
+    assert!(matches!(
+        syncer.add_chunk(&chunks[0].0, chunks[0].1[1..].to_vec()),
+        Err(TreeError::BadProof)
+    ));
+
+    for (proof, chunk) in &chunks {
+        assert!(!syncer.is_complete());
+        assert_eq!(syncer.next_start_path(), Some(proof.start_path()));
+        syncer.add_chunk(proof, chunk.clone()).unwrap();
+    }
+    assert!(syncer.is_complete());
+    assert!(matches!(
+        syncer.add_chunk(&chunks[0].0, chunks[0].1.clone()),
+        Err(TreeError::UnexpectedChunk { expected: None })
+    ));
+
+    // A sync cannot finish early.
+    let mut partial = StateSyncer::new(SimpleStore::new(), SimpleStore::new(), smt.root());
+    partial
+        .add_chunk(&chunks[0].0, chunks[0].1.clone())
+        .unwrap();
+    assert!(matches!(
+        partial.finish(),
+        Err(TreeError::IncompleteSync { next_path }) if next_path.eq(chunks[1].0.start_path())
+    ));
+
+    let synced = syncer.finish().unwrap();
+    assert_eq!(synced.root(), smt.root());
+    assert_eq!(synced.nodes.data, smt.nodes.data);
+    assert_eq!(synced.values.data, smt.values.data);
+    for (path, value) in &leaves {
+        assert_eq!(synced.values.get(path).unwrap(), Some(value.clone()));
+    }
+
+    // The synced tree can be updated like the original.
+    let mut synced = synced;
+    synced
+        .update(b"testKey", Bytes::from_static(b"testValue"))
+        .unwrap();
+    smt.update(b"testKey", Bytes::from_static(b"testValue"))
+        .unwrap();
+    assert_eq!(synced.root(), smt.root());
+
+    // The empty tree is synced from the start.
+    let syncer = StateSyncer::<SimpleStore>::new(
+        SimpleStore::new(),
+        SimpleStore::new(),
+        new_sparse_merkle_tree().root(),
+    );
+    assert!(syncer.is_complete());
+    assert!(syncer.finish().is_ok());
+}
+
+#[test]
+fn test_smt_verify_integrity() {
+    let mut smt = new_sparse_merkle_tree();
+    assert!(smt.verify_integrity(smt.root()).is_ok());
+    for i in 0..20u8 {
+        smt.update(&[i], Bytes::from(vec![i + 1; 4])).unwrap();
+    }
+    assert!(smt.verify_integrity(smt.root()).is_ok());
+    let imported = SparseMerkleTree::<SimpleStore>::import_verified(
+        smt.nodes.clone(),
+        smt.values.clone(),
+        smt.root(),
+    )
+    .unwrap();
+    assert_eq!(imported.get(&[3]).unwrap(), Some(Bytes::from(vec![4; 4])));
+
+    let inconsistency = |tree: &SparseMerkleTree<SimpleStore>| match tree
+        .verify_integrity(tree.root())
+        .unwrap_err()
+    {
+        TreeError::Inconsistent(inconsistency) => inconsistency,
+        e => panic!("unexpected error: {}", e),
+    };
+
+    // An unknown root.
+    let mut tree = smt.clone();
+    tree.set_root(smt.th.path_into(b"unknown"));
+    let found = inconsistency(&tree);
+    assert_eq!(found.kind(), InconsistencyKind::MissingNode);
+    assert_eq!(found.depth(), 0);
+    assert!(matches!(
+        SparseMerkleTree::<SimpleStore>::import_verified(tree.nodes, tree.values, tree.root),
+        Err(TreeError::Inconsistent(_))
+    ));
+
+    // A missing leaf, reported with the path leading to it.
+    let path = smt.th.path_into([7]);
+    let UpdateResult {
+        side_nodes,
+        path_nodes,
+        ..
+    } = smt.snapshot().side_nodes_for_root(&path, false).unwrap();
+    let mut tree = smt.clone();
+    tree.nodes.data.remove(&path_nodes[0]);
+    let found = inconsistency(&tree);
+    assert_eq!(found.kind(), InconsistencyKind::MissingNode);
+    assert_eq!(found.hash(), &path_nodes[0]);
+    assert_eq!(found.depth(), side_nodes.len());
+    assert!(count_common_prefix(found.path(), &path) >= found.depth());
+
+    // A node whose data does not match its hash.
+    let mut tree = smt.clone();
+    let data = tree.nodes.data.get(&path_nodes[1]).unwrap().to_vec();
+    let mut tampered = data.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    tree.nodes
+        .data
+        .insert(path_nodes[1].clone(), tampered.into());
+    assert_eq!(inconsistency(&tree).kind(), InconsistencyKind::HashMismatch);
+
+    // A node with malformed data.
+    tree.nodes
+        .data
+        .insert(path_nodes[1].clone(), Bytes::from_static(b"corrupt"));
+    assert_eq!(inconsistency(&tree).kind(), InconsistencyKind::CorruptNode);
+
+    // A missing or wrong value.
+    let mut tree = smt.clone();
+    tree.values.data.remove(&path);
+    let found = inconsistency(&tree);
+    assert_eq!(found.kind(), InconsistencyKind::MissingValue);
+    assert_eq!(found.path(), &path);
+    tree.values
+        .data
+        .insert(path.clone(), Bytes::from_static(b"wrong"));
+    assert_eq!(
+        inconsistency(&tree).kind(),
+        InconsistencyKind::ValueMismatch
+    );
+
+    // A versioned tree must have the value of every leaf of its latest root under
+    // the path of the leaf, even though it also stores the value by its hash.
+    let mut versioned = SparseMerkleTree::<SimpleStore>::new_versioned();
+    for i in 0..20u8 {
+        versioned.update(&[i], Bytes::from(vec![i + 1; 4])).unwrap();
+    }
+    let old_root = versioned.root();
+    versioned.update(&[7], Bytes::from(vec![0; 4])).unwrap();
+    assert!(versioned.verify_integrity(versioned.root()).is_ok());
+    assert!(versioned.verify_integrity(old_root.clone()).is_ok());
+    let mut tree = versioned.clone();
+    tree.values
+        .data
+        .insert(path.clone(), Bytes::from_static(b"wrong"));
+    let found = inconsistency(&tree);
+    assert_eq!(found.kind(), InconsistencyKind::ValueMismatch);
+    assert_eq!(found.path(), &path);
+    // Older roots read the values they no longer share with the latest root by hash.
+    assert!(tree.verify_integrity(old_root.clone()).is_ok());
+    tree.values
+        .data
+        .remove(&super::values::value_key(&smt.th.digest([8; 4])));
+    assert!(tree.verify_integrity(old_root).is_err());
+}
+
+#[test]
+fn test_smt_tree_errors() {
+    let mut smt = new_sparse_merkle_tree();
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    smt.update(b"testKey2", Bytes::from("testValue2")).unwrap();
+
+    // A root which does not have the length of a hash.
+    let short_root = Bytes::from_static(b"short");
+    assert!(matches!(
+        smt.get_for_root(b"testKey1", short_root.clone()),
+        Err(TreeError::InvalidRootLength { len: 5 })
+    ));
+    assert!(matches!(
+        smt.prove_for_root(b"testKey1", short_root.clone()),
+        Err(TreeError::InvalidRootLength { len: 5 })
+    ));
+    assert!(matches!(
+        smt.update_for_root(b"testKey1", Bytes::from("testValue3"), short_root.clone()),
+        Err(TreeError::InvalidRootLength { len: 5 })
+    ));
+    assert!(matches!(
+        smt.iter_leaves(short_root).next(),
+        Some(Err(TreeError::InvalidRootLength { len: 5 }))
+    ));
+
+    // A root which is not in the nodes store.
+    let unknown_root = smt.th.path_into(b"unknown");
+    assert!(matches!(
+        smt.prove_for_root(b"testKey1", unknown_root.clone()),
+        Err(TreeError::MissingNode { hash }) if hash == unknown_root
+    ));
+    assert!(matches!(
+        smt.update_batch_for_root([(b"testKey1", Bytes::from("testValue3"))], unknown_root),
+        Err(TreeError::MissingNode { .. })
+    ));
+
+    // A node whose data is malformed.
+    let mut corrupt = smt.clone();
+    corrupt
+        .nodes
+        .data
+        .insert(smt.root(), Bytes::from_static(b"corrupt"));
+    assert!(matches!(
+        corrupt.prove(b"testKey1"),
+        Err(TreeError::CorruptNode { hash }) if hash == smt.root()
+    ));
+    assert!(matches!(
+        corrupt.get_descend(b"testKey1"),
+        Err(TreeError::CorruptNode { .. })
+    ));
+    assert!(matches!(
+        corrupt.update(b"testKey3", Bytes::from("testValue3")),
+        Err(TreeError::CorruptNode { .. })
+    ));
+
+    // An invalid proof.
+    let mut proof = smt.prove(b"testKey1").unwrap();
+    proof.side_nodes.clear();
+    let mut dsmst = SparseMerkleTree::import(SimpleStore::new(), SimpleStore::new(), smt.root());
+    assert!(matches!(
+        dsmst.add_branch(proof, b"testKey1", Bytes::from("testValue1")),
+        Err(TreeError::BadProof)
+    ));
+}
+
+/// Runs every read and write of the tree against its current root, which must not
+/// panic whatever the nodes store holds.
+fn exercise_tree(smt: &mut SparseMerkleTree<SimpleStore>, keys: &[Vec<u8>]) {
+    let root = smt.root();
+    for key in keys {
+        let _ = smt.get_descend(key);
+        let _ = smt.get_for_root(key, root.clone());
+        let _ = smt.prove(key);
+        let _ = smt.prove_updatable(key);
+        let _ = smt.prove_compact(key);
+    }
+    let _ = smt.prove_many(keys);
+    let _ = smt.prove_range(vec![0; 32], vec![0x7f; 32]);
+    let _ = smt.iter_leaves(root.clone()).with_values().count();
+    let _ = smt.verify_integrity(root.clone());
+    let _ = smt.update_batch_for_root(
+        keys.iter().map(|key| (key, Bytes::from_static(b"batch"))),
+        root.clone(),
+    );
+    let _ = smt.update_for_root(&keys[0], DEFAULT_VALUE, root.clone());
+    let _ = smt.update_for_root(b"newKey", Bytes::from_static(b"new"), root);
+}
+
+#[test]
+fn test_smt_corrupt_nodes_do_not_panic() {
+    use rand::{Rng, RngCore};
+
+    let mut rng = rand::thread_rng();
+    let mut smt = new_sparse_merkle_tree();
+    let keys = (0..16u8).map(|i| vec![i; 4]).collect::<Vec<_>>();
+    for key in &keys {
+        smt.update(key, Bytes::from(key.repeat(2))).unwrap();
+    }
+    let hashes = smt.nodes.data.keys().cloned().collect::<Vec<_>>();
+
+    for _ in 0..200 {
+        // Replace a node with arbitrary bytes, which may be truncated, too long or have
+        // the prefix of a leaf or a node.
+        let mut tree = smt.clone();
+        let hash = &hashes[rng.gen_range(0..hashes.len())];
+        let mut data = vec![0; rng.gen_range(0..2 * 65)];
+        rng.fill_bytes(&mut data);
+        if let Some(prefix) = data.first_mut() {
+            *prefix = rng.gen_range(0..3);
+        }
+        tree.nodes.data.insert(hash.clone(), data.into());
+
+        // Every node is reachable, so the corruption is always found.
+        assert!(tree.verify_integrity(tree.root()).is_err());
+        exercise_tree(&mut tree, &keys);
+    }
+
+    for _ in 0..50 {
+        // A store of arbitrary, but well formed, nodes pointing to missing nodes.
+        let mut tree = new_sparse_merkle_tree();
+        let mut root = vec![0; 32];
+        rng.fill_bytes(&mut root);
+        let mut hash = Bytes::from(root);
+        tree.set_root(hash.clone());
+        for _ in 0..rng.gen_range(0..8) {
+            let mut data = vec![0; 65];
+            rng.fill_bytes(&mut data);
+            data[0] = rng.gen_range(0..2);
+            let data = Bytes::from(data);
+            tree.nodes.data.insert(hash, data.clone());
+            hash = data.slice(1 + 32 * rng.gen_range(0..2)..).slice(..32);
+        }
+        exercise_tree(&mut tree, &keys);
+    }
+}
+
+#[test]
+fn test_smt_snapshot() {
+    fn assert_send<T: Send>(_: &T) {}
+
+    let mut smt = SparseMerkleTree::<SimpleStore>::new_versioned();
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    smt.update(b"testKey2", Bytes::from("testValue2")).unwrap();
+    let old_root = smt.root();
+    smt.update(b"testKey1", Bytes::from("testValue3")).unwrap();
+    smt.remove(b"testKey2").unwrap();
+
+    // A view answers for its root, whatever the latest root of the tree is.
+    let snapshot = smt.snapshot();
+    assert_send(&snapshot);
+    assert_eq!(snapshot.root(), smt.root());
+    assert_eq!(
+        snapshot.get(b"testKey1").unwrap(),
+        Some(Bytes::from("testValue3"))
+    );
+    assert!(!snapshot.contains(b"testKey2").unwrap());
+
+    let view = smt.snapshot_at(old_root.clone());
+    assert_eq!(
+        view.get(b"testKey1").unwrap(),
+        Some(Bytes::from("testValue1"))
+    );
+    assert_eq!(
+        view.get_descend(b"testKey2").unwrap(),
+        Some(Bytes::from("testValue2"))
+    );
+    assert!(view.has_descend(b"testKey2").unwrap());
+    assert!(!view.contains(b"testKey3").unwrap());
+    let proof = view.prove(b"testKey2").unwrap();
+    assert!(proof.verify(old_root.clone(), b"testKey2", b"testValue2"));
+    let proof = view.prove_compact(b"testKey1").unwrap();
+    assert!(proof.verify(old_root.clone(), b"testKey1", b"testValue1"));
+    let proof = view.prove_many([b"testKey1", b"testKey2"]).unwrap();
+    assert!(proof.verify(
+        old_root.clone(),
+        [
+            (b"testKey1", b"testValue1".as_slice()),
+            (b"testKey2", b"testValue2".as_slice())
+        ]
+    ));
+    assert_eq!(view.iter_leaves().count(), 2);
+    assert_eq!(snapshot.iter_leaves().count(), 1);
+
+    // Views can also be created over stores shared with a writer.
+    let shared = SparseMerkleTreeView::<SimpleStore>::new(&smt.nodes, &smt.values, old_root);
+    assert_eq!(
+        shared.get(b"testKey1").unwrap(),
+        view.get(b"testKey1").unwrap()
+    );
+}
+
+#[test]
+fn test_smt_view_during_writes() {
+    let (nodes, values) = (SharedStore::default(), SharedStore::default());
+    let mut smt =
+        SparseMerkleTree::<SharedStore>::new_versioned_with_stores(nodes.clone(), values.clone());
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    smt.update(b"testKey2", Bytes::from("testValue2")).unwrap();
+    let root = smt.root();
+
+    // Views over their own handles to the stores of a versioned tree keep answering
+    // for their root while the tree is updated, here and on another thread.
+    let reader = {
+        let (nodes, values, root) = (nodes.clone(), values.clone(), root.clone());
+        std::thread::spawn(move || {
+            let view = SparseMerkleTreeView::<SharedStore>::new(&nodes, &values, root.clone());
+            for _ in 0..100 {
+                assert_eq!(
+                    view.get(b"testKey1").unwrap(),
+                    Some(Bytes::from("testValue1"))
+                );
+                let proof = view.prove(b"testKey2").unwrap();
+                assert!(proof.verify(root.clone(), b"testKey2", b"testValue2"));
+            }
+        })
+    };
+    let view = SparseMerkleTreeView::<SharedStore>::new(&nodes, &values, root.clone());
+    for i in 0..20u8 {
+        smt.update(b"testKey1", Bytes::from(vec![i; 4])).unwrap();
+        smt.update(&[i], Bytes::from(vec![i; 4])).unwrap();
+        if i % 4 == 0 {
+            smt.remove(b"testKey2").unwrap();
+        } else {
+            smt.update(b"testKey2", Bytes::from(vec![i; 8])).unwrap();
+        }
+        assert_eq!(
+            view.get(b"testKey1").unwrap(),
+            Some(Bytes::from("testValue1"))
+        );
+        let proof = view.prove(b"testKey2").unwrap();
+        assert!(proof.verify(root.clone(), b"testKey2", b"testValue2"));
+        assert_eq!(view.iter_leaves().count(), 2);
+    }
+    reader.join().unwrap();
+
+    // A tree which is not versioned removes the nodes of the roots it updates, so
+    // views of those roots stop answering.
+    let (nodes, values) = (SharedStore::default(), SharedStore::default());
+    let mut smt = SparseMerkleTree::<SharedStore>::new_with_stores(nodes.clone(), values.clone());
+    smt.update(b"testKey1", Bytes::from("testValue1")).unwrap();
+    let view = SparseMerkleTreeView::<SharedStore>::new(&nodes, &values, smt.root());
+    smt.update(b"testKey1", Bytes::from("testValue2")).unwrap();
+    assert!(matches!(
+        view.get(b"testKey1"),
+        Err(TreeError::MissingNode { .. })
+    ));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_smt() {
+    use rand::Rng;
+
+    fn assert_send<T: Send>(_: &T) {}
+
+    let mut rng = rand::thread_rng();
+    let mut smt = new_sparse_merkle_tree();
+    let mut async_smt = AsyncSparseMerkleTree::<SimpleStore>::new();
+    let keys = (0..32u8).map(|i| vec![i; 4]).collect::<Vec<_>>();
+    for _ in 0..200 {
+        let key = &keys[rng.gen_range(0..keys.len())];
+        let value = if rng.gen_bool(0.3) {
+            DEFAULT_VALUE
+        } else {
+            Bytes::from(vec![rng.gen_range(1..4u8); 4])
+        };
+        smt.update(key, value.clone()).unwrap();
+        let update = async_smt.update(key, value);
+        assert_send(&update);
+        pollster::block_on(update).unwrap();
+        assert_eq!(async_smt.root(), smt.root());
+    }
+
+    // The stores are laid out the same way.
+    assert_eq!(async_smt.nodes.data, smt.nodes.data);
+    assert_eq!(async_smt.values.data, smt.values.data);
+
+    pollster::block_on(async {
+        for key in &keys {
+            assert_eq!(async_smt.get(key).await.unwrap(), smt.get(key).unwrap());
+            assert_eq!(
+                async_smt.contains(key).await.unwrap(),
+                smt.contains(key).unwrap()
+            );
+            let proof = async_smt.prove(key).await.unwrap();
+            let value = smt.get(key).unwrap().unwrap_or_default();
+            assert!(proof.verify(smt.root(), key, &value));
+            assert_eq!(
+                async_smt.prove_compact(key).await.unwrap().side_nodes(),
+                smt.prove_compact(key).unwrap().side_nodes()
+            );
+        }
+
+        // A subtree can be built from branches.
+        let mut dsmst =
+            AsyncSparseMerkleTree::import(SimpleStore::new(), SimpleStore::new(), smt.root());
+        for key in &keys[..4] {
+            let proof = async_smt.prove_updatable(key).await.unwrap();
+            let value = smt.get(key).unwrap().unwrap_or_default();
+            dsmst.add_branch(proof, key, value).await.unwrap();
+        }
+        for key in &keys[..4] {
+            dsmst
+                .update(key, Bytes::from_static(b"testValue"))
+                .await
+                .unwrap();
+            smt.update(key, Bytes::from_static(b"testValue")).unwrap();
+        }
+        assert_eq!(dsmst.root(), smt.root());
+        assert!(matches!(
+            dsmst
+                .add_branch(
+                    smt.prove(&keys[4]).unwrap(),
+                    &keys[4],
+                    Bytes::from_static(b"bad")
+                )
+                .await,
+            Err(TreeError::BadProof)
+        ));
+        for key in &keys[..4] {
+            assert_eq!(
+                dsmst
+                    .prove_compact_for_root(key, smt.root())
+                    .await
+                    .unwrap()
+                    .encode(),
+                smt.prove_compact_for_root(key, smt.root())
+                    .unwrap()
+                    .encode()
+            );
+        }
+    });
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_async_smt_write_batch() {
+    let mut smt = SparseMerkleTree::<BatchStore>::new();
+    let mut async_smt = AsyncSparseMerkleTree::<BatchStore>::new();
+    let keys = (0..16u8).map(|i| vec![i; 4]).collect::<Vec<_>>();
+    pollster::block_on(async {
+        // Each update is written as the same single batch to each store.
+        for key in &keys {
+            smt.update(key, Bytes::from(key.repeat(2))).unwrap();
+            async_smt
+                .update(key, Bytes::from(key.repeat(2)))
+                .await
+                .unwrap();
+        }
+        smt.remove(&keys[0]).unwrap();
+        async_smt.remove(&keys[0]).await.unwrap();
+        assert_eq!(async_smt.root(), smt.root());
+        assert_eq!(async_smt.nodes.batches, smt.nodes.batches);
+        assert_eq!(async_smt.values.batches, smt.values.batches);
+
+        // Missing and corrupt nodes are reported like by the synchronous tree, and
+        // a failed operation writes nothing.
+        let leaf = smt
+            .th
+            .digest_leaf(smt.th.path(&keys[15]), smt.th.digest(keys[15].repeat(2)))
+            .0;
+        for data in [None, Some(Bytes::from_static(b"corrupt"))] {
+            let mut smt = smt.clone();
+            let mut async_smt = AsyncSparseMerkleTree::<BatchStore>::import(
+                async_smt.nodes.clone(),
+                async_smt.values.clone(),
+                async_smt.root(),
+            );
+            for store in [&mut smt.nodes.store, &mut async_smt.nodes.store] {
+                match &data {
+                    Some(data) => store.data.insert(leaf.clone(), data.clone()),
+                    None => store.data.remove(&leaf),
+                };
+            }
+            let nodes = async_smt.nodes.store.data.clone();
+            let values = async_smt.values.store.data.clone();
+            let expected = smt.remove(&keys[15]).unwrap_err();
+            let err = async_smt.remove(&keys[15]).await.unwrap_err();
+            assert_eq!(format!("{:?}", err), format!("{:?}", expected));
+            assert!(matches!(
+                err,
+                TreeError::MissingNode { .. } | TreeError::CorruptNode { .. }
+            ));
+            assert_eq!(async_smt.root(), smt.root());
+            assert_eq!(async_smt.nodes.store.data, nodes);
+            assert_eq!(async_smt.values.store.data, values);
+        }
+
+        // Errors of the underlying store are passed through, and abort both batches.
+        async_smt.nodes.fail = true;
+        assert!(matches!(
+            async_smt
+                .update(&keys[4], Bytes::from_static(b"testValue"))
+                .await,
+            Err(TreeError::Store(Error::NotFound))
+        ));
+        assert!(!async_smt.nodes.in_batch && !async_smt.values.in_batch);
+    });
+}
+
+#[test]
+fn test_smt_write_batch() {
+    let mut smt = SparseMerkleTree::<BatchStore>::new();
+    let mut expected = new_sparse_merkle_tree();
+    let keys = (0..16u8).map(|i| vec![i; 4]).collect::<Vec<_>>();
+    for (i, key) in keys.iter().enumerate() {
+        smt.update(key, Bytes::from(key.repeat(2))).unwrap();
+        expected.update(key, Bytes::from(key.repeat(2))).unwrap();
+        // Each update is written as a single batch to each store.
+        assert_eq!(smt.nodes.batches.len(), i + 1);
+        assert_eq!(smt.values.batches.len(), i + 1);
+    }
+    smt.remove(&keys[0]).unwrap();
+    expected.remove(&keys[0]).unwrap();
+    smt.update_batch(keys[1..4].iter().map(|key| (key, DEFAULT_VALUE)))
+        .unwrap();
+    expected
+        .update_batch(keys[1..4].iter().map(|key| (key, DEFAULT_VALUE)))
+        .unwrap();
+    assert_eq!(smt.nodes.batches.len(), keys.len() + 2);
+    assert_eq!(smt.root(), expected.root());
+    assert_eq!(smt.nodes.store.data, expected.nodes.data);
+    assert_eq!(smt.values.store.data, expected.values.data);
+
+    // Setting the same value writes nothing.
+    smt.update(&keys[4], Bytes::from(keys[4].repeat(2)))
+        .unwrap();
+    assert_eq!(smt.nodes.batches.len(), keys.len() + 2);
+
+    // A failed operation writes nothing.
+    let mut corrupt = smt.clone();
+    let leaf = corrupt
+        .th
+        .digest_leaf(
+            corrupt.th.path(&keys[15]),
+            corrupt.th.digest(keys[15].repeat(2)),
+        )
+        .0;
+    corrupt
+        .nodes
+        .store
+        .data
+        .insert(leaf, Bytes::from_static(b"corrupt"));
+    let nodes = corrupt.nodes.store.data.clone();
+    let values = corrupt.values.store.data.clone();
+    assert!(corrupt.remove(&keys[15]).is_err());
+    assert!(corrupt
+        .update_batch(
+            keys.iter()
+                .map(|key| (key, Bytes::from_static(b"testValue")))
+        )
+        .is_err());
+    assert_eq!(corrupt.root(), smt.root());
+    assert_eq!(corrupt.nodes.store.data, nodes);
+    assert_eq!(corrupt.values.store.data, values);
+
+    // Errors of the underlying store are passed through, and abort both batches.
+    smt.nodes.fail = true;
+    assert!(matches!(
+        smt.update(&keys[4], Bytes::from_static(b"testValue")),
+        Err(TreeError::Store(Error::NotFound))
+    ));
+    assert!(!smt.nodes.in_batch && !smt.values.in_batch);
+}
+
+#[test]
+fn test_smt_cached_store() {
+    let mut smt = SparseMerkleTree::<_, sha2::Sha256>::new_with_stores(
+        CachedStore::new(BatchStore::default()),
+        CachedStore::new(BatchStore::default()),
+    );
+    let mut expected = new_sparse_merkle_tree();
+    let keys = (0..16u8).map(|i| vec![i; 4]).collect::<Vec<_>>();
+    for key in &keys {
+        smt.update(key, Bytes::from(key.repeat(2))).unwrap();
+        expected.update(key, Bytes::from(key.repeat(2))).unwrap();
+    }
+    smt.remove(&keys[0]).unwrap();
+    expected.remove(&keys[0]).unwrap();
+    assert_eq!(smt.root(), expected.root());
+    assert_eq!(smt.get(&keys[1]).unwrap(), expected.get(&keys[1]).unwrap());
+
+    // Nothing reaches the inner stores before the commit.
+    assert!(smt.nodes_store().is_dirty());
+    assert!(smt.nodes_store().inner().batches.is_empty());
+    smt.nodes_store_mut().commit().unwrap();
+    smt.values_store_mut().commit().unwrap();
+    assert!(!smt.nodes_store().is_dirty());
+    assert_eq!(smt.nodes_store().inner().batches.len(), 1);
+    assert_eq!(smt.nodes_store().inner().store.data, expected.nodes.data);
+    assert_eq!(smt.values_store().inner().store.data, expected.values.data);
+
+    // Discarding a speculative block restores the committed tree.
+    let committed = smt.root();
+    smt.update(&keys[1], Bytes::from_static(b"testValue"))
+        .unwrap();
+    smt.remove(&keys[2]).unwrap();
+    assert_ne!(smt.root(), committed);
+    smt.nodes_store_mut().discard();
+    smt.values_store_mut().discard();
+    smt.set_root(committed);
+    assert_eq!(smt.get(&keys[1]).unwrap(), expected.get(&keys[1]).unwrap());
+    assert!(smt.contains(&keys[2]).unwrap());
+    assert_eq!(smt.root(), expected.root());
+
+    // A failed commit aborts the batch of the inner store and keeps the cached writes.
+    smt.update(&keys[1], Bytes::from_static(b"testValue"))
+        .unwrap();
+    smt.nodes_store_mut().inner_mut().fail = true;
+    assert!(smt.nodes_store_mut().commit().is_err());
+    assert!(!smt.nodes_store().inner().in_batch);
+    assert!(smt.nodes_store().is_dirty());
+    smt.nodes_store_mut().inner_mut().fail = false;
+    smt.nodes_store_mut().commit().unwrap();
+    smt.values_store_mut().commit().unwrap();
+    let smt2 = SparseMerkleTree::<BatchStore>::import(
+        smt.nodes_store().inner().clone(),
+        smt.values_store().inner().clone(),
+        smt.root(),
+    );
+    assert_eq!(
+        smt2.get(&keys[1]).unwrap(),
+        Some(Bytes::from_static(b"testValue"))
+    );
+    smt2.verify_integrity(smt2.root()).unwrap();
+}
+
+#[test]
+fn test_smt_hasher_decoupled() {
+    // The same store type backs trees of different hashers.
+    let mut sha256 = SparseMerkleTree::<BatchStore, sha2::Sha256>::new();
+    let mut sha512 = SparseMerkleTree::<BatchStore, sha2::Sha512>::new();
+    for i in 0..8u8 {
+        sha256.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+        sha512.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+    }
+    assert_eq!(sha256.root().len(), 32);
+    assert_eq!(sha512.root().len(), 64);
+    assert_eq!(sha256.get(&[3; 4]).unwrap(), sha512.get(&[3; 4]).unwrap());
+
+    let proof: SparseMerkleProof<sha2::Sha512> = sha512.prove([3; 4]).unwrap();
+    assert!(proof.verify(sha512.root_ref(), [3; 4], [3; 8]));
+    assert!(!sha256
+        .prove([3; 4])
+        .unwrap()
+        .verify(sha512.root_ref(), [3; 4], [3; 8]));
+}
+
+/// A scheme with its own domain separation and a non-zero placeholder.
+#[derive(Clone, Default)]
+struct TaggedScheme;
+
+impl TaggedScheme {
+    fn hash(parts: &[&[u8]]) -> Bytes {
+        use digest::Digest;
+        let mut hasher = sha2::Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().to_vec().into()
+    }
+}
+
+impl crate::TreeHashScheme for TaggedScheme {
+    fn hash_size() -> usize {
+        32
+    }
+
+    fn path(&self, key: &[u8]) -> Bytes {
+        Self::hash(&[b"path", key])
+    }
+
+    fn hash_value(&self, value: &[u8]) -> Bytes {
+        Self::hash(&[b"value", value])
+    }
+
+    fn hash_leaf(&self, path: &[u8], value_hash: &[u8]) -> Bytes {
+        Self::hash(&[b"leaf", path, value_hash])
+    }
+
+    fn hash_node(&self, left: &[u8], right: &[u8]) -> Bytes {
+        Self::hash(&[b"node", left, right])
+    }
+
+    fn placeholder(&self) -> Bytes {
+        vec![0xff; 32].into()
+    }
+}
+
+#[test]
+fn test_smt_hash_scheme() {
+    let mut smt = SparseMerkleTree::<SimpleStore, TaggedScheme>::new();
+    let mut expected = new_sparse_merkle_tree();
+    assert_eq!(smt.root(), Bytes::from(vec![0xff; 32]));
+
+    for i in 0..16u8 {
+        smt.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+        expected.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+    }
+    assert_ne!(smt.root(), expected.root());
+    assert_eq!(smt.nodes_store().len(), expected.nodes_store().len());
+    smt.verify_integrity(smt.root()).unwrap();
+
+    let root = smt.root();
+    for i in 0..16u8 {
+        assert_eq!(smt.get(&[i; 4]).unwrap(), Some(Bytes::from(vec![i; 8])));
+        assert_eq!(
+            smt.get_descend([i; 4]).unwrap(),
+            Some(Bytes::from(vec![i; 8]))
+        );
+        let proof = smt.prove([i; 4]).unwrap();
+        assert!(proof.verify(&root, [i; 4], [i; 8]));
+        assert!(!proof.verify(&root, [i; 4], [i; 7]));
+        assert!(smt
+            .prove_compact([i; 4])
+            .unwrap()
+            .verify(&root, [i; 4], [i; 8]));
+    }
+    let proof = smt.prove([16; 4]).unwrap();
+    assert!(proof.verify(&root, [16; 4], DEFAULT_VALUE));
+
+    for i in 0..16u8 {
+        smt.remove(&[i; 4]).unwrap();
+    }
+    assert_eq!(smt.root(), Bytes::from(vec![0xff; 32]));
+}
+
+#[test]
+fn test_smt_keyed_hasher() {
+    use hmac::{Hmac, Mac};
+
+    type HmacScheme = crate::KeyedHasher<Hmac<sha2::Sha256>>;
+
+    let hmac = |key: &[u8]| HmacScheme::new(Hmac::new_from_slice(key).unwrap());
+    let new_tree = |scheme| {
+        SparseMerkleTree::<SimpleStore, HmacScheme>::new_with_scheme(
+            SimpleStore::new(),
+            SimpleStore::new(),
+            scheme,
+        )
+    };
+
+    // Without a key, the scheme hashes like the `Digest` one.
+    let mut plain =
+        SparseMerkleTree::<SimpleStore, crate::KeyedHasher<sha2::Sha256>>::new_with_scheme(
+            SimpleStore::new(),
+            SimpleStore::new(),
+            crate::KeyedHasher::new(sha2::Sha256::default()),
+        );
+    let mut expected = new_sparse_merkle_tree();
+    let mut alpha = new_tree(hmac(b"alpha"));
+    let mut beta = new_tree(hmac(b"beta"));
+    assert_eq!(alpha.root(), Bytes::from(vec![0; 32]));
+
+    alpha.update(b"key", Bytes::from_static(b"value")).unwrap();
+    let mac = |data: &[&[u8]]| {
+        let mut mac = Hmac::<sha2::Sha256>::new_from_slice(b"alpha").unwrap();
+        for part in data {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().to_vec()
+    };
+    let path = mac(&[b"key"]);
+    assert_eq!(alpha.scheme().path(b"key"), Bytes::from(path.clone()));
+    assert_eq!(
+        alpha.root(),
+        Bytes::from(mac(&[&[0], &path, &mac(&[b"value"])]))
+    );
+
+    for i in 0..16u8 {
+        plain.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+        expected.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+        alpha.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+        beta.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+    }
+    assert_eq!(plain.root(), expected.root());
+    assert_ne!(alpha.root(), beta.root());
+    alpha.verify_integrity(alpha.root()).unwrap();
+
+    let root = alpha.root();
+    for i in 0..16u8 {
+        let proof = alpha.prove([i; 4]).unwrap();
+        assert!(proof.verify_with(alpha.scheme(), &root, [i; 4], [i; 8]));
+        assert!(!proof.verify_with(beta.scheme(), &root, [i; 4], [i; 8]));
+
+        let compact = alpha.prove_compact([i; 4]).unwrap();
+        assert!(compact.verify_with(alpha.scheme(), &root, [i; 4], [i; 8]));
+        assert_eq!(
+            compact.decompact_with(alpha.scheme()).unwrap().encode(),
+            proof.encode()
+        );
+        let decoded =
+            SparseCompactMerkleProof::decode_with(&mut compact.encode().as_ref(), alpha.scheme())
+                .unwrap();
+        assert!(decoded.verify_with(alpha.scheme(), &root, [i; 4], [i; 8]));
+    }
+
+    let nodes = alpha.nodes_store().clone();
+    let values = alpha.values_store().clone();
+    let imported = SparseMerkleTree::<SimpleStore, HmacScheme>::import_with_scheme(
+        nodes,
+        values,
+        root.clone(),
+        hmac(b"alpha"),
+    );
+    for i in 0..16u8 {
+        assert_eq!(
+            imported.get_descend([i; 4]).unwrap(),
+            Some(Bytes::from(vec![i; 8]))
+        );
+    }
+    let view = SparseMerkleTreeView::new_with_scheme(
+        alpha.nodes_store(),
+        alpha.values_store(),
+        root,
+        hmac(b"beta"),
+    );
+    assert_eq!(view.get([0; 4]).unwrap(), None);
+}
+
+#[test]
+fn test_smt_tree_id() {
+    let mut plain = new_sparse_merkle_tree();
+    let mut alpha = SparseMerkleTree::<SimpleStore>::new().with_tree_id(b"alpha");
+    let mut beta = SparseMerkleTree::<SimpleStore>::new().with_tree_id(b"beta");
+    assert_eq!(alpha.tree_id(), Some(&b"alpha"[..]));
+    assert_eq!(plain.tree_id(), None);
+    // Empty subtrees are not bound to the tree.
+    assert_eq!(alpha.root(), plain.root());
+
+    for i in 0..16u8 {
+        plain.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+        alpha.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+        beta.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+    }
+    assert_ne!(alpha.root(), plain.root());
+    assert_ne!(alpha.root(), beta.root());
+    alpha.verify_integrity(alpha.root()).unwrap();
+
+    let root = alpha.root();
+    for i in 0..16u8 {
+        assert_eq!(alpha.get(&[i; 4]).unwrap(), Some(Bytes::from(vec![i; 8])));
+        assert_eq!(
+            alpha.get_descend([i; 4]).unwrap(),
+            Some(Bytes::from(vec![i; 8]))
+        );
+
+        let proof = alpha.prove([i; 4]).unwrap();
+        assert!(proof.verify_for_tree(b"alpha", &root, [i; 4], [i; 8]));
+        assert!(!proof.verify_for_tree(b"beta", &root, [i; 4], [i; 8]));
+        assert!(!proof.verify(&root, [i; 4], [i; 8]));
+
+        // Proofs of a tree with the same leaves do not verify against this one.
+        let other = beta.prove([i; 4]).unwrap();
+        assert!(!other.verify_for_tree(b"alpha", &root, [i; 4], [i; 8]));
+        assert!(!other.verify_for_tree(b"beta", &root, [i; 4], [i; 8]));
+
+        let compact = alpha.prove_compact([i; 4]).unwrap();
+        assert!(compact.verify_for_tree(b"alpha", &root, [i; 4], [i; 8]));
+        assert!(!compact.verify(&root, [i; 4], [i; 8]));
+    }
+    let proof = alpha.prove([16; 4]).unwrap();
+    assert!(proof.verify_for_tree(b"alpha", &root, [16; 4], DEFAULT_VALUE));
+    let proof = alpha.prove_updatable([3; 4]).unwrap();
+    assert!(proof.verify_for_tree(b"alpha", &root, [3; 4], [3; 8]));
+    // The sibling data is bound to the tree too.
+    assert!(proof.compact().is_err());
+    assert!(proof.compact_for_tree(b"beta").is_err());
+    let compact = proof.compact_for_tree(b"alpha").unwrap();
+    assert!(compact.verify_for_tree(b"alpha", &root, [3; 4], [3; 8]));
+
+    let proof = alpha.prove_many([[0; 4], [5; 4], [16; 4]]).unwrap();
+    let entries = [
+        ([0u8; 4], vec![0u8; 8]),
+        ([5; 4], vec![5; 8]),
+        ([16; 4], vec![]),
+    ];
+    assert!(proof.verify_for_tree(b"alpha", &root, entries.clone()));
+    assert!(!proof.verify(&root, entries.clone()));
+    assert!(proof
+        .compact()
+        .unwrap()
+        .verify_for_tree(b"alpha", &root, entries));
+
+    let view = alpha.snapshot();
+    assert_eq!(view.tree_id(), Some(&b"alpha"[..]));
+    assert!(view
+        .prove([7; 4])
+        .unwrap()
+        .verify_for_tree(b"alpha", &root, [7; 4], [7; 8]));
+
+    // The leaves of the whole tree, synced into new stores.
+    let leaves = alpha
+        .iter_leaves(alpha.root())
+        .with_values()
+        .map(|leaf| {
+            let (path, _, value) = leaf.unwrap().into_parts();
+            (path, value.unwrap())
+        })
+        .collect::<Vec<_>>();
+    let proof = alpha
+        .prove_range(Bytes::from(vec![0; 32]), Bytes::from(vec![0xff; 32]))
+        .unwrap();
+    assert!(proof.verify_for_tree(b"alpha", &root, leaves.clone()));
+    assert!(!proof.verify(&root, leaves.clone()));
+    let mut syncer =
+        StateSyncer::<SimpleStore>::new(SimpleStore::new(), SimpleStore::new(), root.clone());
+    assert!(syncer.add_chunk(&proof, leaves.clone()).is_err());
+    let mut syncer = syncer.with_tree_id(b"alpha");
+    syncer.add_chunk(&proof, leaves).unwrap();
+    let synced = syncer.finish().unwrap();
+    assert_eq!(synced.tree_id(), Some(&b"alpha"[..]));
+    for i in 0..16u8 {
+        assert_eq!(synced.get(&[i; 4]).unwrap(), Some(Bytes::from(vec![i; 8])));
+    }
+}
+
+#[test]
+fn test_smt_btree_store() {
+    let mut smt = SparseMerkleTree::<crate::BTreeStore<sha2::Sha256>>::new();
+    let mut expected = new_sparse_merkle_tree();
+    for i in 0..16u8 {
+        smt.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+        expected.update(&[i; 4], Bytes::from(vec![i; 8])).unwrap();
+    }
+    assert_eq!(smt.root(), expected.root());
+    assert_eq!(smt.nodes_store().len(), expected.nodes_store().len());
+
+    // Entries are iterated in key order.
+    let keys = smt.nodes_store().iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert!(keys.windows(2).all(|w| w[0] < w[1]));
+
+    let mut store = crate::BTreeStore::<sha2::Sha256>::new();
+    assert!(store.is_empty());
+    assert_eq!(store.remove(b"key"), Err(crate::StoreError::NotFound));
+}
+
+/// Golden vectors of Diem's sparse Merkle tree hashing, generated by
+/// `ci/diem-vectors` with diem-crypto and diem-types 0.0.3, published from diem/diem
+/// commit 99524bbe3c5a015d1d46b12c45a88216cec3575f. Keys and values are hashed with
+/// plain SHA3-256, as by `DiemScheme`.
+#[cfg(feature = "diem")]
+#[test]
+fn test_smt_diem_scheme() {
+    use crate::DiemScheme;
+
+    fn hex(data: &[u8]) -> String {
+        data.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    const PLACEHOLDER: &str = "5350415253455f4d45524b4c455f504c414345484f4c4445525f484153480000";
+    let entries: [(&[u8], &[u8]); 4] = [
+        (b"alice", b"100"),
+        (b"bob", b"200"),
+        (b"carol", b"300"),
+        (b"dave", b"400"),
+    ];
+
+    let mut smt = SparseMerkleTree::<SimpleStore, DiemScheme>::new();
+    assert_eq!(hex(&smt.root()), PLACEHOLDER);
+    smt.update(entries[0].0, Bytes::from(entries[0].1)).unwrap();
+    assert_eq!(
+        hex(&smt.root()),
+        "53f0d5c621c64ef70abaf98d750635eff3adcc94b9d7f5e387fcf9fd94ae84dc"
+    );
+    for (key, value) in &entries[1..] {
+        smt.update(key, Bytes::copy_from_slice(value)).unwrap();
+    }
+    let root = smt.root();
+    assert_eq!(
+        hex(&root),
+        "20707a93e891c93e67539419ac9414ba280c321b47ad2ceba992990a78f18fcd"
+    );
+
+    // Membership.
+    let proof = smt.prove(b"alice").unwrap();
+    assert!(proof.verify(&root, b"alice", b"100"));
+    let diem = proof.to_diem(b"alice", b"100").unwrap();
+    let (path, value_hash) = diem.leaf().unwrap();
+    assert_eq!(
+        hex(path),
+        "a7dcef9aef26202fce82a7c7d6672afb3a149db207d90a07e437d5abc7fc99ed"
+    );
+    assert_eq!(
+        hex(value_hash),
+        "46b55626ab805350ea5f08f3592bd81298c12f2fee1d6040d1b8b3c7b490d966"
+    );
+    assert_eq!(
+        diem.siblings().iter().map(|s| hex(s)).collect::<Vec<_>>(),
+        [
+            "f26316a11d3590dd7312a5ad2b43ff605b5381ce0fc06f9c9870c91e69d955b1",
+            "869018fb5964269a572fc610a9b97a9d9aaaf1c1352c644d6040dbc9651b6fb2",
+            PLACEHOLDER,
+            PLACEHOLDER,
+        ]
+    );
+    assert!(diem.verify(&root, b"alice", b"100"));
+    assert!(diem.verify_by_hash(&root, path, Some(value_hash)));
+    assert!(!diem.verify(&root, b"alice", b"101"));
+    assert!(!diem.verify(&root, b"alice", DEFAULT_VALUE));
+
+    // Non-membership, ending in an empty subtree.
+    let proof = smt.prove(b"eve").unwrap();
+    let diem = proof.to_diem(b"eve", DEFAULT_VALUE).unwrap();
+    assert!(diem.leaf().is_none());
+    assert_eq!(
+        diem.siblings().iter().map(|s| hex(s)).collect::<Vec<_>>(),
+        [
+            "fa02358574cdeb8ed32bdc53e7f9853cfbb9630014bb1cdd8560fe6fdbc263ef",
+            PLACEHOLDER,
+        ]
+    );
+    assert!(diem.verify(&root, b"eve", DEFAULT_VALUE));
+    assert!(!diem.verify(&root, b"eve", b"500"));
+
+    // Non-membership, ending in the leaf of another key.
+    let proof = smt.prove(b"key11").unwrap();
+    let diem = proof.to_diem(b"key11", DEFAULT_VALUE).unwrap();
+    let (path, value_hash) = diem.leaf().unwrap();
+    assert_eq!(
+        hex(path),
+        "9255386a0faee0205777b798ba4fc6c4b2553be139e4ba27339d4722a471a632"
+    );
+    assert_eq!(
+        hex(value_hash),
+        "c652ab9fc1523c5ce7d5c5fd7d061b38245fd291c8b123d434035c9041ce2460"
+    );
+    assert_eq!(
+        diem.siblings().iter().map(|s| hex(s)).collect::<Vec<_>>(),
+        [
+            "33d0c1384c1e8aeb574ad02a669431c3c7b9bd4840f138d61988e138e524d2be",
+            PLACEHOLDER,
+            PLACEHOLDER,
+        ]
+    );
+    assert!(diem.verify(&root, b"key11", DEFAULT_VALUE));
+    assert!(!diem.verify(&root, b"carol", DEFAULT_VALUE));
+    assert!(proof.to_diem(b"key11", b"1").is_err());
+}
+
+#[cfg(feature = "file-store")]
+#[test]
+fn test_smt_file_store() {
+    use crate::FileStore;
+    use std::io::Write;
+
+    type Store = FileStore<sha2::Sha256>;
+    let dir = tempfile::tempdir().unwrap();
+    let (nodes_path, values_path) = (dir.path().join("nodes"), dir.path().join("values"));
+    let open = || {
+        SparseMerkleTree::<Store>::new_with_stores(
+            Store::open(&nodes_path).unwrap(),
+            Store::open(&values_path).unwrap(),
+        )
+    };
+
+    let mut smt = open();
+    let mut expected = new_sparse_merkle_tree();
+    let keys = (0..32u8).map(|i| vec![i; 4]).collect::<Vec<_>>();
+    for key in &keys {
+        smt.update(key, Bytes::from(key.repeat(2))).unwrap();
+        expected.update(key, Bytes::from(key.repeat(2))).unwrap();
+    }
+    for key in &keys[..8] {
+        smt.remove(key).unwrap();
+        expected.remove(key).unwrap();
+    }
+    let root = smt.root();
+    assert_eq!(root, expected.root());
+    assert_eq!(smt.nodes_store().len(), expected.nodes.data.len());
+    drop(smt);
+
+    // The index is rebuilt from the log.
+    let mut smt = open();
+    smt.set_root(root.clone());
+    smt.verify_integrity(root.clone()).unwrap();
+    assert_eq!(smt.get(&keys[8]).unwrap(), expected.get(&keys[8]).unwrap());
+    assert_eq!(smt.get(&keys[0]).unwrap(), None);
+    let log_len = smt.nodes_store().log_len();
+
+    // An uncommitted batch and a torn record are truncated.
+    let mut batch = WriteBatch::new();
+    batch.set(Bytes::from_static(b"key"), Bytes::from_static(b"value"));
+    smt.nodes_store_mut().begin_batch().unwrap();
+    smt.nodes_store_mut().write_batch(batch).unwrap();
+    assert!(smt.nodes_store().contains(b"key").unwrap());
+    drop(smt);
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&nodes_path)
+        .unwrap();
+    file.write_all(&[0, 3, 0, 0, 0, 200]).unwrap();
+    drop(file);
+    let mut smt = open();
+    smt.set_root(root.clone());
+    assert!(!smt.nodes_store().contains(b"key").unwrap());
+    assert_eq!(smt.nodes_store().log_len(), log_len);
+    smt.verify_integrity(root.clone()).unwrap();
+
+    // So is a last record whose checksum does not match, here the commit record of
+    // a write.
+    smt.nodes_store_mut()
+        .set(Bytes::from_static(b"key"), Bytes::from_static(b"value"))
+        .unwrap();
+    drop(smt);
+    let mut log = std::fs::read(&nodes_path).unwrap();
+    *log.last_mut().unwrap() ^= 1;
+    std::fs::write(&nodes_path, &log).unwrap();
+    let mut smt = open();
+    smt.set_root(root.clone());
+    assert!(!smt.nodes_store().contains(b"key").unwrap());
+    assert_eq!(smt.nodes_store().log_len(), log_len);
+    smt.verify_integrity(root.clone()).unwrap();
+
+    // Compaction is refused during a batch.
+    smt.nodes_store_mut().begin_batch().unwrap();
+    assert!(smt.nodes_store_mut().compact().is_err());
+    smt.nodes_store_mut().commit().unwrap();
+
+    // An aborted batch is truncated, and the keys it wrote or removed are restored.
+    let log_len = smt.nodes_store().log_len();
+    let removed = root.clone();
+    let mut batch = WriteBatch::new();
+    batch.set(Bytes::from_static(b"key"), Bytes::from_static(b"value"));
+    batch.remove(removed.clone());
+    smt.nodes_store_mut().begin_batch().unwrap();
+    smt.nodes_store_mut().write_batch(batch).unwrap();
+    assert!(!smt.nodes_store().contains(&removed).unwrap());
+    smt.nodes_store_mut().abort_batch().unwrap();
+    assert!(!smt.nodes_store().contains(b"key").unwrap());
+    assert!(smt.nodes_store().contains(&removed).unwrap());
+    assert_eq!(smt.nodes_store().log_len(), log_len);
+    assert_eq!(std::fs::metadata(&nodes_path).unwrap().len(), log_len);
+    smt.verify_integrity(root.clone()).unwrap();
+
+    // Compaction drops removed and overwritten values.
+    smt.nodes_store_mut().compact().unwrap();
+    smt.values_store_mut().compact().unwrap();
+    assert!(smt.nodes_store().log_len() < log_len);
+    smt.update(&keys[0], Bytes::from_static(b"testValue"))
+        .unwrap();
+    expected
+        .update(&keys[0], Bytes::from_static(b"testValue"))
+        .unwrap();
+    let root = smt.root();
+    drop(smt);
+    let mut smt = open();
+    smt.set_root(root.clone());
+    smt.verify_integrity(root).unwrap();
+    assert_eq!(smt.root(), expected.root());
+    assert_eq!(
+        smt.get(&keys[0]).unwrap(),
+        Some(Bytes::from_static(b"testValue"))
+    );
+    drop(smt);
+
+    // A bad record followed by others is corruption, and is not truncated, whether
+    // its key or its length is corrupted. The first record follows the magic, and
+    // its key follows its tag, lengths and header checksum.
+    let log = std::fs::read(&nodes_path).unwrap();
+    for (position, mask) in [(8 + 13, 1), (8 + 4, 0x80)] {
+        let mut corrupted = log.clone();
+        corrupted[position] ^= mask;
+        std::fs::write(&nodes_path, &corrupted).unwrap();
+        let err = Store::open(&nodes_path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(&nodes_path).unwrap(), corrupted);
+    }
+}
+
+#[test]
+fn test_smt_node_cache() {
+    let mut smt = new_sparse_merkle_tree().with_node_cache(16);
+    let mut expected = new_sparse_merkle_tree();
+    assert_eq!(expected.node_cache_stats(), None);
+    let keys = (0..64u8).map(|i| vec![i; 4]).collect::<Vec<_>>();
+    for key in &keys {
+        smt.update(key, Bytes::from(key.repeat(2))).unwrap();
+        expected.update(key, Bytes::from(key.repeat(2))).unwrap();
+    }
+    assert_eq!(smt.root(), expected.root());
+    smt.clear_node_cache();
+    assert_eq!(
+        smt.node_cache_stats(),
+        Some(NodeCacheStats {
+            capacity: 16,
+            ..Default::default()
+        })
+    );
+
+    // The second proof of a key is served from the cache.
+    let proof = smt.prove(&keys[7]).unwrap().encode();
+    let misses = smt.node_cache_stats().unwrap().misses;
+    assert!(misses > 0);
+    assert_eq!(smt.prove(&keys[7]).unwrap().encode(), proof);
+    let stats = smt.node_cache_stats().unwrap();
+    assert_eq!(stats.misses, misses);
+    assert!(stats.hits >= misses);
+    assert_eq!(proof, expected.prove(&keys[7]).unwrap().encode());
+
+    // The cache is bounded and shared with snapshots.
+    for key in &keys {
+        assert_eq!(
+            smt.snapshot().get(key).unwrap(),
+            expected.get_descend(key).unwrap()
+        );
+        assert_eq!(
+            smt.prove(key).unwrap().encode(),
+            expected.prove(key).unwrap().encode()
+        );
+    }
+    let stats = smt.node_cache_stats().unwrap();
+    assert_eq!(stats.len, 16);
+    assert!(stats.hits > misses);
+
+    // Nodes removed by the tree are evicted.
+    let old_root = smt.root();
+    smt.prove(&keys[0]).unwrap();
+    smt.remove(&keys[0]).unwrap();
+    expected.remove(&keys[0]).unwrap();
+    assert_eq!(smt.root(), expected.root());
+    assert!(matches!(
+        smt.prove_for_root(&keys[0], old_root),
+        Err(TreeError::MissingNode { .. })
+    ));
+    for key in &keys {
+        assert_eq!(
+            smt.get_descend(key).unwrap(),
+            expected.get_descend(key).unwrap()
+        );
+    }
+}
+
 // // Test all tree operations in bulk.
 // #[test]
 // fn test_sparse_merkle_tree() {
@@ -0,0 +1,112 @@
+use super::SparseMerkleTree;
+use crate::{tree_hasher::TreeHasher, KVStore, TreeError, TreeHashScheme};
+use alloc::vec::Vec;
+use bytes::Bytes;
+
+/// Prefix of the keys under which versioned trees also store values
+/// content-addressed (by value hash) in the values store. The prefix makes these
+/// keys one byte longer than a path, so they can never collide with the keys of the
+/// latest values.
+const VALUE_KEY_PREFIX: u8 = 0;
+
+/// Prefix of the keys under which the number of leaves referencing each
+/// content-addressed value is stored in the values store.
+const VALUE_REFS_KEY_PREFIX: u8 = 1;
+
+/// Returns the key of the content-addressed value with the given hash.
+#[inline]
+pub(super) fn value_key(value_hash: &[u8]) -> Bytes {
+    prefixed_key(VALUE_KEY_PREFIX, value_hash)
+}
+
+/// Returns the key of the number of leaves referencing the content-addressed value
+/// with the given hash.
+#[inline]
+pub(super) fn value_refs_key(value_hash: &[u8]) -> Bytes {
+    prefixed_key(VALUE_REFS_KEY_PREFIX, value_hash)
+}
+
+/// Decodes the number of leaves referencing a content-addressed value.
+#[inline]
+pub(super) fn decode_value_refs(refs: Option<Bytes>) -> u64 {
+    refs.and_then(|refs| refs.as_ref().try_into().ok())
+        .map_or(0, u64::from_be_bytes)
+}
+
+/// Reads the value of a leaf from the values store. The latest value of the leaf's
+/// path is returned if it has the leaf's value hash, otherwise the value is looked
+/// up by its hash, which only versioned trees store.
+pub(super) fn read_leaf_value<S: KVStore, H: TreeHashScheme>(
+    th: &TreeHasher<H>,
+    values: &S,
+    path: &[u8],
+    value_hash: &[u8],
+) -> Result<Option<Bytes>, TreeError<S::Error>> {
+    match values.get(path).map_err(TreeError::Store)? {
+        Some(value) if th.digest(&value) == value_hash => Ok(Some(value)),
+        _ => values.get(&value_key(value_hash)).map_err(TreeError::Store),
+    }
+}
+
+#[inline]
+pub(super) fn prefixed_key(prefix: u8, value_hash: &[u8]) -> Bytes {
+    let mut key = Vec::with_capacity(1 + value_hash.len());
+    key.push(prefix);
+    key.extend_from_slice(value_hash);
+    key.into()
+}
+
+impl<S: KVStore, H: TreeHashScheme> SparseMerkleTree<S, H> {
+    /// Gets the value of a key from the tree at a specific root, by descending the
+    /// nodes store from that root.
+    ///
+    /// Unlike `get`, which always returns the latest value of the key, this returns
+    /// the value committed under `root`, as long as the nodes of `root` are still in
+    /// the nodes store. Values which are no longer the latest value of their key are
+    /// only kept by versioned trees (see `new_versioned`).
+    pub fn get_for_root(
+        &self,
+        key: impl AsRef<[u8]>,
+        root: Bytes,
+    ) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        self.snapshot_at(root).get(key)
+    }
+
+    /// Returns the number of leaves referencing the content-addressed value.
+    fn value_refs(&self, key: &[u8]) -> Result<u64, TreeError<S::Error>> {
+        self.read_value(key).map(decode_value_refs)
+    }
+
+    /// Records a new leaf referencing the content-addressed value.
+    pub(super) fn retain_value(&mut self, value_hash: &[u8]) -> Result<(), TreeError<S::Error>> {
+        let key = value_refs_key(value_hash);
+        let refs = self.value_refs(&key)?;
+        self.write_value(key, Bytes::copy_from_slice(&(refs + 1).to_be_bytes()));
+        Ok(())
+    }
+
+    /// Records a removed leaf referencing the content-addressed value, removing the
+    /// value once it is no longer referenced.
+    pub(super) fn release_value(&mut self, value_hash: &[u8]) -> Result<(), TreeError<S::Error>> {
+        let key = value_refs_key(value_hash);
+        let refs = self.value_refs(&key)?;
+        if refs > 1 {
+            self.write_value(key, Bytes::copy_from_slice(&(refs - 1).to_be_bytes()));
+            return Ok(());
+        }
+
+        self.remove_value(&key)?;
+        self.remove_value(&value_key(value_hash))
+    }
+
+    /// Writes the latest value of a path to the values store. Versioned trees also
+    /// write the value content-addressed by its hash, so that it outlives the next
+    /// update of the path.
+    #[inline]
+    pub(super) fn put_value(&mut self, path: Bytes, value_hash: &[u8], value: Bytes) {
+        if self.is_versioned() {
+            self.write_value(value_key(value_hash), value.clone());
+        }
+        self.write_value(path, value);
+    }
+}
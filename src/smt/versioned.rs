@@ -0,0 +1,540 @@
+use super::{values::prefixed_key, SparseMerkleTree};
+use crate::{tree_hasher::TreeHasher, KVStore, TreeError, TreeHashScheme};
+use alloc::{
+    collections::{BTreeMap, BTreeSet},
+    vec::Vec,
+};
+use bytes::Bytes;
+
+/// Prefix of the key of the record of a versioned tree in the values store, which
+/// holds its latest version and the first version with a root or stale nodes. Like
+/// the keys of the version records below, it is shorter than the path of any hash
+/// a tree is used with, so it cannot collide with the keys of the latest values.
+const VERSIONS_KEY_PREFIX: u8 = 2;
+
+/// Prefix of the keys under which the root of each retained version is stored in
+/// the values store.
+const VERSION_ROOT_KEY_PREFIX: u8 = 3;
+
+/// Prefix of the keys under which the stale nodes orphaned at each version are
+/// stored, concatenated, in the values store.
+const STALE_NODES_KEY_PREFIX: u8 = 4;
+
+#[inline]
+fn versions_key() -> Bytes {
+    prefixed_key(VERSIONS_KEY_PREFIX, &[])
+}
+
+#[inline]
+fn version_root_key(version: u64) -> Bytes {
+    prefixed_key(VERSION_ROOT_KEY_PREFIX, &version.to_be_bytes())
+}
+
+#[inline]
+fn stale_nodes_key(version: u64) -> Bytes {
+    prefixed_key(STALE_NODES_KEY_PREFIX, &version.to_be_bytes())
+}
+
+/// Book-keeping of a versioned tree.
+///
+/// Every update committed through `update`, `remove` or `update_batch` creates a
+/// new version. Nodes orphaned by an update are kept in the nodes store, and are
+/// recorded in the stale node index under the version at which they stopped being
+/// reachable from the latest root.
+///
+/// The retained roots and the stale node index are written to the values store in
+/// the same batch as the nodes of each update, so `open_versioned` can reopen the
+/// tree from its stores.
+#[derive(Debug, Clone)]
+pub(super) struct Versions {
+    /// The latest committed version.
+    pub(super) version: u64,
+    /// The root of every retained version.
+    pub(super) roots: BTreeMap<u64, Bytes>,
+    /// Nodes orphaned by the update in progress.
+    pub(super) orphaned: BTreeSet<Bytes>,
+    /// The version at which each stale node was orphaned.
+    pub(super) stale_since: BTreeMap<Bytes, u64>,
+    /// The stale nodes orphaned at each version.
+    pub(super) stale: BTreeMap<u64, BTreeSet<Bytes>>,
+    /// The versions whose root or stale nodes changed since they were last written
+    /// to the values store.
+    dirty: BTreeSet<u64>,
+    /// The state before the operation in progress, see `begin`.
+    undo: Option<Undo>,
+}
+
+/// The state of the versions before an operation, restored by `rollback` if the
+/// operation fails.
+#[derive(Debug, Clone)]
+struct Undo {
+    version: u64,
+    orphaned: BTreeSet<Bytes>,
+    dirty: BTreeSet<u64>,
+    /// The root and stale nodes, before the operation, of each version it changed.
+    changed: BTreeMap<u64, (Option<Bytes>, Option<BTreeSet<Bytes>>)>,
+}
+
+impl Versions {
+    fn new(root: Bytes) -> Self {
+        let mut roots = BTreeMap::new();
+        roots.insert(0, root);
+        Self {
+            version: 0,
+            roots,
+            orphaned: BTreeSet::new(),
+            stale_since: BTreeMap::new(),
+            stale: BTreeMap::new(),
+            dirty: core::iter::once(0).collect(),
+            undo: None,
+        }
+    }
+
+    /// Starts an operation, which is undone by `rollback` if it fails, or kept by
+    /// `end`. Only the versions changed by the operation are saved.
+    pub(super) fn begin(&mut self) {
+        self.undo = Some(Undo {
+            version: self.version,
+            orphaned: self.orphaned.clone(),
+            dirty: self.dirty.clone(),
+            changed: BTreeMap::new(),
+        });
+    }
+
+    /// Ends the operation in progress, keeping its changes.
+    #[inline]
+    pub(super) fn end(&mut self) {
+        self.undo = None;
+    }
+
+    /// Undoes the changes of the operation in progress.
+    pub(super) fn rollback(&mut self) {
+        let undo = match self.undo.take() {
+            Some(undo) => undo,
+            None => return,
+        };
+
+        // A node may have moved between the stale nodes of two changed versions, so
+        // the current ones are all dropped before the saved ones are restored.
+        for version in undo.changed.keys() {
+            for hash in self.stale.remove(version).into_iter().flatten() {
+                self.stale_since.remove(&hash);
+            }
+        }
+        for (version, (root, stale)) in undo.changed {
+            match root {
+                Some(root) => self.roots.insert(version, root),
+                None => self.roots.remove(&version),
+            };
+            if let Some(stale) = stale {
+                for hash in &stale {
+                    self.stale_since.insert(hash.clone(), version);
+                }
+                self.stale.insert(version, stale);
+            }
+        }
+        self.version = undo.version;
+        self.orphaned = undo.orphaned;
+        self.dirty = undo.dirty;
+    }
+
+    /// Marks a version whose root or stale nodes are about to change, saving them
+    /// for `rollback` first.
+    fn touch(&mut self, version: u64) {
+        if let Some(undo) = &mut self.undo {
+            undo.changed.entry(version).or_insert_with(|| {
+                (
+                    self.roots.get(&version).cloned(),
+                    self.stale.get(&version).cloned(),
+                )
+            });
+        }
+        self.dirty.insert(version);
+    }
+
+    /// Decodes the version records read from the values store by `read`, or returns
+    /// `None` if there are none.
+    fn read<E>(
+        path_size: usize,
+        mut read: impl FnMut(&Bytes) -> Result<Option<Bytes>, TreeError<E>>,
+    ) -> Result<Option<Self>, TreeError<E>> {
+        let record = match read(&versions_key())? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        let (latest, first) = match (record.get(..8), record.get(8..)) {
+            (Some(latest), Some(first)) if first.len() == 8 => (
+                u64::from_be_bytes(latest.try_into().unwrap()),
+                u64::from_be_bytes(first.try_into().unwrap()),
+            ),
+            _ => return Err(TreeError::CorruptVersions),
+        };
+        if first > latest {
+            return Err(TreeError::CorruptVersions);
+        }
+
+        let mut versions = Self {
+            version: latest,
+            roots: BTreeMap::new(),
+            orphaned: BTreeSet::new(),
+            stale_since: BTreeMap::new(),
+            stale: BTreeMap::new(),
+            dirty: BTreeSet::new(),
+            undo: None,
+        };
+        for version in first..=latest {
+            if let Some(root) = read(&version_root_key(version))? {
+                if root.len() != path_size {
+                    return Err(TreeError::CorruptVersions);
+                }
+                versions.roots.insert(version, root);
+            }
+            if let Some(nodes) = read(&stale_nodes_key(version))? {
+                if nodes.is_empty() || nodes.len() % path_size != 0 {
+                    return Err(TreeError::CorruptVersions);
+                }
+                let stale = (0..nodes.len())
+                    .step_by(path_size)
+                    .map(|start| nodes.slice(start..start + path_size))
+                    .collect::<BTreeSet<_>>();
+                for hash in &stale {
+                    versions.stale_since.insert(hash.clone(), version);
+                }
+                versions.stale.insert(version, stale);
+            }
+        }
+        if !versions.roots.contains_key(&latest) {
+            return Err(TreeError::CorruptVersions);
+        }
+        Ok(Some(versions))
+    }
+
+    /// Returns the writes updating the records of the versions changed since the
+    /// last call, with `None` for records to remove.
+    fn take_records(&mut self) -> Vec<(Bytes, Option<Bytes>)> {
+        let dirty = core::mem::take(&mut self.dirty);
+        if dirty.is_empty() {
+            return Vec::new();
+        }
+
+        let mut records = Vec::with_capacity(2 * dirty.len() + 1);
+        for version in dirty {
+            let root = self.roots.get(&version).cloned();
+            records.push((version_root_key(version), root));
+            let stale = self.stale.get(&version).map(|stale| {
+                stale
+                    .iter()
+                    .flat_map(|hash| hash.iter().copied())
+                    .collect::<Vec<_>>()
+                    .into()
+            });
+            records.push((stale_nodes_key(version), stale));
+        }
+
+        // The latest version always has a root, so the first is at most the latest.
+        let first = self
+            .stale
+            .keys()
+            .chain(self.roots.keys())
+            .min()
+            .copied()
+            .unwrap_or(self.version);
+        let mut record = Vec::with_capacity(16);
+        record.extend_from_slice(&self.version.to_be_bytes());
+        record.extend_from_slice(&first.to_be_bytes());
+        records.push((versions_key(), Some(record.into())));
+        records
+    }
+
+    /// Records that the node is no longer reachable from the latest root.
+    #[inline]
+    pub(super) fn orphan(&mut self, hash: Bytes) {
+        self.orphaned.insert(hash);
+    }
+
+    /// Records that the node is reachable from the latest root again.
+    #[inline]
+    pub(super) fn revive(&mut self, hash: &[u8]) {
+        self.orphaned.remove(hash);
+        self.forget(hash);
+    }
+
+    /// Removes the node from the stale node index.
+    fn forget(&mut self, hash: &[u8]) {
+        if let Some(&version) = self.stale_since.get(hash) {
+            self.touch(version);
+            self.stale_since.remove(hash);
+            if let Some(stale) = self.stale.get_mut(&version) {
+                stale.remove(hash);
+                if stale.is_empty() {
+                    self.stale.remove(&version);
+                }
+            }
+        }
+    }
+
+    /// Returns a node which became stale at or before the given version.
+    fn next_stale(&self, version: u64) -> Option<Bytes> {
+        self.stale
+            .range(..=version)
+            .next()
+            .and_then(|(_, stale)| stale.iter().next().cloned())
+    }
+
+    /// Drops the roots of the versions before `version`.
+    fn drop_roots_before(&mut self, version: u64) {
+        let dropped = self.roots.range(..version).map(|(&version, _)| version);
+        for version in dropped.collect::<Vec<_>>() {
+            self.touch(version);
+        }
+        self.roots = self.roots.split_off(&version);
+    }
+
+    /// Commits a new version with the given root, marking the nodes orphaned by
+    /// the update as stale since that version.
+    fn commit(&mut self, root: Bytes) {
+        self.touch(self.version + 1);
+        self.version += 1;
+        self.roots.insert(self.version, root);
+
+        let orphaned = core::mem::take(&mut self.orphaned);
+        if orphaned.is_empty() {
+            return;
+        }
+        for hash in &orphaned {
+            self.stale_since.insert(hash.clone(), self.version);
+        }
+        self.stale.insert(self.version, orphaned);
+    }
+}
+
+impl<S: KVStore + Default, H: TreeHashScheme + Default> SparseMerkleTree<S, H> {
+    /// Create a new versioned sparse merkle tree.
+    ///
+    /// See `new_versioned_with_stores` for details.
+    pub fn new_versioned() -> Self {
+        Self::new_versioned_with_stores(S::default(), S::default())
+    }
+}
+
+impl<S: KVStore, H: TreeHashScheme> SparseMerkleTree<S, H> {
+    /// Create a versioned sparse merkle tree based on the given stores.
+    ///
+    /// A versioned tree does not remove the nodes orphaned by an update from the
+    /// nodes store, so historical roots remain readable by `get_for_root` and
+    /// `prove_for_root`. Each call to `update`, `remove` or `update_batch` commits
+    /// a new version, starting from version 0 for the empty tree.
+    ///
+    /// Nodes orphaned by the `*_for_root` methods may still be reachable from other
+    /// roots, so they are kept but not recorded as stale.
+    ///
+    /// The version book-keeping (retained roots and the stale node index) is written
+    /// to the values store along with each update, and a versioned tree is reopened
+    /// from its stores by `open_versioned`.
+    #[inline]
+    pub fn new_versioned_with_stores(nodes_store: S, values_store: S) -> Self
+    where
+        H: Default,
+    {
+        Self::new_versioned_with_scheme(nodes_store, values_store, H::default())
+    }
+
+    /// Create a versioned sparse merkle tree based on the given stores, hashed with
+    /// the given scheme instance.
+    #[inline]
+    pub fn new_versioned_with_scheme(nodes_store: S, values_store: S, scheme: H) -> Self {
+        let mut tree = Self::new_with_scheme(nodes_store, values_store, scheme);
+        tree.versions = Some(Versions::new(tree.root()));
+        tree
+    }
+
+    /// Reopens a versioned sparse merkle tree from the given stores, at the latest
+    /// version committed to them. Stores without version records, e.g. empty ones,
+    /// open as a new versioned tree.
+    #[inline]
+    pub fn open_versioned(nodes_store: S, values_store: S) -> Result<Self, TreeError<S::Error>>
+    where
+        H: Default,
+    {
+        Self::open_versioned_with_scheme(nodes_store, values_store, H::default())
+    }
+
+    /// Reopens a versioned sparse merkle tree from the given stores, hashed with the
+    /// given scheme instance.
+    pub fn open_versioned_with_scheme(
+        nodes_store: S,
+        values_store: S,
+        scheme: H,
+    ) -> Result<Self, TreeError<S::Error>> {
+        let mut tree = Self::new_versioned_with_scheme(nodes_store, values_store, scheme);
+        let versions = Versions::read(TreeHasher::<H>::path_size(), |key| {
+            tree.values.get(key).map_err(TreeError::Store)
+        })?;
+        if let Some(versions) = versions {
+            tree.root = versions.roots[&versions.version].clone();
+            tree.versions = Some(versions);
+        }
+        Ok(tree)
+    }
+
+    /// Returns true if this is a versioned tree.
+    #[inline]
+    pub fn is_versioned(&self) -> bool {
+        self.versions.is_some()
+    }
+
+    /// Returns the latest committed version, which is always 0 for trees that are not
+    /// versioned.
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.versions
+            .as_ref()
+            .map_or(0, |versions| versions.version)
+    }
+
+    /// Returns the root committed at the given version, if the version is retained.
+    #[inline]
+    pub fn root_at_version(&self, version: u64) -> Option<Bytes> {
+        self.versions
+            .as_ref()
+            .and_then(|versions| versions.roots.get(&version).cloned())
+    }
+
+    /// Returns the hashes of the nodes which became stale at the given version, that
+    /// is the nodes only reachable from the roots of earlier versions.
+    pub fn stale_nodes_at_version(&self, version: u64) -> Vec<Bytes> {
+        self.versions
+            .as_ref()
+            .and_then(|versions| versions.stale.get(&version))
+            .map(|stale| stale.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Prunes the versions before `version`, removing at most `limit` nodes from the
+    /// nodes store. Returns `true` once every node only reachable from the pruned
+    /// versions has been removed, otherwise the call should be repeated (e.g. on the
+    /// next block) to continue pruning.
+    ///
+    /// The roots of the pruned versions are dropped by the first call, so they are no
+    /// longer readable even if pruning is not finished. The latest version is always
+    /// retained. Content-addressed values are removed once no remaining leaf
+    /// references them. Does nothing for trees that are not versioned.
+    pub fn prune_versions_before(
+        &mut self,
+        version: u64,
+        limit: usize,
+    ) -> Result<bool, TreeError<S::Error>> {
+        let version = match &self.versions {
+            Some(versions) => version.min(versions.version),
+            None => return Ok(true),
+        };
+
+        // Nodes which became stale at a version are only reachable from the roots
+        // of earlier versions, so the nodes stale at or before `version` can go.
+        self.batched(|tree| {
+            if let Some(versions) = &mut tree.versions {
+                versions.drop_roots_before(version);
+            }
+            let mut pruned = 0;
+            while let Some(hash) = tree
+                .versions
+                .as_ref()
+                .and_then(|versions| versions.next_stale(version))
+            {
+                if pruned == limit {
+                    return Ok(false);
+                }
+                tree.prune_node(&hash)?;
+                pruned += 1;
+            }
+            Ok(true)
+        })
+    }
+
+    /// Removes a stale node from the nodes store and the stale node index.
+    fn prune_node(&mut self, hash: &Bytes) -> Result<(), TreeError<S::Error>> {
+        let data = self.read_node(hash)?;
+        if let Some(data) = &data {
+            if !TreeHasher::<H>::is_well_formed(data) {
+                return Err(TreeError::CorruptNode { hash: hash.clone() });
+            }
+            if TreeHasher::<H>::is_leaf(&Some(data)) {
+                let (_, value_hash) = Self::parse_leaf(hash, data)?;
+                self.release_value(value_hash)?;
+            }
+            self.take_node(hash)?;
+        }
+
+        if let Some(versions) = &mut self.versions {
+            versions.forget(hash);
+        }
+        Ok(())
+    }
+
+    /// Commits a new version with the given root. Does nothing if the tree is not
+    /// versioned.
+    pub(super) fn commit_version(&mut self, root: Bytes) {
+        if let Some(versions) = &mut self.versions {
+            versions.commit(root);
+        }
+    }
+
+    /// Writes the records of the versions changed by the operation in progress to
+    /// the values store.
+    pub(super) fn write_versions(&mut self) -> Result<(), TreeError<S::Error>> {
+        let records = match &mut self.versions {
+            Some(versions) => versions.take_records(),
+            None => return Ok(()),
+        };
+        for (key, record) in records {
+            match record {
+                Some(record) => self.write_value(key, record),
+                None => self.remove_value(&key)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the nodes orphaned by the update in progress, keeping them in the
+    /// nodes store.
+    pub(super) fn discard_orphans(&mut self) {
+        if let Some(versions) = &mut self.versions {
+            versions.orphaned.clear();
+        }
+    }
+
+    /// Writes a node to the nodes store.
+    #[inline]
+    pub(super) fn put_node(&mut self, hash: Bytes, data: Bytes) -> Result<(), TreeError<S::Error>> {
+        let versions = match &mut self.versions {
+            Some(versions) => versions,
+            None => {
+                self.write_node(hash, data);
+                return Ok(());
+            }
+        };
+        versions.revive(&hash);
+
+        // Count the leaves referencing each content-addressed value, so that the
+        // value can be removed once it is no longer needed.
+        if TreeHasher::<H>::is_leaf(&Some(&data)) && !self.has_node(&hash)? {
+            let (_, value_hash) = Self::parse_leaf(&hash, &data)?;
+            self.retain_value(value_hash)?;
+        }
+        self.write_node(hash, data);
+        Ok(())
+    }
+
+    /// Removes a node orphaned by an update. Versioned trees keep the node, and
+    /// record it as orphaned instead.
+    #[inline]
+    pub(super) fn remove_node(&mut self, hash: &Bytes) -> Result<(), TreeError<S::Error>> {
+        if let Some(versions) = &mut self.versions {
+            versions.orphan(hash.clone());
+            return Ok(());
+        }
+
+        let data = self.take_node(hash)?;
+        super::changes::checked_node::<H, _>(hash, data).map(|_| ())
+    }
+}
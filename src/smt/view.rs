@@ -0,0 +1,475 @@
+use super::{
+    changes::{self, SideNodesWalk},
+    node_cache,
+    values::read_leaf_value,
+    Leaves, NodeCache, SparseMerkleTree, UpdateResult, RIGHT,
+};
+use crate::{
+    count_common_prefix, get_bit_at_from_msb,
+    proofs::{clear_bit_at_from_msb, in_range, overlap, Overlap},
+    set_bit_at_from_msb,
+    tree_hasher::TreeHasher,
+    KVStore, SparseCompactMerkleMultiProof, SparseCompactMerkleProof, SparseMerkleMultiProof,
+    SparseMerkleProof, SparseMerkleRangeProof, StoreHasher, TreeError, TreeHashScheme,
+};
+use alloc::{vec, vec::Vec};
+use bytes::Bytes;
+
+/// A read-only view of a sparse merkle tree, pinned at a root.
+///
+/// A view borrows the stores of the tree and only reads nodes reachable from its
+/// root, and the values of the leaves of its root.
+///
+/// Views are cheap to create and to clone, and can be sent to other threads if the
+/// stores are `Sync`. Views created by `SparseMerkleTree::snapshot` borrow the
+/// tree, so it cannot be updated while they are alive. To keep serving a root while
+/// the writer produces new roots, create views with `SparseMerkleTreeView::new`
+/// over handles which share the stores with the writer (e.g. stores behind an
+/// `Arc`), and make the writer a versioned tree (see
+/// `SparseMerkleTree::new_versioned`): a tree which is not versioned removes the
+/// nodes orphaned by each update, so its views fail with `TreeError::MissingNode`
+/// once their root has been updated.
+pub struct SparseMerkleTreeView<'a, S: KVStore, H: TreeHashScheme = <S as StoreHasher>::Hasher> {
+    pub(super) th: TreeHasher<H>,
+    nodes: &'a S,
+    pub(super) values: &'a S,
+    root: Bytes,
+    /// The node cache of the tree the view was created from, if enabled.
+    pub(super) node_cache: Option<&'a NodeCache>,
+}
+
+impl<'a, S: KVStore + core::fmt::Debug, H: TreeHashScheme> core::fmt::Debug
+    for SparseMerkleTreeView<'a, S, H>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct(core::any::type_name::<Self>())
+            .field("nodes", &self.nodes)
+            .field("values", &self.values)
+            .field("root", &self.root.as_ref())
+            .field("tree_hasher", &self.th)
+            .finish()
+    }
+}
+
+impl<'a, S: KVStore, H: TreeHashScheme> Clone for SparseMerkleTreeView<'a, S, H> {
+    fn clone(&self) -> Self {
+        Self {
+            th: self.th.clone(),
+            nodes: self.nodes,
+            values: self.values,
+            root: self.root.clone(),
+            node_cache: self.node_cache,
+        }
+    }
+}
+
+impl<'a, S: KVStore, H: TreeHashScheme> SparseMerkleTreeView<'a, S, H> {
+    /// Creates a view of the tree with the given root in the given stores.
+    #[inline]
+    pub fn new(nodes_store: &'a S, values_store: &'a S, root: impl Into<Bytes>) -> Self
+    where
+        H: Default,
+    {
+        Self::new_with_scheme(nodes_store, values_store, root, H::default())
+    }
+
+    /// Creates a view of the tree with the given root in the given stores, hashed
+    /// with the given scheme instance.
+    #[inline]
+    pub fn new_with_scheme(
+        nodes_store: &'a S,
+        values_store: &'a S,
+        root: impl Into<Bytes>,
+        scheme: H,
+    ) -> Self {
+        Self::with_hasher(TreeHasher::new(scheme), nodes_store, values_store, root)
+    }
+
+    #[inline]
+    pub(super) fn with_hasher(
+        th: TreeHasher<H>,
+        nodes_store: &'a S,
+        values_store: &'a S,
+        root: impl Into<Bytes>,
+    ) -> Self {
+        Self {
+            th,
+            nodes: nodes_store,
+            values: values_store,
+            root: root.into(),
+            node_cache: None,
+        }
+    }
+
+    /// Returns the root the view is pinned at
+    #[inline]
+    pub fn root(&self) -> Bytes {
+        self.root.clone()
+    }
+
+    /// Returns the root reference the view is pinned at
+    #[inline]
+    pub fn root_ref(&self) -> &[u8] {
+        &self.root
+    }
+
+    /// Returns the hash scheme instance of the view.
+    #[inline]
+    pub fn scheme(&self) -> &H {
+        self.th.scheme()
+    }
+
+    /// Binds the view to the tree with the given identifier (see
+    /// `SparseMerkleTree::with_tree_id`). Snapshots have the identifier of their tree.
+    #[inline]
+    pub fn with_tree_id(mut self, tree_id: impl AsRef<[u8]>) -> Self {
+        self.th = self.th.with_tree_id(Some(tree_id.as_ref()));
+        self
+    }
+
+    /// Returns the identifier of the tree, if it has one.
+    #[inline]
+    pub fn tree_id(&self) -> Option<&[u8]> {
+        self.th.tree_id()
+    }
+
+    #[inline]
+    fn depth(&self) -> usize {
+        TreeHasher::<H>::path_size() * 8
+    }
+
+    /// Reads a node through the node cache, if enabled.
+    #[inline]
+    fn read_node(&self, hash: &[u8]) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        node_cache::read_through(self.node_cache, self.nodes, hash)
+    }
+
+    /// Reads a node which must be in the nodes store, checking that its data is well
+    /// formed.
+    pub(super) fn get_node(&self, hash: &Bytes) -> Result<Bytes, TreeError<S::Error>> {
+        changes::checked_node::<H, _>(hash, self.read_node(hash)?)
+    }
+
+    /// Gets the value of a key at the root of the view, by descending the nodes
+    /// store from the root.
+    ///
+    /// Errors if the key cannot be reached by descending.
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        let path = self.th.path(key);
+        let UpdateResult {
+            path_nodes,
+            current_data: leaf_data,
+            ..
+        } = self.side_nodes_for_root(path.as_ref(), false)?;
+
+        if path_nodes[0].eq(self.th.placeholder_ref()) {
+            return Ok(None);
+        }
+
+        match leaf_data {
+            Some(leaf_data) => {
+                let (actual_path, value_hash) =
+                    SparseMerkleTree::<S, H>::parse_leaf(&path_nodes[0], &leaf_data)?;
+                if actual_path.ne(path.as_ref()) {
+                    // A different key was found in its place; the key is empty.
+                    return Ok(None);
+                }
+                read_leaf_value(&self.th, self.values, &path, value_hash)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns true if the value at the given key is non-default at the root of the
+    /// view, false otherwise.
+    pub fn contains(&self, key: impl AsRef<[u8]>) -> Result<bool, TreeError<S::Error>> {
+        self.get(key).map(|v| v.is_some())
+    }
+
+    /// Gets the value of a key by descending the tree.
+    ///
+    /// A view always descends from its root, so this is the same as `get`.
+    #[inline]
+    pub fn get_descend(&self, key: impl AsRef<[u8]>) -> Result<Option<Bytes>, TreeError<S::Error>> {
+        self.get(key)
+    }
+
+    /// Returns true if the value at the given key is non-default, false otherwise.
+    ///
+    /// A view always descends from its root, so this is the same as `contains`.
+    #[inline]
+    pub fn has_descend(&self, key: impl AsRef<[u8]>) -> Result<bool, TreeError<S::Error>> {
+        self.contains(key)
+    }
+
+    /// Generates a Merkle proof for a key against the root of the view.
+    ///
+    /// This proof can be used for read-only applications, but should not be used if
+    /// the leaf may be updated (e.g. in a state transition fraud proof). For
+    /// updatable proofs, see `prove_updatable`.
+    pub fn prove(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        self.do_prove(key, false)
+    }
+
+    /// Generates an updatable Merkle proof for a key against the root of the view.
+    pub fn prove_updatable(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        self.do_prove(key, true)
+    }
+
+    /// Generates a compacted Merkle proof for a key against the root of the view.
+    pub fn prove_compact(
+        &self,
+        key: impl AsRef<[u8]>,
+    ) -> Result<SparseCompactMerkleProof<H>, TreeError<S::Error>> {
+        let proof = self.do_prove(key, false)?;
+        proof
+            .compact_into_with(self.th.scheme())
+            .map_err(Into::into)
+    }
+
+    /// Generates a Merkle multi proof for several keys against the root of the view.
+    ///
+    /// Side nodes shared by the paths of the keys are only included once.
+    pub fn prove_many<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<SparseMerkleMultiProof<H>, TreeError<S::Error>> {
+        SparseMerkleTree::<S, H>::check_root(&self.root)?;
+        let mut paths = keys
+            .into_iter()
+            .enumerate()
+            .map(|(idx, key)| (self.th.path_into(key), idx))
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut proof = SparseMerkleMultiProof::new(Vec::new(), Vec::new(), vec![0; paths.len()]);
+        if !paths.is_empty() {
+            self.do_prove_many(self.root(), 0, &paths, &mut proof)?;
+        }
+        Ok(proof)
+    }
+
+    /// Generates a compacted Merkle multi proof for several keys against the root of
+    /// the view.
+    pub fn prove_many_compact<K: AsRef<[u8]>>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+    ) -> Result<SparseCompactMerkleMultiProof<H>, TreeError<S::Error>> {
+        let proof = self.prove_many(keys)?;
+        proof
+            .compact_into_with(self.th.scheme())
+            .map_err(Into::into)
+    }
+
+    /// Walks the subtree rooted at `hash`, which sits at `depth`, along the sorted `paths`.
+    fn do_prove_many(
+        &self,
+        hash: Bytes,
+        depth: usize,
+        paths: &[(Bytes, usize)],
+        proof: &mut SparseMerkleMultiProof<H>,
+    ) -> Result<(), TreeError<S::Error>> {
+        let data = if hash.eq(self.th.placeholder_ref()) {
+            None
+        } else {
+            Some(self.get_node(&hash)?)
+        };
+
+        if data.is_none() || TreeHasher::<H>::is_leaf(&data) {
+            // We've reached the end of all the remaining paths.
+            for (_, idx) in paths {
+                proof.depths[*idx] = depth;
+            }
+
+            match data {
+                Some(data) => {
+                    let (actual_path, _) = SparseMerkleTree::<S, H>::parse_leaf(&hash, &data)?;
+                    if !paths.iter().any(|(path, _)| path.eq(actual_path)) {
+                        // This is a non-membership proof that involves showing a different leaf.
+                        proof.non_membership_leaf_data.push(Some(data));
+                    }
+                }
+                None => proof.non_membership_leaf_data.push(None),
+            }
+            return Ok(());
+        }
+
+        let (left, right) = SparseMerkleTree::<S, H>::parse_node(&hash, &data)?;
+        let split = paths.partition_point(|(path, _)| get_bit_at_from_msb(path, depth) != RIGHT);
+        let (left_paths, right_paths) = paths.split_at(split);
+        if left_paths.is_empty() {
+            proof.side_nodes.push(left);
+        } else {
+            self.do_prove_many(left, depth + 1, left_paths, proof)?;
+        }
+        if right_paths.is_empty() {
+            proof.side_nodes.push(right);
+        } else {
+            self.do_prove_many(right, depth + 1, right_paths, proof)?;
+        }
+        Ok(())
+    }
+
+    /// Generates a Merkle range proof for the leaves whose paths lie in
+    /// `[start_path, end_path]`, against the root of the view.
+    ///
+    /// Returns `TreeError::BadProof` if the paths do not have the length of a path,
+    /// or if `start_path` is greater than `end_path`. The leaves themselves can be
+    /// read with `iter_leaves`.
+    pub fn prove_range(
+        &self,
+        start_path: impl AsRef<[u8]>,
+        end_path: impl AsRef<[u8]>,
+    ) -> Result<SparseMerkleRangeProof<H>, TreeError<S::Error>> {
+        let (start_path, end_path) = (start_path.as_ref(), end_path.as_ref());
+        let path_size = TreeHasher::<H>::path_size();
+        if start_path.len() != path_size || end_path.len() != path_size || start_path > end_path {
+            return Err(TreeError::BadProof);
+        }
+        SparseMerkleTree::<S, H>::check_root(&self.root)?;
+
+        let mut proof = SparseMerkleRangeProof::new(
+            Bytes::copy_from_slice(start_path),
+            Bytes::copy_from_slice(end_path),
+            Vec::new(),
+            Vec::new(),
+            self.depth(),
+            self.depth(),
+        );
+        let mut prefix = vec![0; path_size];
+        self.do_prove_range(self.root(), 0, &mut prefix, &mut proof)?;
+        Ok(proof)
+    }
+
+    /// Walks the subtree rooted at `hash`, which sits at `depth` and whose paths start
+    /// with the first `depth` bits of `prefix`, along the boundaries of the range.
+    fn do_prove_range(
+        &self,
+        hash: Bytes,
+        depth: usize,
+        prefix: &mut Vec<u8>,
+        proof: &mut SparseMerkleRangeProof<H>,
+    ) -> Result<(), TreeError<S::Error>> {
+        match overlap(prefix, depth, &proof.start_path, &proof.end_path) {
+            Overlap::Disjoint => {
+                proof.side_nodes.push(hash);
+                return Ok(());
+            }
+            // The verifier rebuilds this subtree from the leaves of the range.
+            Overlap::Contained => return Ok(()),
+            Overlap::Partial => {}
+        }
+
+        let data = if hash.eq(self.th.placeholder_ref()) {
+            None
+        } else {
+            Some(self.get_node(&hash)?)
+        };
+
+        if data.is_none() || TreeHasher::<H>::is_leaf(&data) {
+            // We've reached the end of the boundary paths passing through this node.
+            if count_common_prefix(prefix, &proof.start_path) >= depth {
+                proof.start_depth = depth;
+            }
+            if count_common_prefix(prefix, &proof.end_path) >= depth {
+                proof.end_depth = depth;
+            }
+
+            match data {
+                Some(data) => {
+                    let (actual_path, _) = SparseMerkleTree::<S, H>::parse_leaf(&hash, &data)?;
+                    if !in_range(actual_path, &proof.start_path, &proof.end_path) {
+                        // This leaf is outside of the range.
+                        proof.boundary_leaf_data.push(Some(data));
+                    }
+                }
+                None => proof.boundary_leaf_data.push(None),
+            }
+            return Ok(());
+        }
+
+        let (left, right) = SparseMerkleTree::<S, H>::parse_node(&hash, &data)?;
+        self.do_prove_range(left, depth + 1, prefix, proof)?;
+        set_bit_at_from_msb(prefix, depth);
+        let result = self.do_prove_range(right, depth + 1, prefix, proof);
+        clear_bit_at_from_msb(prefix, depth);
+        result
+    }
+
+    /// Returns an iterator over the leaves of the tree at the root of the view, in
+    /// path order (see `SparseMerkleTree::iter_leaves`).
+    pub fn iter_leaves(&self) -> Leaves<'a, S, H> {
+        Leaves::new(self.clone())
+    }
+
+    #[inline]
+    fn do_prove(
+        &self,
+        key: impl AsRef<[u8]>,
+        is_updatable: bool,
+    ) -> Result<SparseMerkleProof<H>, TreeError<S::Error>> {
+        let path = self.th.path(key);
+        let result = self.side_nodes_for_root(path.as_ref(), is_updatable)?;
+        proof_from_side_nodes(&self.th, path.as_ref(), result)
+    }
+
+    /// Get all the sibling nodes (sidenodes) for a given path from the root of the
+    /// view. Returns an array of sibling nodes, the leaf hash found at that path, the
+    /// leaf data, and the sibling data.
+    ///
+    /// If the leaf is a placeholder, the leaf data is nil.
+    pub(super) fn side_nodes_for_root(
+        &self,
+        path: &[u8],
+        get_sibling_data: bool,
+    ) -> Result<UpdateResult, TreeError<S::Error>> {
+        let mut walk = SideNodesWalk::new(&self.th, path, self.root(), get_sibling_data)?;
+        while let Some(hash) = walk.next_read().cloned() {
+            walk.feed(self.read_node(&hash)?)?;
+        }
+        Ok(walk.finish())
+    }
+}
+
+/// Builds the Merkle proof for `path` from the nodes found along the path.
+pub(super) fn proof_from_side_nodes<H: TreeHashScheme, E>(
+    th: &TreeHasher<H>,
+    path: &[u8],
+    result: UpdateResult,
+) -> Result<SparseMerkleProof<H>, TreeError<E>> {
+    let UpdateResult {
+        side_nodes,
+        path_nodes,
+        sibling_data,
+        current_data: leaf_data,
+    } = result;
+
+    let non_empty_side_nodes = side_nodes
+        .into_iter()
+        .filter(|n| !n.is_empty())
+        .collect::<Vec<_>>();
+
+    // Deal with non-membership proofs. If the leaf hash is the placeholder
+    // value, we do not need to add anything else to the proof.
+    let non_membership_leaf_data = match leaf_data {
+        Some(leaf_data) if path_nodes[0].ne(th.placeholder_ref()) => {
+            let (actual_path, _) = changes::parse_leaf::<H, E>(&path_nodes[0], &leaf_data)?;
+            // If the leaf has a different path, this is a non-membership proof that
+            // involves showing a different leaf. Add the leaf data to the proof.
+            actual_path.ne(path).then_some(leaf_data)
+        }
+        _ => None,
+    };
+
+    Ok(SparseMerkleProof::new(
+        non_empty_side_nodes,
+        non_membership_leaf_data,
+        sibling_data,
+    ))
+}
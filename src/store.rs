@@ -0,0 +1,174 @@
+use crate::{KVStore, StoreHasher, TreeHashScheme};
+use alloc::collections::{btree_map, BTreeMap};
+use bytes::Bytes;
+use core::marker::PhantomData;
+
+/// Returned by `MemoryStore` and `BTreeStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    /// The key to remove does not exist.
+    NotFound,
+}
+
+impl core::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StoreError::NotFound => write!(f, "key not found"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StoreError {}
+
+/// An in-memory `KVStore` backed by a `HashMap`, for trees hashed with `H`.
+#[cfg(feature = "std")]
+pub struct MemoryStore<H> {
+    pub(crate) data: std::collections::HashMap<Bytes, Bytes>,
+    _hasher: PhantomData<fn() -> H>,
+}
+
+/// An in-memory `KVStore` backed by a `BTreeMap`, for trees hashed with `H`.
+///
+/// Only needs `alloc`, and iterates over its entries in key order.
+pub struct BTreeStore<H> {
+    data: BTreeMap<Bytes, Bytes>,
+    _hasher: PhantomData<fn() -> H>,
+}
+
+#[cfg(feature = "std")]
+impl<H> MemoryStore<H> {
+    /// Creates an empty store.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            data: std::collections::HashMap::new(),
+            _hasher: PhantomData,
+        }
+    }
+
+    /// Returns the number of entries in the store.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if the store is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns an iterator over the entries of the store, in arbitrary order.
+    #[inline]
+    pub fn iter(&self) -> std::collections::hash_map::Iter<'_, Bytes, Bytes> {
+        self.data.iter()
+    }
+}
+
+impl<H> BTreeStore<H> {
+    /// Creates an empty store.
+    #[inline]
+    pub fn new() -> Self {
+        Self {
+            data: BTreeMap::new(),
+            _hasher: PhantomData,
+        }
+    }
+
+    /// Returns the number of entries in the store.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns true if the store is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Returns an iterator over the entries of the store, in key order.
+    #[inline]
+    pub fn iter(&self) -> btree_map::Iter<'_, Bytes, Bytes> {
+        self.data.iter()
+    }
+}
+
+macro_rules! impl_store {
+    ($store:ident) => {
+        impl<H> Default for $store<H> {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+
+        impl<H> Clone for $store<H> {
+            fn clone(&self) -> Self {
+                Self {
+                    data: self.data.clone(),
+                    _hasher: PhantomData,
+                }
+            }
+        }
+
+        impl<H> core::fmt::Debug for $store<H> {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.debug_struct(stringify!($store))
+                    .field("data", &self.data)
+                    .finish()
+            }
+        }
+
+        impl<H: TreeHashScheme> StoreHasher for $store<H> {
+            type Hasher = H;
+        }
+
+        impl<H> KVStore for $store<H> {
+            type Error = StoreError;
+
+            fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error> {
+                Ok(self.data.get(key).cloned())
+            }
+
+            fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error> {
+                self.data.insert(key, value);
+                Ok(())
+            }
+
+            fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error> {
+                self.data.remove(key).ok_or(StoreError::NotFound)
+            }
+
+            fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+                Ok(self.data.contains_key(key))
+            }
+        }
+
+        #[cfg(feature = "async")]
+        #[async_trait::async_trait]
+        impl<H> crate::AsyncKVStore for $store<H> {
+            type Error = StoreError;
+
+            async fn get(&self, key: &[u8]) -> Result<Option<Bytes>, Self::Error> {
+                KVStore::get(self, key)
+            }
+
+            async fn set(&mut self, key: Bytes, value: Bytes) -> Result<(), Self::Error> {
+                KVStore::set(self, key, value)
+            }
+
+            async fn remove(&mut self, key: &[u8]) -> Result<Bytes, Self::Error> {
+                KVStore::remove(self, key)
+            }
+
+            async fn contains(&self, key: &[u8]) -> Result<bool, Self::Error> {
+                KVStore::contains(self, key)
+            }
+        }
+    };
+}
+
+#[cfg(feature = "std")]
+impl_store!(MemoryStore);
+impl_store!(BTreeStore);
@@ -1,21 +1,168 @@
 use alloc::boxed::Box;
 use alloc::{vec, vec::Vec};
 use bytes::Bytes;
-use digest::{generic_array::GenericArray, Digest, OutputSizeUser};
+use digest::{Digest, FixedOutput, OutputSizeUser, Update};
 
 pub(crate) const LEAF_PREFIX: [u8; 1] = [0];
 const NODE_PREFIX: [u8; 1] = [1];
 
+/// How a tree hashes its leaves and nodes, what its empty subtrees hash to, and how
+/// keys are mapped to paths.
+///
+/// The tree stores leaves and nodes in the same format whatever the scheme, so the
+/// scheme only decides the hashes, which are what roots and proofs are made of.
+///
+/// Every `Digest` is a scheme, following [celestiaorg/smt]: leaves hash to
+/// `H(0x00 || path || value_hash)`, nodes to `H(0x01 || left || right)`, empty
+/// subtrees to all zeros, and the path of a key is `H(key)`.
+///
+/// [celestiaorg/smt]: https://github.com/celestiaorg/smt
+pub trait TreeHashScheme: Clone {
+    /// Returns the size of hashes and paths in bytes.
+    fn hash_size() -> usize;
+
+    /// Returns the path of a key.
+    fn path(&self, key: &[u8]) -> Bytes;
+
+    /// Returns the hash of a value, which is stored in its leaf.
+    fn hash_value(&self, value: &[u8]) -> Bytes;
+
+    /// Returns the hash of a leaf.
+    fn hash_leaf(&self, path: &[u8], value_hash: &[u8]) -> Bytes;
+
+    /// Returns the hash of a node from the hashes of its children.
+    fn hash_node(&self, left: &[u8], right: &[u8]) -> Bytes;
+
+    /// Returns the hash of an empty subtree.
+    fn placeholder(&self) -> Bytes;
+}
+
+impl<D: Digest + Clone> TreeHashScheme for D {
+    #[inline]
+    fn hash_size() -> usize {
+        <D as Digest>::output_size()
+    }
+
+    #[inline]
+    fn path(&self, key: &[u8]) -> Bytes {
+        digest_into::<D>(key)
+    }
+
+    #[inline]
+    fn hash_value(&self, value: &[u8]) -> Bytes {
+        digest_into::<D>(value)
+    }
+
+    fn hash_leaf(&self, path: &[u8], value_hash: &[u8]) -> Bytes {
+        digest_into::<D>(encode(LEAF_PREFIX, path, value_hash))
+    }
+
+    fn hash_node(&self, left: &[u8], right: &[u8]) -> Bytes {
+        digest_into::<D>(encode(NODE_PREFIX, left, right))
+    }
+
+    fn placeholder(&self) -> Bytes {
+        vec![0; <D as Digest>::output_size()].into()
+    }
+}
+
+/// A scheme hashing with clones of a hasher instance, for hashers which need a key,
+/// a personalization string or other runtime parameters, such as `Hmac` or keyed
+/// and personalised BLAKE2.
+///
+/// Hashes are the same as those of a `Digest` scheme, except that each one is
+/// computed by a fresh clone of the instance, so that keyed hashing also blinds the
+/// paths of keys. Empty subtrees hash to all zeros.
+#[derive(Debug, Clone)]
+pub struct KeyedHasher<D> {
+    hasher: D,
+}
+
+impl<D: Update + FixedOutput + Clone> KeyedHasher<D> {
+    /// Creates a scheme hashing with clones of `hasher`, which should not have been
+    /// fed any data.
+    #[inline]
+    pub fn new(hasher: D) -> Self {
+        Self { hasher }
+    }
+
+    /// Returns the hasher instance.
+    #[inline]
+    pub fn into_inner(self) -> D {
+        self.hasher
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> Bytes {
+        let mut hasher = self.hasher.clone();
+        for part in parts {
+            Update::update(&mut hasher, part);
+        }
+        output_into::<D>(hasher.finalize_fixed())
+    }
+}
+
+impl<D: Update + FixedOutput + Clone> TreeHashScheme for KeyedHasher<D> {
+    #[inline]
+    fn hash_size() -> usize {
+        <D as OutputSizeUser>::output_size()
+    }
+
+    #[inline]
+    fn path(&self, key: &[u8]) -> Bytes {
+        self.hash(&[key])
+    }
+
+    #[inline]
+    fn hash_value(&self, value: &[u8]) -> Bytes {
+        self.hash(&[value])
+    }
+
+    fn hash_leaf(&self, path: &[u8], value_hash: &[u8]) -> Bytes {
+        self.hash(&[&LEAF_PREFIX, path, value_hash])
+    }
+
+    fn hash_node(&self, left: &[u8], right: &[u8]) -> Bytes {
+        self.hash(&[&NODE_PREFIX, left, right])
+    }
+
+    fn placeholder(&self) -> Bytes {
+        vec![0; Self::hash_size()].into()
+    }
+}
+
+/// Hashes data into `Bytes` without copying the output.
+fn digest_into<D: Digest>(data: impl AsRef<[u8]>) -> Bytes {
+    output_into::<D>(<D as Digest>::digest(data))
+}
+
+fn output_into<D: OutputSizeUser>(output: digest::Output<D>) -> Bytes {
+    let ptr = Box::into_raw(Box::new(output)) as *mut u8;
+    let size = <D as OutputSizeUser>::output_size();
+    Bytes::from(unsafe { Vec::from_raw_parts(ptr, size, size) })
+}
+
+/// Encodes the data of a leaf or a node.
+fn encode(prefix: [u8; 1], a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(prefix.len() + a.len() + b.len());
+    data.extend_from_slice(&prefix);
+    data.extend_from_slice(a);
+    data.extend_from_slice(b);
+    data
+}
+
 pub(crate) struct TreeHasher<H> {
+    scheme: H,
     zero_value: Bytes,
-    _marker: core::marker::PhantomData<H>,
+    /// The identifier of the tree, if any, prefixed with its length.
+    domain: Option<Bytes>,
 }
 
-impl<H> Clone for TreeHasher<H> {
+impl<H: Clone> Clone for TreeHasher<H> {
     fn clone(&self) -> Self {
         Self {
+            scheme: self.scheme.clone(),
             zero_value: self.zero_value.clone(),
-            _marker: core::marker::PhantomData,
+            domain: self.domain.clone(),
         }
     }
 }
@@ -24,65 +171,97 @@ impl<H> core::fmt::Debug for TreeHasher<H> {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         f.debug_struct(core::any::type_name::<Self>())
             .field("zero_value", &self.zero_value)
+            .field("tree_id", &self.domain.as_ref().map(|domain| &domain[4..]))
             .finish()
     }
 }
 
-impl<H: Digest + OutputSizeUser> Default for TreeHasher<H> {
+impl<H: TreeHashScheme + Default> Default for TreeHasher<H> {
     fn default() -> Self {
-        Self {
-            zero_value: vec![0; <H as Digest>::output_size()].into(),
-            _marker: core::marker::PhantomData,
-        }
+        Self::new(H::default())
     }
 }
 
-impl<H: Digest> TreeHasher<H> {
-    pub(crate) fn new(zero_value: Bytes) -> Self {
+impl<H: TreeHashScheme> TreeHasher<H> {
+    pub(crate) fn new(scheme: H) -> Self {
         Self {
-            _marker: Default::default(),
-            zero_value,
+            zero_value: scheme.placeholder(),
+            scheme,
+            domain: None,
         }
     }
 
-    pub(crate) fn digest(
-        &self,
-        data: impl AsRef<[u8]>,
-    ) -> GenericArray<u8, <H as OutputSizeUser>::OutputSize> {
-        <H as Digest>::digest(data)
+    /// Binds the hashes to the tree with the given identifier: it is mixed into the
+    /// paths of keys and the hashes of leaves and nodes, so no two trees with
+    /// different identifiers share a leaf, a node or a proof. Empty subtrees still
+    /// hash to the placeholder.
+    pub(crate) fn with_tree_id(mut self, tree_id: Option<&[u8]>) -> Self {
+        self.domain = tree_id.map(|id| {
+            let mut domain = Vec::with_capacity(4 + id.len());
+            domain.extend_from_slice(&(id.len() as u32).to_be_bytes());
+            domain.extend_from_slice(id);
+            domain.into()
+        });
+        self
+    }
+
+    #[inline]
+    pub(crate) fn tree_id(&self) -> Option<&[u8]> {
+        self.domain.as_ref().map(|domain| &domain[4..])
+    }
+
+    #[inline]
+    pub(crate) fn scheme(&self) -> &H {
+        &self.scheme
+    }
+
+    /// Prefixes the first input of a hash with the identifier of the tree, if any.
+    #[inline]
+    fn bind<'a>(&self, data: &'a [u8]) -> alloc::borrow::Cow<'a, [u8]> {
+        match &self.domain {
+            Some(domain) => {
+                let mut bound = Vec::with_capacity(domain.len() + data.len());
+                bound.extend_from_slice(domain);
+                bound.extend_from_slice(data);
+                alloc::borrow::Cow::Owned(bound)
+            }
+            None => alloc::borrow::Cow::Borrowed(data),
+        }
+    }
+
+    #[inline]
+    fn hash_leaf(&self, path: &[u8], value_hash: &[u8]) -> Bytes {
+        self.scheme.hash_leaf(&self.bind(path), value_hash)
+    }
+
+    #[inline]
+    fn hash_node(&self, left: &[u8], right: &[u8]) -> Bytes {
+        self.scheme.hash_node(&self.bind(left), right)
     }
 
+    /// Returns the hash of a value.
+    #[inline]
+    pub(crate) fn digest(&self, value: impl AsRef<[u8]>) -> Bytes {
+        self.scheme.hash_value(value.as_ref())
+    }
+
+    #[inline]
     pub(crate) fn digest_leaf_hash(
         &self,
         path: impl AsRef<[u8]>,
-        leaf_data: impl AsRef<[u8]>,
+        value_hash: impl AsRef<[u8]>,
     ) -> Bytes {
-        let path = path.as_ref();
-        let leaf_data = leaf_data.as_ref();
-        let mut value = Vec::with_capacity(1 + path.len() + leaf_data.len());
-        value.push(LEAF_PREFIX[0]);
-        value.extend_from_slice(path);
-        value.extend_from_slice(leaf_data);
-        let ptr = Box::into_raw(Box::new(<H as Digest>::digest(&value))) as *mut u8;
-        let size = <H as OutputSizeUser>::output_size();
-        Bytes::from(unsafe { Vec::from_raw_parts(ptr, size, size) })
+        self.hash_leaf(path.as_ref(), value_hash.as_ref())
     }
 
     pub(crate) fn digest_leaf(
         &self,
         path: impl AsRef<[u8]>,
-        leaf_data: impl AsRef<[u8]>,
+        value_hash: impl AsRef<[u8]>,
     ) -> (Bytes, Bytes) {
-        let path = path.as_ref();
-        let leaf_data = leaf_data.as_ref();
-        let mut value = Vec::with_capacity(1 + path.len() + leaf_data.len());
-        value.push(LEAF_PREFIX[0]);
-        value.extend_from_slice(path);
-        value.extend_from_slice(leaf_data);
-        let ptr = Box::into_raw(Box::new(<H as Digest>::digest(&value))) as *mut u8;
-        let size = <H as OutputSizeUser>::output_size();
-        let sum = Bytes::from(unsafe { Vec::from_raw_parts(ptr, size, size) });
-        (sum, value.into())
+        let (path, value_hash) = (path.as_ref(), value_hash.as_ref());
+        let hash = self.hash_leaf(path, value_hash);
+        (hash, encode(LEAF_PREFIX, path, value_hash).into())
     }
 
     pub(crate) fn digest_node(
@@ -97,14 +276,8 @@ impl<H: Digest> TreeHasher<H> {
 
     #[inline]
     fn digest_node_helper(&self, left_data: &[u8], right_data: &[u8]) -> (Bytes, Bytes) {
-        let mut value = Vec::with_capacity(1 + left_data.len() + right_data.len());
-        value.push(NODE_PREFIX[0]);
-        value.extend_from_slice(left_data);
-        value.extend_from_slice(right_data);
-        let ptr = Box::into_raw(Box::new(<H as Digest>::digest(&value))) as *mut u8;
-        let size = <H as Digest>::output_size();
-        let sum = Bytes::from(unsafe { Vec::from_raw_parts(ptr, size, size) });
-        (sum, value.into())
+        let hash = self.hash_node(left_data, right_data);
+        (hash, encode(NODE_PREFIX, left_data, right_data).into())
     }
 
     pub(crate) fn digest_left_node(&self, left_data: impl AsRef<[u8]>) -> (Bytes, Bytes) {
@@ -119,58 +292,75 @@ impl<H: Digest> TreeHasher<H> {
         self.digest_node_helper(left_data, right_data)
     }
 
-    pub(crate) fn parse_leaf(data: &[u8]) -> (&[u8], &[u8]) {
-        let leaf_prefix_len = LEAF_PREFIX.len();
-        let path_size = Self::path_size();
-        (
-            &data[leaf_prefix_len..path_size + leaf_prefix_len],
-            &data[leaf_prefix_len + path_size..],
-        )
-    }
-
-    pub(crate) fn parse_node(data: &Option<Bytes>) -> (Bytes, Bytes) {
-        match data {
-            Some(data) => {
-                let node_prefix_len = NODE_PREFIX.len();
-                let left_size = Self::path_size();
-                let right_size = Self::path_size();
-                (
-                    data.slice(node_prefix_len..left_size + node_prefix_len),
-                    data.slice(
-                        node_prefix_len + left_size..node_prefix_len + left_size + right_size,
-                    ),
-                )
-            }
-            None => (Bytes::new(), Bytes::new()),
+    /// Returns the hash of the data of a leaf or a node, or `None` if the data is
+    /// malformed.
+    pub(crate) fn digest_data(&self, data: &[u8]) -> Option<Bytes> {
+        if let Some((path, value_hash)) = Self::parse_leaf(data) {
+            return Some(self.hash_leaf(path, value_hash));
         }
+        if !data.starts_with(&NODE_PREFIX) || data.len() != Self::node_data_len() {
+            return None;
+        }
+        let (left, right) = data[NODE_PREFIX.len()..].split_at(H::hash_size());
+        Some(self.hash_node(left, right))
     }
 
-    pub(crate) fn is_leaf(data: &Option<impl AsRef<[u8]>>) -> bool {
-        match data {
-            Some(data) => {
-                let data = data.as_ref();
-                let leaf_prefix_len = LEAF_PREFIX.len();
-                data[..leaf_prefix_len].eq(&LEAF_PREFIX)
-            }
-            None => false,
+    /// Parses the data of a leaf into its path and value hash. Returns `None` if the
+    /// data does not have the prefix and the length of a leaf.
+    pub(crate) fn parse_leaf(data: &[u8]) -> Option<(&[u8], &[u8])> {
+        if !data.starts_with(&LEAF_PREFIX) || data.len() != Self::node_data_len() {
+            return None;
         }
+        let (path, value_hash) = data[LEAF_PREFIX.len()..].split_at(Self::path_size());
+        Some((path, value_hash))
     }
 
-    pub(crate) fn path(
-        &self,
-        key: impl AsRef<[u8]>,
-    ) -> GenericArray<u8, <H as OutputSizeUser>::OutputSize> {
-        <H as Digest>::digest(key)
+    /// Parses the data of a node into the hashes of its children. Returns `None` if
+    /// the data does not have the prefix and the length of a node.
+    pub(crate) fn parse_node(data: &Bytes) -> Option<(Bytes, Bytes)> {
+        if !data.starts_with(&NODE_PREFIX) || data.len() != Self::node_data_len() {
+            return None;
+        }
+        let left_start = NODE_PREFIX.len();
+        let right_start = left_start + H::hash_size();
+        Some((
+            data.slice(left_start..right_start),
+            data.slice(right_start..),
+        ))
     }
 
+    /// Returns true if the data has the prefix of a leaf.
+    pub(crate) fn is_leaf(data: &Option<impl AsRef<[u8]>>) -> bool {
+        matches!(data, Some(data) if data.as_ref().starts_with(&LEAF_PREFIX))
+    }
+
+    /// Returns true if `data` has the prefix and the length of a leaf or a node.
+    pub(crate) fn is_well_formed(data: &[u8]) -> bool {
+        (data.starts_with(&LEAF_PREFIX) || data.starts_with(&NODE_PREFIX))
+            && data.len() == Self::node_data_len()
+    }
+
+    /// Returns the length of the data of leaves and nodes, which is the same as paths
+    /// are hashes.
+    #[inline]
+    fn node_data_len() -> usize {
+        LEAF_PREFIX.len() + Self::path_size() + H::hash_size()
+    }
+
+    /// Returns the path of a key.
+    #[inline]
+    pub(crate) fn path(&self, key: impl AsRef<[u8]>) -> Bytes {
+        self.scheme.path(&self.bind(key.as_ref()))
+    }
+
+    #[inline]
     pub(crate) fn path_into(&self, key: impl AsRef<[u8]>) -> Bytes {
-        let ptr = Box::into_raw(Box::new(<H as Digest>::digest(key))) as *mut u8;
-        let size = <H as Digest>::output_size();
-        Bytes::from(unsafe { Vec::from_raw_parts(ptr, size, size) })
+        self.path(key)
     }
 
+    #[inline]
     pub(crate) fn path_size() -> usize {
-        <H as digest::Digest>::output_size()
+        H::hash_size()
     }
 
     pub(crate) fn placeholder(&self) -> Bytes {